@@ -1,2 +1,2 @@
 #!/bin/bash
-./target/release/practice $1 $2
\ No newline at end of file
+./target/release/practice "$@"
\ No newline at end of file
@@ -0,0 +1,179 @@
+use std::collections::HashMap;
+use std::fs;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+
+use flate2::read::DeflateDecoder;
+use flate2::Crc;
+
+// Entry of a .rtscene archive holding the scene description itself.
+const ARCHIVE_SCENE_ENTRY: &str = "scene.txt";
+
+const END_OF_CENTRAL_DIRECTORY: u32 = 0x06054b50;
+const CENTRAL_DIRECTORY_HEADER: u32 = 0x02014b50;
+const LOCAL_FILE_HEADER: u32 = 0x04034b50;
+const METHOD_STORED: u16 = 0;
+const METHOD_DEFLATED: u16 = 8;
+
+// Where the meshes and images a scene refers to are read from: the file system, or
+// the entries of the .rtscene archive the scene came in.
+pub enum Assets {
+    // relative paths are looked up in each root in turn, the scene's directory first
+    FileSystem { roots: Vec<PathBuf> },
+    Archive(HashMap<String, Vec<u8>>),
+}
+
+impl Assets {
+    // Loose files next to the scene file at `scene_path`.
+    pub fn next_to(scene_path: &str) -> Assets {
+        let directory = Path::new(scene_path)
+            .parent()
+            .filter(|directory| !directory.as_os_str().is_empty())
+            .unwrap_or(Path::new("."));
+        Assets::FileSystem {
+            roots: vec![directory.to_path_buf()],
+        }
+    }
+
+    // Archives are self-contained, so extra roots only apply to loose files.
+    pub fn add_search_root(&mut self, root: &str) {
+        if let Assets::FileSystem { roots } = self {
+            roots.push(PathBuf::from(root));
+        }
+    }
+
+    pub fn read(&self, path: &str) -> Result<Vec<u8>, String> {
+        match self {
+            Assets::FileSystem { roots } => {
+                let resolved = if Path::new(path).is_absolute() {
+                    PathBuf::from(path)
+                } else {
+                    roots
+                        .iter()
+                        .map(|root| root.join(path))
+                        .find(|candidate| candidate.is_file())
+                        .ok_or_else(|| {
+                            let searched: Vec<String> = roots
+                                .iter()
+                                .map(|root| root.display().to_string())
+                                .collect();
+                            format!("{} is not in {}", path, searched.join(", "))
+                        })?
+                };
+                fs::read(&resolved).map_err(|error| format!("{}: {}", resolved.display(), error))
+            }
+            Assets::Archive(entries) => entries
+                .get(path.trim_start_matches("./"))
+                .cloned()
+                .ok_or_else(|| format!("no {} in the scene archive", path)),
+        }
+    }
+}
+
+fn read_u16(bytes: &[u8], offset: usize) -> Option<u16> {
+    Some(u16::from_le_bytes(
+        bytes.get(offset..offset + 2)?.try_into().ok()?,
+    ))
+}
+
+fn read_u32(bytes: &[u8], offset: usize) -> Option<u32> {
+    Some(u32::from_le_bytes(
+        bytes.get(offset..offset + 4)?.try_into().ok()?,
+    ))
+}
+
+struct ZipEntry<'a> {
+    name: String,
+    method: u16,
+    crc: u32,
+    size: usize,
+    data: &'a [u8],
+    // size of the central directory header, which the next one follows
+    header_size: usize,
+}
+
+fn read_zip_entry(bytes: &[u8], header: usize) -> Option<ZipEntry<'_>> {
+    if read_u32(bytes, header)? != CENTRAL_DIRECTORY_HEADER {
+        return None;
+    }
+    let compressed_size = read_u32(bytes, header + 20)? as usize;
+    let name_length = read_u16(bytes, header + 28)? as usize;
+    let local_header = read_u32(bytes, header + 42)? as usize;
+    if read_u32(bytes, local_header)? != LOCAL_FILE_HEADER {
+        return None;
+    }
+    let data_start = local_header
+        + 30
+        + read_u16(bytes, local_header + 26)? as usize
+        + read_u16(bytes, local_header + 28)? as usize;
+    Some(ZipEntry {
+        name: String::from_utf8_lossy(bytes.get(header + 46..header + 46 + name_length)?)
+            .into_owned(),
+        method: read_u16(bytes, header + 10)?,
+        crc: read_u32(bytes, header + 16)?,
+        size: read_u32(bytes, header + 24)? as usize,
+        data: bytes.get(data_start..data_start + compressed_size)?,
+        header_size: 46
+            + name_length
+            + read_u16(bytes, header + 30)? as usize
+            + read_u16(bytes, header + 32)? as usize,
+    })
+}
+
+// Stored and deflated files of a zip archive by name, directories are skipped.
+fn read_zip(bytes: &[u8]) -> Result<HashMap<String, Vec<u8>>, String> {
+    let corrupted = || "the scene archive is not a valid zip file".to_string();
+    // the end record is last, followed only by a comment of up to 64 KiB
+    let end = (0..bytes.len().saturating_sub(21))
+        .rev()
+        .take(u16::MAX as usize + 1)
+        .find(|&offset| read_u32(bytes, offset) == Some(END_OF_CENTRAL_DIRECTORY))
+        .ok_or_else(corrupted)?;
+    let entry_count = read_u16(bytes, end + 10).ok_or_else(corrupted)?;
+    let mut header = read_u32(bytes, end + 16).ok_or_else(corrupted)? as usize;
+
+    let mut entries = HashMap::new();
+    for _ in 0..entry_count {
+        let entry = read_zip_entry(bytes, header).ok_or_else(corrupted)?;
+        header += entry.header_size;
+        if entry.name.ends_with('/') {
+            continue;
+        }
+        let data = match entry.method {
+            METHOD_STORED => entry.data.to_vec(),
+            METHOD_DEFLATED => {
+                let mut inflated = Vec::with_capacity(entry.size);
+                DeflateDecoder::new(entry.data)
+                    .read_to_end(&mut inflated)
+                    .map_err(|_| corrupted())?;
+                inflated
+            }
+            _ => {
+                return Err(format!(
+                    "{} in the scene archive uses an unsupported compression method",
+                    entry.name
+                ))
+            }
+        };
+        let mut checksum = Crc::new();
+        checksum.update(&data);
+        if data.len() != entry.size || checksum.sum() != entry.crc {
+            return Err(format!("{} in the scene archive is corrupted", entry.name));
+        }
+        entries.insert(entry.name, data);
+    }
+    Ok(entries)
+}
+
+// A .rtscene archive is a zip file with the scene description in scene.txt and the
+// files it refers to stored under the paths it uses for them.
+pub fn open_archive(path: &str) -> Result<(String, Assets), String> {
+    let bytes = fs::read(path).map_err(|error| error.to_string())?;
+    let mut entries = read_zip(&bytes)?;
+    let scene_content = entries
+        .remove(ARCHIVE_SCENE_ENTRY)
+        .ok_or_else(|| format!("the scene archive has no {}", ARCHIVE_SCENE_ENTRY))?;
+    let scene_content = String::from_utf8(scene_content)
+        .map_err(|_| format!("{} is not valid UTF-8", ARCHIVE_SCENE_ENTRY))?;
+    Ok((scene_content, Assets::Archive(entries)))
+}
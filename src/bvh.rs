@@ -0,0 +1,289 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use nalgebra::Vector3;
+
+use crate::geometry::{intersect_primitive, triangle_area_cdf, Intersection, Ray, Shape};
+use crate::scene::Primitive;
+
+const MAX_LEAF_SIZE: usize = 2;
+// deeper than any median split tree of items that fit in memory
+const MAX_DEPTH: usize = 64;
+
+#[derive(Clone, Copy)]
+pub(crate) struct Aabb {
+    pub(crate) min: Vector3<f64>,
+    pub(crate) max: Vector3<f64>,
+}
+
+impl Aabb {
+    pub(crate) fn empty() -> Aabb {
+        Aabb {
+            min: Vector3::repeat(f64::INFINITY),
+            max: Vector3::repeat(f64::NEG_INFINITY),
+        }
+    }
+
+    pub(crate) fn grow(&mut self, point: &Vector3<f64>) {
+        self.min = self.min.inf(point);
+        self.max = self.max.sup(point);
+    }
+
+    fn union(&self, other: &Aabb) -> Aabb {
+        Aabb {
+            min: self.min.inf(&other.min),
+            max: self.max.sup(&other.max),
+        }
+    }
+
+    fn centroid(&self) -> Vector3<f64> {
+        (self.min + self.max) / 2.0
+    }
+
+    // Slab test, whether the ray is inside the box somewhere between t_min and t_max, in
+    // units of the ray parameter like Intersection::ts.
+    fn hit(&self, ray: &Ray, inverse_direction: &Vector3<f64>, (t_min, t_max): (f64, f64)) -> bool {
+        let t0 = (self.min - ray.point).component_mul(inverse_direction);
+        let t1 = (self.max - ray.point).component_mul(inverse_direction);
+        let t_enter = t0.inf(&t1).max().max(t_min).max(0.0);
+        let t_exit = t0.sup(&t1).min().min(t_max);
+        t_enter <= t_exit
+    }
+}
+
+// Box in the shape's own frame, None for infinite shapes.
+fn local_bounds(shape: &Shape) -> Option<Aabb> {
+    match shape {
+        Shape::Plane { normal: _ } => None,
+        Shape::Ellipsoid { r } => Some(Aabb { min: -r, max: *r }),
+        Shape::Sphere { r } => Some(Aabb {
+            min: Vector3::repeat(-r),
+            max: Vector3::repeat(*r),
+        }),
+        Shape::Box { s } => Some(Aabb { min: -s, max: *s }),
+        Shape::Cylinder { r, h } | Shape::Cone { r, h } => Some(Aabb {
+            min: Vector3::new(-r, -h, -r),
+            max: Vector3::new(*r, *h, *r),
+        }),
+        Shape::Rectangle { s } => Some(Aabb {
+            min: Vector3::new(-s.x, 0.0, -s.y),
+            max: Vector3::new(s.x, 0.0, s.y),
+        }),
+        Shape::Disc { r } => Some(Aabb {
+            min: Vector3::new(-r, 0.0, -r),
+            max: Vector3::new(*r, 0.0, *r),
+        }),
+        Shape::TriangleMesh { bvh, .. } => bvh.nodes.first().map(|root| root.bounds),
+    }
+}
+
+// World space bounds of the rotated and moved local box.
+fn primitive_bounds(primitive: &Primitive, local: &Aabb) -> Aabb {
+    let mut bounds = Aabb::empty();
+    for corner in 0..8 {
+        let point = Vector3::from_fn(|axis, _| {
+            if corner & (1 << axis) == 0 {
+                local.min[axis]
+            } else {
+                local.max[axis]
+            }
+        });
+        bounds.grow(&(primitive.rotation.transform_vector(&point) + primitive.position));
+    }
+    bounds
+}
+
+pub(crate) enum BvhNodeKind {
+    Leaf { first: usize, count: usize },
+    Interior { left: usize, right: usize },
+}
+
+pub(crate) struct BvhNode {
+    pub(crate) bounds: Aabb,
+    pub(crate) kind: BvhNodeKind,
+}
+
+// Bounding volume hierarchy over the scene primitives. Unbounded primitives (planes)
+// are kept aside and tested against every ray.
+#[derive(Default)]
+pub struct Bvh {
+    nodes: Vec<BvhNode>,
+    // primitive indices, leaves reference contiguous ranges of it
+    indices: Vec<usize>,
+    unbounded: Vec<usize>,
+}
+
+impl Bvh {
+    // Meshes need their own hierarchy from build_mesh_bvhs first.
+    pub fn build(primitives: &[Primitive]) -> Bvh {
+        Bvh::build_over(primitives.iter().map(|primitive| {
+            Some(primitive_bounds(
+                primitive,
+                &local_bounds(&primitive.shape)?,
+            ))
+        }))
+    }
+
+    // Hierarchy over the triangles of a mesh, in the mesh's own frame.
+    pub(crate) fn build_mesh(vertices: &[Vector3<f64>], triangles: &[[usize; 3]]) -> Bvh {
+        Bvh::build_over(triangles.iter().map(|triangle| {
+            let mut bounds = Aabb::empty();
+            triangle
+                .iter()
+                .for_each(|&vertex| bounds.grow(&vertices[vertex]));
+            Some(bounds)
+        }))
+    }
+
+    // Hierarchy over any items given by their bounds, None for unbounded ones.
+    pub(crate) fn build_over(bounds: impl Iterator<Item = Option<Aabb>>) -> Bvh {
+        let mut bvh = Bvh::default();
+        let mut items: Vec<(usize, Aabb)> = vec![];
+        for (index, bounds) in bounds.enumerate() {
+            match bounds {
+                Some(bounds) => items.push((index, bounds)),
+                None => bvh.unbounded.push(index),
+            }
+        }
+        if !items.is_empty() {
+            bvh.build_node(&mut items);
+        }
+        bvh
+    }
+
+    // Median split along the widest axis of the centroids; returns the node index.
+    fn build_node(&mut self, items: &mut [(usize, Aabb)]) -> usize {
+        let bounds = items
+            .iter()
+            .fold(Aabb::empty(), |bounds, (_, item)| bounds.union(item));
+        let node = self.nodes.len();
+        if items.len() <= MAX_LEAF_SIZE {
+            self.nodes.push(BvhNode {
+                bounds,
+                kind: BvhNodeKind::Leaf {
+                    first: self.indices.len(),
+                    count: items.len(),
+                },
+            });
+            self.indices.extend(items.iter().map(|(index, _)| index));
+            return node;
+        }
+
+        let mut centroid_bounds = Aabb::empty();
+        items
+            .iter()
+            .for_each(|(_, item)| centroid_bounds.grow(&item.centroid()));
+        let axis = (centroid_bounds.max - centroid_bounds.min).imax();
+        items.sort_by(|(_, x), (_, y)| {
+            x.centroid()[axis]
+                .partial_cmp(&y.centroid()[axis])
+                .expect("Nan in primitive bounds.")
+        });
+
+        // children are filled in once they are built
+        self.nodes.push(BvhNode {
+            bounds,
+            kind: BvhNodeKind::Interior { left: 0, right: 0 },
+        });
+        let (left_items, right_items) = items.split_at_mut(items.len() / 2);
+        let left = self.build_node(left_items);
+        let right = self.build_node(right_items);
+        self.nodes[node].kind = BvhNodeKind::Interior { left, right };
+        node
+    }
+
+    // Depth first, the root first and every interior node before its children.
+    pub(crate) fn nodes(&self) -> &[BvhNode] {
+        &self.nodes
+    }
+
+    // Item indices in leaf order, leaves reference contiguous ranges of them.
+    pub(crate) fn indices(&self) -> &[usize] {
+        &self.indices
+    }
+
+    // Calls visit with every item whose box the ray passes through, unbounded items
+    // first. visit returns the range of the ray parameter it still needs items from,
+    // boxes outside of it are skipped.
+    pub(crate) fn traverse(&self, ray: &Ray, mut visit: impl FnMut(usize) -> (f64, f64)) {
+        let mut range = (0.0, f64::INFINITY);
+        for &index in &self.unbounded {
+            range = visit(index);
+        }
+        if self.nodes.is_empty() {
+            return;
+        }
+
+        let inverse_direction = ray.direction.map(|x| 1.0 / x);
+        let mut stack = [0; MAX_DEPTH];
+        let mut stack_size = 1;
+        while stack_size > 0 {
+            stack_size -= 1;
+            let node = &self.nodes[stack[stack_size]];
+            if !node.bounds.hit(ray, &inverse_direction, range) {
+                continue;
+            }
+            match node.kind {
+                BvhNodeKind::Leaf { first, count } => {
+                    for &index in &self.indices[first..first + count] {
+                        range = visit(index);
+                    }
+                }
+                BvhNodeKind::Interior { left, right } => {
+                    stack[stack_size] = right;
+                    stack[stack_size + 1] = left;
+                    stack_size += 2;
+                }
+            }
+        }
+    }
+
+    // Closest hit by the first ray parameter, like a linear scan would find.
+    // The closest hit along with the index of the primitive that was hit.
+    pub fn intersect(&self, ray: &Ray, primitives: &[Primitive]) -> Option<(Intersection, usize)> {
+        let mut closest: Option<(Intersection, usize)> = None;
+        self.traverse(ray, |index| {
+            if let Some(intersection) = intersect_primitive(ray, &primitives[index]) {
+                if closest
+                    .as_ref()
+                    .is_none_or(|(best, _)| intersection.ts[0] < best.ts[0])
+                {
+                    closest = Some((intersection, index));
+                }
+            }
+            (
+                0.0,
+                closest
+                    .as_ref()
+                    .map_or(f64::INFINITY, |(best, _)| best.ts[0]),
+            )
+        });
+        closest
+    }
+}
+
+// Gives every mesh the hierarchy and the area distribution over its triangles once its
+// buffers are final. Instances sharing the buffers of a mesh share both.
+pub(crate) fn build_mesh_bvhs(primitives: &mut [Primitive]) {
+    let mut built: HashMap<_, (Arc<Bvh>, Arc<Vec<f64>>)> = HashMap::new();
+    for primitive in primitives {
+        if let Shape::TriangleMesh {
+            vertices,
+            triangles,
+            normals: _,
+            bvh,
+            area_cdf,
+        } = &mut primitive.shape
+        {
+            (*bvh, *area_cdf) = built
+                .entry((Arc::as_ptr(vertices), Arc::as_ptr(triangles)))
+                .or_insert_with(|| {
+                    (
+                        Arc::new(Bvh::build_mesh(vertices, triangles)),
+                        Arc::new(triangle_area_cdf(vertices, triangles)),
+                    )
+                })
+                .clone();
+        }
+    }
+}
@@ -0,0 +1,720 @@
+use std::f64::consts::PI;
+use std::ptr;
+use std::sync::Arc;
+
+use nalgebra::{UnitQuaternion, Vector2, Vector3};
+use rand::{Rng, RngCore, SeedableRng};
+use rand_pcg::Pcg32;
+
+use crate::bvh::Bvh;
+use crate::distribution::{
+    generate_unit_on_sphere, BackgroundDistr, CosineWeightedDistr, DirectionSample,
+    DistributionTooling, EnvironmentDistr, LightSourceDistr, MixDistr,
+};
+use crate::environment::EnvironmentMap;
+use crate::geometry::{triangle_area_cdf, Intersection, Ray, Shape};
+use crate::medium::{HomogeneousMedium, Medium};
+use crate::rendering::{
+    pick_material, sample_car_paint, sample_dielectric, schlick_reflectance, CarPaintLobe,
+    DielectricLobe, PathContext, CLEARCOAT_IOR,
+};
+use crate::sampler::{Sampler, SamplerType};
+use crate::scene::{EmissionGradient, LightLink, Material, MaterialMix, MixWeight, Primitive};
+use crate::tessellation::tessellate_shape;
+
+// Bins are equal-area cells of the sphere, split uniformly in cos(theta) and phi.
+const COS_THETA_BINS: usize = 40;
+const PHI_BINS: usize = 80;
+// pdf() is integrated over each bin on a grid of this many points per side
+const INTEGRATION_RESOLUTION: usize = 8;
+const EDGE_INTEGRATION_RESOLUTION: usize = 128;
+// kept low enough that the integration error at the edges of lights stays well below
+// the sampling noise
+const SAMPLES: usize = 200_000;
+// random orientations every distribution is tested in
+const ROTATIONS: usize = 3;
+// bins expecting fewer samples are pooled, as the chi-square approximation needs.
+// This includes bins where the integrated pdf() is zero, as a light can cover a sliver
+// of a bin between the integration points.
+const MIN_EXPECTED: f64 = 5.0;
+const SIGNIFICANCE: f64 = 0.01;
+const MIN_FACING_COSINE: f64 = 0.3;
+// relative difference allowed between the pdf a sample reports and pdf()
+const PDF_TOLERANCE: f64 = 1e-6;
+
+fn bin_index(direction: &Vector3<f64>) -> usize {
+    let direction = direction.normalize();
+    let cos_theta_bin = ((direction.z + 1.0) / 2.0 * COS_THETA_BINS as f64) as usize;
+    let phi = direction.y.atan2(direction.x) + PI;
+    let phi_bin = (phi / (2.0 * PI) * PHI_BINS as f64) as usize;
+    cos_theta_bin.min(COS_THETA_BINS - 1) * PHI_BINS + phi_bin.min(PHI_BINS - 1)
+}
+
+// Probability of one bin according to pdf(), by the midpoint rule on a grid of
+// `resolution` points per side, and whether pdf() was zero only on part of the grid.
+fn integrate_bin(
+    distr: &dyn DistributionTooling,
+    point_from: &Vector3<f64>,
+    normal_from: &Vector3<f64>,
+    bin: usize,
+    resolution: usize,
+) -> (f64, bool) {
+    let cell_solid_angle = 4.0 * PI / (COS_THETA_BINS * PHI_BINS * resolution * resolution) as f64;
+    let (mut probability, mut zero_cells) = (0.0, 0);
+    for cos_theta_cell in 0..resolution {
+        let cos_theta = ((bin / PHI_BINS) as f64
+            + (cos_theta_cell as f64 + 0.5) / resolution as f64)
+            / COS_THETA_BINS as f64
+            * 2.0
+            - 1.0;
+        let sin_theta = (1.0 - cos_theta * cos_theta).sqrt();
+        for phi_cell in 0..resolution {
+            let phi = ((bin % PHI_BINS) as f64 + (phi_cell as f64 + 0.5) / resolution as f64)
+                / PHI_BINS as f64
+                * 2.0
+                * PI
+                - PI;
+            let direction = Vector3::new(sin_theta * phi.cos(), sin_theta * phi.sin(), cos_theta);
+            let pdf = distr.pdf(point_from, normal_from, &direction);
+            if pdf == 0.0 {
+                zero_cells += 1;
+            }
+            probability += pdf * cell_solid_angle;
+        }
+    }
+    (
+        probability,
+        zero_cells > 0 && zero_cells < resolution * resolution,
+    )
+}
+
+// Probability of every bin according to pdf(). Bins on the edge of the support and
+// their neighbours are integrated again on a much finer grid, as pdf() jumps there.
+fn integrate_pdf(
+    distr: &dyn DistributionTooling,
+    point_from: &Vector3<f64>,
+    normal_from: &Vector3<f64>,
+) -> Vec<f64> {
+    let (mut probabilities, on_edge): (Vec<f64>, Vec<bool>) = (0..COS_THETA_BINS * PHI_BINS)
+        .map(|bin| integrate_bin(distr, point_from, normal_from, bin, INTEGRATION_RESOLUTION))
+        .unzip();
+    let coarse_probabilities = probabilities.clone();
+    for bin in 0..COS_THETA_BINS * PHI_BINS {
+        let (cos_theta_bin, phi_bin) = ((bin / PHI_BINS) as i64, (bin % PHI_BINS) as i64);
+        // the coarse grid can miss a sliver of the support, but never in a bin that
+        // borders one it hits
+        let near_edge = (-1..=1).any(|cos_theta_offset| {
+            (-1..=1).any(|phi_offset| {
+                let neighbour_cos_theta = cos_theta_bin + cos_theta_offset;
+                if !(0..COS_THETA_BINS as i64).contains(&neighbour_cos_theta) {
+                    return false;
+                }
+                let neighbour_phi = (phi_bin + phi_offset).rem_euclid(PHI_BINS as i64);
+                let neighbour = neighbour_cos_theta as usize * PHI_BINS + neighbour_phi as usize;
+                on_edge[neighbour]
+                    || (coarse_probabilities[neighbour] > 0.0) != (coarse_probabilities[bin] > 0.0)
+            })
+        });
+        if near_edge {
+            probabilities[bin] = integrate_bin(
+                distr,
+                point_from,
+                normal_from,
+                bin,
+                EDGE_INTEGRATION_RESOLUTION,
+            )
+            .0;
+        }
+    }
+    probabilities
+}
+
+// Regularized upper incomplete gamma function Q(a, x), as in Numerical Recipes.
+fn gamma_q(a: f64, x: f64) -> f64 {
+    let ln_gamma_a = ln_gamma(a);
+    if x < a + 1.0 {
+        let mut term = 1.0 / a;
+        let mut sum = term;
+        for n in 1..1000 {
+            term *= x / (a + n as f64);
+            sum += term;
+            if term.abs() < sum.abs() * 1e-15 {
+                break;
+            }
+        }
+        1.0 - sum * (-x + a * x.ln() - ln_gamma_a).exp()
+    } else {
+        // Lentz's method for the continued fraction
+        let tiny = 1e-300;
+        let mut b = x + 1.0 - a;
+        let mut c = 1.0 / tiny;
+        let mut d = 1.0 / b;
+        let mut h = d;
+        for i in 1..1000 {
+            let an = -(i as f64) * (i as f64 - a);
+            b += 2.0;
+            d = an * d + b;
+            if d.abs() < tiny {
+                d = tiny;
+            }
+            c = b + an / c;
+            if c.abs() < tiny {
+                c = tiny;
+            }
+            d = 1.0 / d;
+            let delta = d * c;
+            h *= delta;
+            if (delta - 1.0).abs() < 1e-15 {
+                break;
+            }
+        }
+        (-x + a * x.ln() - ln_gamma_a).exp() * h
+    }
+}
+
+// Lanczos approximation.
+fn ln_gamma(x: f64) -> f64 {
+    const COEFFICIENTS: [f64; 6] = [
+        76.18009172947146,
+        -86.50532032941677,
+        24.01409824083091,
+        -1.231739572450155,
+        0.1208650973866179e-2,
+        -0.5395239384953e-5,
+    ];
+    let tmp = x + 5.5 - (x + 0.5) * (x + 5.5).ln();
+    let series = COEFFICIENTS
+        .iter()
+        .enumerate()
+        .fold(1.000000000190015, |sum, (i, c)| {
+            sum + c / (x + 1.0 + i as f64)
+        });
+    -tmp + (2.5066282746310005 * series / x).ln()
+}
+
+struct TestResult {
+    p_value: f64,
+    degrees_of_freedom: usize,
+    statistic: f64,
+    integral: f64,
+    // samples in bins where the integrated pdf() is zero
+    stray_samples: usize,
+    // samples whose reported pdf disagrees with pdf() for their direction
+    pdf_mismatches: usize,
+}
+
+fn chi_square_test(
+    distr: &dyn DistributionTooling,
+    rng: &mut dyn RngCore,
+    point_from: &Vector3<f64>,
+    normal_from: &Vector3<f64>,
+) -> TestResult {
+    let mut observed = vec![0.0; COS_THETA_BINS * PHI_BINS];
+    let mut pdf_mismatches = 0;
+    for _ in 0..SAMPLES {
+        let sample = distr.sample([rng.gen(), rng.gen()], rng.gen(), point_from, normal_from);
+        observed[bin_index(&sample.direction)] += 1.0;
+        let pdf = distr.pdf(point_from, normal_from, &sample.direction);
+        if (sample.pdf - pdf).abs() > PDF_TOLERANCE * pdf.max(sample.pdf) {
+            pdf_mismatches += 1;
+        }
+    }
+    TestResult {
+        pdf_mismatches,
+        ..test_counts(&observed, &integrate_pdf(distr, point_from, normal_from))
+    }
+}
+
+// Pearson's statistic of the samples counted in each bin against the probabilities of
+// the bins.
+fn test_counts(observed: &[f64], probabilities: &[f64]) -> TestResult {
+    let samples = observed.iter().sum::<f64>();
+    let mut stray_samples = 0;
+    let mut statistic = 0.0;
+    let mut degrees_of_freedom: usize = 0;
+    let (mut pooled_observed, mut pooled_expected) = (0.0, 0.0);
+    for (observed, probability) in observed.iter().zip(probabilities) {
+        let expected = probability * samples;
+        if expected == 0.0 {
+            stray_samples += *observed as usize;
+        }
+        if expected < MIN_EXPECTED {
+            pooled_observed += observed;
+            pooled_expected += expected;
+        } else {
+            statistic += (observed - expected).powi(2) / expected;
+            degrees_of_freedom += 1;
+        }
+    }
+    if pooled_expected > 0.0 {
+        statistic += (pooled_observed - pooled_expected).powi(2) / pooled_expected;
+        degrees_of_freedom += 1;
+    }
+    let degrees_of_freedom = degrees_of_freedom.saturating_sub(1).max(1);
+    TestResult {
+        p_value: gamma_q(degrees_of_freedom as f64 / 2.0, statistic / 2.0),
+        degrees_of_freedom,
+        statistic,
+        integral: probabilities.iter().sum(),
+        stray_samples,
+        pdf_mismatches: 0,
+    }
+}
+
+// Counts the lobes sample_lobe takes by their index, against the probability each
+// should have.
+fn lobe_test(
+    sample_lobe: &dyn Fn(&mut dyn RngCore) -> usize,
+    probabilities: &[f64],
+    rng: &mut dyn RngCore,
+) -> TestResult {
+    let mut observed = vec![0.0; probabilities.len()];
+    for _ in 0..SAMPLES {
+        observed[sample_lobe(rng)] += 1.0;
+    }
+    test_counts(&observed, probabilities)
+}
+
+fn primitive(shape: Shape, position: Vector3<f64>, rotation: UnitQuaternion<f64>) -> Primitive {
+    Primitive {
+        name: None,
+        shape,
+        color: Default::default(),
+        texture: None,
+        uv_transform: Default::default(),
+        position,
+        rotation,
+        material: Material::DIFFUSE,
+        emission: Vector3::new(1.0, 1.0, 1.0),
+        emission_gradient: EmissionGradient::Constant,
+        light_link: LightLink::All,
+        medium: None,
+        mix: None,
+    }
+}
+
+fn light(shape: Shape, position: Vector3<f64>, rotation: UnitQuaternion<f64>) -> LightSourceDistr {
+    LightSourceDistr {
+        primitive: primitive(shape, position, rotation),
+    }
+}
+
+// The phase function of a medium for light travelling along `direction`, which takes
+// the place of the BSDF inside it.
+struct PhaseDistr {
+    medium: HomogeneousMedium,
+    direction: Vector3<f64>,
+}
+
+impl DistributionTooling for PhaseDistr {
+    fn sample(
+        &self,
+        u: [f64; 2],
+        _pick: f64,
+        _point_from: &Vector3<f64>,
+        _normal_from: &Vector3<f64>,
+    ) -> DirectionSample {
+        let direction = self.medium.sample_phase(&self.direction, u);
+        let pdf = self.medium.phase(&self.direction, &direction);
+        // the phase function is its own density
+        DirectionSample {
+            direction,
+            pdf,
+            value: pdf,
+        }
+    }
+
+    fn pdf(
+        &self,
+        _point_from: &Vector3<f64>,
+        _normal_from: &Vector3<f64>,
+        direction: &Vector3<f64>,
+    ) -> f64 {
+        self.medium.phase(&self.direction, &direction.normalize())
+    }
+}
+
+// Four triangles around the origin, smoothly shaded when normals are given.
+fn tetrahedron(normals: Vec<Vector3<f64>>) -> Shape {
+    let vertices = vec![
+        Vector3::new(-1.0, 0.0, -1.0),
+        Vector3::new(1.0, 0.0, -1.0),
+        Vector3::new(0.0, 1.5, 0.0),
+        Vector3::new(0.0, 0.0, 1.0),
+    ];
+    let triangles = vec![[0, 2, 1], [1, 2, 3], [3, 2, 0], [0, 1, 3]];
+    Shape::TriangleMesh {
+        bvh: Arc::new(Bvh::build_mesh(&vertices, &triangles)),
+        area_cdf: Arc::new(triangle_area_cdf(&vertices, &triangles)),
+        vertices: Arc::new(vertices),
+        triangles: Arc::new(triangles),
+        normals: Arc::new(normals),
+    }
+}
+
+fn random_rotation(rng: &mut dyn RngCore) -> UnitQuaternion<f64> {
+    let axis = generate_unit_on_sphere(rng);
+    UnitQuaternion::from_scaled_axis(axis * rng.gen_range(0.0..PI))
+}
+
+// Whether the origin sees a face of an area sampled flat light or mesh nearly edge-on.
+// Area sampling packs that face's whole probability into a sliver thinner than the
+// bins then.
+fn is_grazing(shape: &Shape, position: &Vector3<f64>, rotation: &UnitQuaternion<f64>) -> bool {
+    if !matches!(
+        shape,
+        Shape::Rectangle { .. } | Shape::Disc { .. } | Shape::TriangleMesh { .. }
+    ) {
+        return false;
+    }
+    let list = tessellate_shape(shape, 8);
+    list.triangles.iter().any(|triangle| {
+        let [a, b, c] = triangle.map(|index| rotation.transform_vector(&list.vertices[index]));
+        let normal = (b - a).cross(&(c - a)).normalize();
+        let center = (a + b + c) / 3.0 + position;
+        normal.dot(&center.normalize()).abs() < MIN_FACING_COSINE
+    })
+}
+
+// Dim sky over a darker ground, with a small bright sun in the given direction.
+fn test_environment_map(sun_direction: &Vector3<f64>) -> EnvironmentMap {
+    let (width, height) = (64, 32);
+    let texels = (0..width * height)
+        .map(|index| {
+            let theta = PI * ((index / width) as f64 + 0.5) / height as f64;
+            let phi = 2.0 * PI * ((index % width) as f64 + 0.5) / width as f64 - PI;
+            let direction = Vector3::new(
+                theta.sin() * phi.sin(),
+                theta.cos(),
+                -theta.sin() * phi.cos(),
+            );
+            if direction.dot(sun_direction) > 0.98 {
+                Vector3::new(50.0, 45.0, 35.0)
+            } else if direction.y > 0.0 {
+                Vector3::new(0.3, 0.4, 0.6)
+            } else {
+                Vector3::new(0.1, 0.08, 0.05)
+            }
+        })
+        .collect();
+    EnvironmentMap::new(width, height, texels)
+}
+
+// Every distribution in a few random orientations, each checked against its own pdf().
+fn test_cases(rng: &mut dyn RngCore) -> Vec<(String, Box<dyn DistributionTooling>, Vector3<f64>)> {
+    let mut cases: Vec<(String, Box<dyn DistributionTooling>, Vector3<f64>)> = vec![];
+    for rotation_index in 0..ROTATIONS {
+        let rotation = random_rotation(rng);
+        // lights sit on the equator of the bins, as the thin bins around the poles
+        // integrate poorly across the edge of a light
+        let azimuth = rng.gen_range(0.0..2.0 * PI);
+        let position = Vector3::new(azimuth.cos(), azimuth.sin(), 0.0) * 3.0;
+        let normal_from = generate_unit_on_sphere(rng);
+        let shapes = [
+            (
+                "round ellipsoid",
+                Shape::Ellipsoid {
+                    r: Vector3::repeat(1.0),
+                },
+            ),
+            ("sphere", Shape::Sphere { r: 1.0 }),
+            // area sampled pdf() has an integrable 1 / cos spike on the silhouette
+            // that the midpoint rule can't integrate, so it's tested from inside
+            (
+                "ellipsoid (from inside)",
+                Shape::Ellipsoid {
+                    r: Vector3::new(1.5, 0.5, 1.0),
+                },
+            ),
+            (
+                "box",
+                Shape::Box {
+                    s: Vector3::new(1.0, 0.4, 0.7),
+                },
+            ),
+            (
+                "rectangle",
+                Shape::Rectangle {
+                    s: Vector2::new(1.2, 0.6),
+                },
+            ),
+            ("disc", Shape::Disc { r: 1.0 }),
+            // area sampled with silhouettes like the ellipsoid, so also from inside
+            ("cylinder (from inside)", Shape::Cylinder { r: 1.0, h: 0.8 }),
+            ("cone (from inside)", Shape::Cone { r: 1.2, h: 1.0 }),
+            ("triangle mesh", tetrahedron(vec![])),
+            // shading normals must not change the light's pdf()
+            (
+                "smooth triangle mesh",
+                tetrahedron(vec![
+                    Vector3::new(-1.0, -0.5, -1.0).normalize(),
+                    Vector3::new(1.0, -0.5, -1.0).normalize(),
+                    Vector3::new(0.0, 1.0, 0.0),
+                    Vector3::new(0.0, -0.5, 1.0).normalize(),
+                ]),
+            ),
+        ];
+        let name = |distr: &str| format!("{} #{}", distr, rotation_index + 1);
+        cases.push((
+            name("cosine weighted"),
+            Box::new(CosineWeightedDistr {}),
+            normal_from,
+        ));
+        cases.push((
+            name("background"),
+            Box::new(BackgroundDistr {}),
+            normal_from,
+        ));
+        cases.push((
+            name("environment map"),
+            Box::new(EnvironmentDistr {
+                map: Arc::new(test_environment_map(&generate_unit_on_sphere(rng))),
+            }),
+            normal_from,
+        ));
+        for g in [0.0, 0.6, -0.4] {
+            cases.push((
+                name(&format!("Henyey-Greenstein phase, g = {}", g)),
+                Box::new(PhaseDistr {
+                    medium: HomogeneousMedium {
+                        sigma_a: 0.0,
+                        sigma_s: 1.0,
+                        g,
+                        shadow_rays: Default::default(),
+                    },
+                    direction: generate_unit_on_sphere(rng),
+                }),
+                normal_from,
+            ));
+        }
+        for (shape_name, shape) in shapes {
+            let position = match shape {
+                Shape::Ellipsoid { r } if r.x != r.y => position * 0.1,
+                Shape::Cylinder { .. } | Shape::Cone { .. } => position * 0.1,
+                _ => position,
+            };
+            let mut rotation = rotation;
+            while is_grazing(&shape, &position, &rotation) {
+                rotation = random_rotation(rng);
+            }
+            cases.push((
+                name(&format!("{} light", shape_name)),
+                Box::new(light(shape, position, rotation)),
+                normal_from,
+            ));
+        }
+        cases.push((
+            name("cosine weighted and sphere light mix"),
+            Box::new(MixDistr {
+                distribs: vec![
+                    Box::new(CosineWeightedDistr {}),
+                    Box::new(light(
+                        Shape::Ellipsoid {
+                            r: Vector3::repeat(1.0),
+                        },
+                        position,
+                        rotation,
+                    )),
+                ],
+            }),
+            normal_from,
+        ));
+    }
+    cases
+}
+
+// A sampler of the lobes of a material by their index, with the probability of each.
+type LobeCase = (String, Box<dyn Fn(&mut dyn RngCore) -> usize>, Vec<f64>);
+
+// The discrete choices of the specular materials: the lobe a dielectric or the car
+// paint reflects or refracts into and the material a mix picks, against their Fresnel
+// terms, flake density and mix weight.
+fn lobe_cases() -> Vec<LobeCase> {
+    let normal = Vector3::y();
+    // unit direction onto the surface at `angle` from its normal
+    let incident = |angle: f64| Vector3::new(angle.sin(), -angle.cos(), 0.0);
+    let mut cases: Vec<LobeCase> = vec![];
+
+    // the critical angle from 1.5 to 1 is 0.73
+    for (name, nu_1, nu_2, angle) in [
+        ("dielectric from outside", 1.0, 1.5, 1.0),
+        ("dielectric from inside", 1.5, 1.0, 0.5),
+        ("dielectric beyond the critical angle", 1.5, 1.0, 1.0),
+    ] {
+        let reflectance = if nu_1 / nu_2 * f64::sin(angle) > 1.0 {
+            1.0
+        } else {
+            schlick_reflectance(f64::cos(angle), nu_1, nu_2)
+        };
+        cases.push((
+            name.to_string(),
+            Box::new(move |rng| {
+                match sample_dielectric(&incident(angle), &normal, nu_1, nu_2, || rng.gen()) {
+                    DielectricLobe::Reflected(_) => 0,
+                    DielectricLobe::Refracted(_) => 1,
+                }
+            }),
+            vec![reflectance, 1.0 - reflectance],
+        ));
+    }
+
+    // at normal incidence the reflection off a flake, tilted by at most FLAKE_TILT,
+    // always leaves the surface, so flakes show as often as the density says
+    let flake_density = 0.3;
+    let clearcoat = schlick_reflectance(1.0, 1.0, CLEARCOAT_IOR);
+    cases.push((
+        "car paint".to_string(),
+        Box::new(move |rng| {
+            let ray = Ray {
+                point: normal,
+                direction: -normal,
+            };
+            let intersection = Intersection {
+                ts: vec![1.0],
+                normals: vec![normal],
+                geometric_normals: None,
+                outside: true,
+            };
+            // spread over far more flake cells than there are samples
+            let local_point = Vector3::from_fn(|_, _| rng.gen_range(-10.0..10.0));
+            let u = rng.gen();
+            match sample_car_paint(&ray, &intersection, &local_point, 0.02, flake_density, u) {
+                CarPaintLobe::Clearcoat(_) => 0,
+                CarPaintLobe::Flake(_) => 1,
+                CarPaintLobe::Base => 2,
+            }
+        }),
+        vec![
+            clearcoat,
+            (1.0 - clearcoat) * flake_density,
+            (1.0 - clearcoat) * (1.0 - flake_density),
+        ],
+    ));
+
+    for (name, weight, mix_probability) in [
+        (
+            "mix material, constant weight",
+            MixWeight::Constant(0.3),
+            0.3,
+        ),
+        (
+            "mix material, Fresnel weight",
+            MixWeight::Fresnel(1.5),
+            schlick_reflectance(f64::cos(1.0), 1.0, 1.5),
+        ),
+    ] {
+        let primitive = Primitive {
+            mix: Some(MaterialMix {
+                material: Material::METALLIC,
+                color: Vector3::repeat(1.0),
+                weight,
+            }),
+            ..primitive(
+                Shape::Sphere { r: 1.0 },
+                Vector3::zeros(),
+                UnitQuaternion::identity(),
+            )
+        };
+        cases.push((
+            name.to_string(),
+            Box::new(move |rng| {
+                let mut path = PathContext {
+                    rng: Box::new(Pcg32::seed_from_u64(rng.gen())),
+                    sampler: Sampler::new(1, SamplerType::Independent),
+                    segments: None,
+                    channel: None,
+                    throughput: Vector3::repeat(1.0),
+                    media: vec![],
+                    pass: None,
+                };
+                let ray = Ray {
+                    point: -incident(1.0),
+                    direction: incident(1.0),
+                };
+                let intersection = Intersection {
+                    ts: vec![1.0],
+                    normals: vec![normal],
+                    geometric_normals: None,
+                    outside: true,
+                };
+                let (material, _) = pick_material(&mut path, &primitive, &ray, &intersection, 0);
+                let mix = primitive.mix.as_ref().expect("the primitive has a mix");
+                usize::from(ptr::eq(material, &mix.material))
+            }),
+            vec![1.0 - mix_probability, mix_probability],
+        ));
+    }
+    cases
+}
+
+fn print_result(name: &str, result: &TestResult, passed: bool) {
+    println!(
+        "{}: {}, chi2 = {:.1}, dof = {}, p = {:.4}, pdf integral = {:.4}, stray samples = {}, pdf mismatches = {}",
+        if passed { "PASS" } else { "FAIL" },
+        name,
+        result.statistic,
+        result.degrees_of_freedom,
+        result.p_value,
+        result.integral,
+        result.stray_samples,
+        result.pdf_mismatches
+    );
+}
+
+// Checks the samples of every distribution against its pdf(), printing one line per
+// test. Returns whether all of them pass.
+pub fn run_distribution_tests() -> bool {
+    let mut rng = Pcg32::seed_from_u64(0);
+    let cases = test_cases(&mut rng);
+    // Bonferroni correction, so a correct build passes as a whole with 1 - SIGNIFICANCE
+    let threshold = SIGNIFICANCE / cases.len() as f64;
+    let point_from = Vector3::zeros();
+    let mut all_passed = true;
+    for (name, distr, normal_from) in &cases {
+        let result = chi_square_test(distr.as_ref(), &mut rng, &point_from, normal_from);
+        let passed = result.p_value >= threshold && result.pdf_mismatches == 0;
+        all_passed &= passed;
+        print_result(name, &result, passed);
+    }
+    all_passed
+}
+
+// Checks the lobes the specular materials take against their probabilities, like
+// run_distribution_tests. A lobe with no probability must never be taken.
+pub fn run_lobe_tests() -> bool {
+    let mut rng = Pcg32::seed_from_u64(0);
+    let cases = lobe_cases();
+    let threshold = SIGNIFICANCE / cases.len() as f64;
+    let mut all_passed = true;
+    for (name, sample_lobe, probabilities) in &cases {
+        let result = lobe_test(sample_lobe.as_ref(), probabilities, &mut rng);
+        let passed = result.p_value >= threshold && result.stray_samples == 0;
+        all_passed &= passed;
+        print_result(name, &result, passed);
+    }
+    all_passed
+}
+
+pub fn run_chi_square_tests() -> bool {
+    let distributions_passed = run_distribution_tests();
+    let lobes_passed = run_lobe_tests();
+    distributions_passed && lobes_passed
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // slow, best run with cargo test --release -- --ignored
+    #[test]
+    #[ignore]
+    fn distributions_sample_their_pdf() {
+        assert!(run_distribution_tests());
+    }
+
+    #[test]
+    #[ignore]
+    fn material_lobes_have_their_probabilities() {
+        assert!(run_lobe_tests());
+    }
+}
@@ -0,0 +1,98 @@
+use nalgebra::Vector3;
+
+// Dammertz et al., "Edge-Avoiding À-Trous Wavelet Transform for fast Global
+// Illumination Filtering" (2010): a 5x5 B3 spline kernel applied with holes of growing
+// size, each tap weighted by how much its color, normal and albedo differ from the
+// center pixel's.
+const KERNEL: [f64; 5] = [1.0 / 16.0, 1.0 / 4.0, 3.0 / 8.0, 1.0 / 4.0, 1.0 / 16.0];
+const ITERATIONS: u32 = 5;
+// spreads of the edge-stopping functions; the color one is halved every iteration as
+// the noise left goes down
+const COLOR_SIGMA: f64 = 0.2;
+const NORMAL_SIGMA: f64 = 0.3;
+const ALBEDO_SIGMA: f64 = 0.1;
+// floor of the albedo the color is divided by, so black surfaces keep their color
+const MIN_ALBEDO: f64 = 0.01;
+
+// Colors are compared after this compression, so that the color spread means the same
+// in dark and bright parts of the image.
+fn compress(color: &Vector3<f64>) -> Vector3<f64> {
+    color / (1.0 + color.max().max(0.0))
+}
+
+fn edge_weight(difference: f64, sigma: f64) -> f64 {
+    (-difference / (sigma * sigma)).exp()
+}
+
+// Denoised copy of a linear image of width x height pixels, row by row, guided by the
+// first-hit normal and albedo of every pixel. Surface texture is divided out before
+// filtering and multiplied back after, so only the lighting gets blurred.
+pub fn denoise_image(
+    width: usize,
+    height: usize,
+    color: &[Vector3<f64>],
+    normal: &[Vector3<f64>],
+    albedo: &[Vector3<f64>],
+) -> Vec<Vector3<f64>> {
+    let albedo: Vec<Vector3<f64>> = albedo
+        .iter()
+        .map(|albedo| albedo.map(|channel| channel.max(MIN_ALBEDO)))
+        .collect();
+    let mut lighting: Vec<Vector3<f64>> = color
+        .iter()
+        .zip(&albedo)
+        .map(|(color, albedo)| color.component_div(albedo))
+        .collect();
+
+    let mut filtered = lighting.clone();
+    for iteration in 0..ITERATIONS {
+        let step = 1 << iteration;
+        let color_sigma = COLOR_SIGMA / (1 << iteration) as f64;
+        for row in 0..height {
+            for column in 0..width {
+                let center = row * width + column;
+                let center_color = compress(&lighting[center]);
+                let mut sum = Vector3::zeros();
+                let mut weight_sum = 0.0;
+                for (dy, kernel_y) in KERNEL.iter().enumerate() {
+                    let y = row as isize + (dy as isize - 2) * step;
+                    if y < 0 || y >= height as isize {
+                        continue;
+                    }
+                    for (dx, kernel_x) in KERNEL.iter().enumerate() {
+                        let x = column as isize + (dx as isize - 2) * step;
+                        if x < 0 || x >= width as isize {
+                            continue;
+                        }
+                        let tap = y as usize * width + x as usize;
+                        let weight = kernel_x
+                            * kernel_y
+                            * edge_weight(
+                                (compress(&lighting[tap]) - center_color).norm_squared(),
+                                color_sigma,
+                            )
+                            * edge_weight(
+                                (normal[tap] - normal[center]).norm_squared(),
+                                NORMAL_SIGMA,
+                            )
+                            * edge_weight(
+                                (albedo[tap] - albedo[center]).norm_squared(),
+                                ALBEDO_SIGMA,
+                            );
+                        sum += lighting[tap] * weight;
+                        weight_sum += weight;
+                    }
+                }
+                // the center tap always has a positive weight
+                filtered[center] = sum / weight_sum;
+            }
+        }
+        std::mem::swap(&mut lighting, &mut filtered);
+    }
+
+    lighting
+        .iter()
+        .zip(&albedo)
+        .map(|(lighting, albedo)| lighting.component_mul(albedo))
+        .collect()
+}
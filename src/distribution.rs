@@ -1,20 +1,49 @@
-use std::{f64::EPSILON, f64::consts::PI, iter::zip};
+use std::{f64::consts::PI, sync::Arc};
 
 use nalgebra::Vector3;
-use rand::{rngs::ThreadRng, seq::SliceRandom, Rng};
+use rand::{Rng, RngCore};
 
 use crate::{
-    geometry::{intersect_primitive, Ray, Shape},
+    environment::{sample_cdf, EnvironmentMap},
+    frame::Frame,
+    geometry::{surface_area, surface_crossings, Ray, Shape},
     scene::Primitive,
 };
 
-pub trait DistributionTooling {
+pub struct DirectionSample {
+    pub direction: Vector3<f64>,
+    // density of the whole distribution at the direction, not only of the lobe
+    pub pdf: f64,
+    // Lambertian BRDF times cosine at the direction, zero below the surface
+    pub value: f64,
+}
+
+impl DirectionSample {
+    fn new(direction: Vector3<f64>, pdf: f64, normal_from: &Vector3<f64>) -> Self {
+        DirectionSample {
+            direction,
+            pdf,
+            value: diffuse_value(normal_from, &direction),
+        }
+    }
+}
+
+fn diffuse_value(normal_from: &Vector3<f64>, direction: &Vector3<f64>) -> f64 {
+    f64::max(0.0, direction.normalize().dot(normal_from) / PI)
+}
+
+// Directions for shading a diffuse surface at point_from. sample() reports the pdf
+// along with the direction, so callers only need pdf() for directions they did not
+// sample themselves. `u` places the direction and `pick` chooses between the discrete
+// parts of a distribution, both come from the path's sampler.
+pub trait DistributionTooling: Sync {
     fn sample(
         &self,
-        rng: &mut ThreadRng,
+        u: [f64; 2],
+        pick: f64,
         point_from: &Vector3<f64>,
         normal_from: &Vector3<f64>,
-    ) -> Vector3<f64>;
+    ) -> DirectionSample;
     fn pdf(
         &self,
         point_from: &Vector3<f64>,
@@ -23,7 +52,33 @@ pub trait DistributionTooling {
     ) -> f64;
 }
 
-pub fn generate_unit_on_sphere(rng: &mut ThreadRng) -> Vector3<f64> {
+// Picks an option in proportion to its weight and stretches the part of `pick` that
+// fell into it back over [0, 1), so that it can drive one more decision.
+fn pick_weighted(pick: f64, total: f64, weights: impl IntoIterator<Item = f64>) -> (usize, f64) {
+    let mut remaining = pick * total;
+    let mut last = (0, 0.0);
+    for (index, weight) in weights.into_iter().enumerate() {
+        if weight <= 0.0 {
+            continue;
+        }
+        if remaining < weight {
+            return (index, (remaining / weight).clamp(0.0, 1.0 - f64::EPSILON));
+        }
+        remaining -= weight;
+        last = (index, 1.0 - f64::EPSILON);
+    }
+    last
+}
+
+// z is uniform on the sphere, as the area of a slice does not depend on its height
+pub fn uniform_on_sphere(u: [f64; 2]) -> Vector3<f64> {
+    let z = 1.0 - 2.0 * u[0];
+    let radius = (1.0 - z * z).max(0.0).sqrt();
+    let phi = 2.0 * PI * u[1];
+    Vector3::new(radius * phi.cos(), radius * phi.sin(), z)
+}
+
+pub fn generate_unit_on_sphere(rng: &mut dyn RngCore) -> Vector3<f64> {
     let direction = Vector3::<f64>::new(
         rng.gen_range(-1.0..1.0),
         rng.gen_range(-1.0..1.0),
@@ -41,11 +96,21 @@ pub struct CosineWeightedDistr {}
 impl DistributionTooling for CosineWeightedDistr {
     fn sample(
         &self,
-        rng: &mut ThreadRng,
+        u: [f64; 2],
+        _pick: f64,
         _point_from: &Vector3<f64>,
         normal_from: &Vector3<f64>,
-    ) -> Vector3<f64> {
-        (generate_unit_on_sphere(rng) + normal_from * (1.0 + EPSILON)).normalize()
+    ) -> DirectionSample {
+        // Malley's method: project a uniform disk sample up onto the hemisphere
+        let radius = u[0].sqrt();
+        let phi = 2.0 * PI * u[1];
+        let local = Vector3::new(
+            radius * phi.cos(),
+            radius * phi.sin(),
+            (1.0 - radius * radius).max(0.0).sqrt(),
+        );
+        let direction = Frame::from_normal(normal_from).to_world(&local);
+        DirectionSample::new(direction, local.z / PI, normal_from)
     }
 
     fn pdf(
@@ -54,7 +119,57 @@ impl DistributionTooling for CosineWeightedDistr {
         normal_from: &Vector3<f64>,
         direction: &Vector3<f64>,
     ) -> f64 {
-        f64::max(0.0, direction.normalize().dot(normal_from) / PI)
+        diffuse_value(normal_from, direction)
+    }
+}
+
+// The constant background as a light at infinity, sampled uniformly over the sphere.
+pub struct BackgroundDistr {}
+
+impl DistributionTooling for BackgroundDistr {
+    fn sample(
+        &self,
+        u: [f64; 2],
+        _pick: f64,
+        _point_from: &Vector3<f64>,
+        normal_from: &Vector3<f64>,
+    ) -> DirectionSample {
+        DirectionSample::new(uniform_on_sphere(u), 1.0 / (4.0 * PI), normal_from)
+    }
+
+    fn pdf(
+        &self,
+        _point_from: &Vector3<f64>,
+        _normal_from: &Vector3<f64>,
+        _direction: &Vector3<f64>,
+    ) -> f64 {
+        1.0 / (4.0 * PI)
+    }
+}
+
+pub struct EnvironmentDistr {
+    pub map: Arc<EnvironmentMap>,
+}
+
+impl DistributionTooling for EnvironmentDistr {
+    fn sample(
+        &self,
+        u: [f64; 2],
+        _pick: f64,
+        _point_from: &Vector3<f64>,
+        normal_from: &Vector3<f64>,
+    ) -> DirectionSample {
+        let direction = self.map.sample(u);
+        DirectionSample::new(direction, self.map.pdf(&direction), normal_from)
+    }
+
+    fn pdf(
+        &self,
+        _point_from: &Vector3<f64>,
+        _normal_from: &Vector3<f64>,
+        direction: &Vector3<f64>,
+    ) -> f64 {
+        self.map.pdf(direction)
     }
 }
 
@@ -62,45 +177,349 @@ pub struct LightSourceDistr {
     pub primitive: Primitive,
 }
 
+// Below this solid angle the spherical rectangle formulas lose precision and area
+// sampling is just as good anyway.
+const MIN_SOLID_ANGLE: f64 = 1e-6;
+
+// Ureña et al., "An Area-Preserving Parametrization for Spherical Rectangles" (2013),
+// set up in a local frame where the rectangle lies in the plane z = z0 < 0.
+struct SphericalRectangle {
+    x: Vector3<f64>,
+    y: Vector3<f64>,
+    z: Vector3<f64>,
+    x0: f64,
+    x1: f64,
+    y0: f64,
+    y1: f64,
+    z0: f64,
+    b0: f64,
+    b1: f64,
+    k: f64,
+    solid_angle: f64,
+}
+
+fn angle_between(v1: &Vector3<f64>, v2: &Vector3<f64>) -> f64 {
+    if v1.dot(v2) < 0.0 {
+        PI - 2.0 * ((v1 + v2).norm() / 2.0).min(1.0).asin()
+    } else {
+        2.0 * ((v2 - v1).norm() / 2.0).min(1.0).asin()
+    }
+}
+
+impl SphericalRectangle {
+    fn new(
+        point: &Vector3<f64>,
+        corner: &Vector3<f64>,
+        edge_x: &Vector3<f64>,
+        edge_y: &Vector3<f64>,
+    ) -> SphericalRectangle {
+        let x = edge_x.normalize();
+        let y = edge_y.normalize();
+        let mut z = x.cross(&y);
+        let d = corner - point;
+        let mut z0 = d.dot(&z);
+        if z0 > 0.0 {
+            z = -z;
+            z0 = -z0;
+        }
+        let x0 = d.dot(&x);
+        let y0 = d.dot(&y);
+        let x1 = x0 + edge_x.norm();
+        let y1 = y0 + edge_y.norm();
+
+        let v00 = Vector3::new(x0, y0, z0);
+        let v01 = Vector3::new(x0, y1, z0);
+        let v10 = Vector3::new(x1, y0, z0);
+        let v11 = Vector3::new(x1, y1, z0);
+        let n0 = v00.cross(&v10).normalize();
+        let n1 = v10.cross(&v11).normalize();
+        let n2 = v11.cross(&v01).normalize();
+        let n3 = v01.cross(&v00).normalize();
+        let g0 = angle_between(&-n0, &n1);
+        let g1 = angle_between(&-n1, &n2);
+        let g2 = angle_between(&-n2, &n3);
+        let g3 = angle_between(&-n3, &n0);
+
+        SphericalRectangle {
+            x,
+            y,
+            z,
+            x0,
+            x1,
+            y0,
+            y1,
+            z0,
+            b0: n0.z,
+            b1: n2.z,
+            k: 2.0 * PI - g2 - g3,
+            solid_angle: g0 + g1 + g2 + g3 - 2.0 * PI,
+        }
+    }
+
+    // Direction from the reference point towards a uniformly chosen point of the
+    // rectangle's projection on the unit sphere.
+    fn sample(&self, u: f64, v: f64) -> Vector3<f64> {
+        let au = u * self.solid_angle + self.k;
+        let fu = (au.cos() * self.b0 - self.b1) / au.sin();
+        let cu = (1.0 / (fu * fu + self.b0 * self.b0).sqrt())
+            .copysign(fu)
+            .clamp(-1.0 + f64::EPSILON, 1.0 - f64::EPSILON);
+        let xu = (-(cu * self.z0) / (1.0 - cu * cu).sqrt()).clamp(self.x0, self.x1);
+        let d = (xu * xu + self.z0 * self.z0).sqrt();
+        let h0 = self.y0 / (d * d + self.y0 * self.y0).sqrt();
+        let h1 = self.y1 / (d * d + self.y1 * self.y1).sqrt();
+        let hv = h0 + v * (h1 - h0);
+        let yv = if hv * hv < 1.0 - 1e-6 {
+            hv * d / (1.0 - hv * hv).sqrt()
+        } else {
+            self.y1
+        };
+        (xu * self.x + yv * self.y + self.z0 * self.z).normalize()
+    }
+}
+
+// Faces of an axis-aligned box with half sizes `s` that face `point`, as
+// (corner, edge, edge) rectangles.
+fn visible_box_faces(
+    point: &Vector3<f64>,
+    s: &Vector3<f64>,
+) -> Vec<(Vector3<f64>, Vector3<f64>, Vector3<f64>)> {
+    let mut faces = vec![];
+    for axis in 0..3 {
+        let (u, v) = ((axis + 1) % 3, (axis + 2) % 3);
+        for sign in [1.0, -1.0] {
+            if point[axis] * sign <= s[axis] {
+                continue;
+            }
+            let mut corner = -s;
+            corner[axis] = sign * s[axis];
+            let mut edge_u = Vector3::zeros();
+            edge_u[u] = 2.0 * s[u];
+            let mut edge_v = Vector3::zeros();
+            edge_v[v] = 2.0 * s[v];
+            faces.push((corner, edge_u, edge_v));
+        }
+    }
+    faces
+}
+
+enum SolidAngleLight {
+    // uniform cone around the direction to the center of a sphere
+    Sphere {
+        center_direction: Vector3<f64>,
+        one_minus_cos_max: f64,
+    },
+    // spherical rectangles of a rectangle or the box faces seen from the point, in
+    // local space
+    Rectangles {
+        faces: Vec<SphericalRectangle>,
+        solid_angle: f64,
+    },
+}
+
+// Cone of directions to a sphere of the given radius around `center_direction`, None
+// from inside of it.
+fn sphere_light(
+    local_point: &Vector3<f64>,
+    center_direction: Vector3<f64>,
+    radius: f64,
+) -> Option<SolidAngleLight> {
+    let distance_squared = local_point.norm_squared();
+    if distance_squared <= radius * radius {
+        return None;
+    }
+    let sin_squared_max = radius * radius / distance_squared;
+    let cos_max = (1.0 - sin_squared_max).sqrt();
+    Some(SolidAngleLight::Sphere {
+        center_direction,
+        one_minus_cos_max: sin_squared_max / (1.0 + cos_max),
+    })
+}
+
+fn rectangles_light(faces: Vec<SphericalRectangle>) -> Option<SolidAngleLight> {
+    let solid_angle: f64 = faces.iter().map(|face| face.solid_angle).sum();
+    if faces.is_empty() || solid_angle < MIN_SOLID_ANGLE {
+        return None;
+    }
+    Some(SolidAngleLight::Rectangles { faces, solid_angle })
+}
+
+impl LightSourceDistr {
+    // Solid-angle sampling applies to spheres and boxes seen from outside and to
+    // rectangles; other cases fall back to area sampling.
+    fn solid_angle_light(&self, point_from: &Vector3<f64>) -> Option<SolidAngleLight> {
+        let local_point = self
+            .primitive
+            .rotation
+            .conjugate()
+            .transform_vector(&(point_from - self.primitive.position));
+        let center_direction = (self.primitive.position - point_from).normalize();
+        match self.primitive.shape {
+            Shape::Sphere { r } => sphere_light(&local_point, center_direction, r),
+            Shape::Ellipsoid { r } => {
+                let radius = r.x;
+                let is_sphere =
+                    (r.y - radius).abs() <= 1e-9 * radius && (r.z - radius).abs() <= 1e-9 * radius;
+                if !is_sphere {
+                    return None;
+                }
+                sphere_light(&local_point, center_direction, radius)
+            }
+            Shape::Box { s } => rectangles_light(
+                visible_box_faces(&local_point, &s)
+                    .iter()
+                    .map(|(corner, edge_u, edge_v)| {
+                        SphericalRectangle::new(&local_point, corner, edge_u, edge_v)
+                    })
+                    .collect(),
+            ),
+            // points in the plane of the rectangle see it edge-on
+            Shape::Rectangle { s } if local_point.y.abs() > f64::EPSILON => {
+                rectangles_light(vec![SphericalRectangle::new(
+                    &local_point,
+                    &Vector3::new(-s.x, 0.0, -s.y),
+                    &Vector3::new(2.0 * s.x, 0.0, 0.0),
+                    &Vector3::new(0.0, 0.0, 2.0 * s.y),
+                )])
+            }
+            Shape::Plane { normal: _ }
+            | Shape::Disc { r: _ }
+            | Shape::Rectangle { s: _ }
+            | Shape::Cylinder { .. }
+            | Shape::Cone { .. }
+            | Shape::TriangleMesh { .. } => None,
+        }
+    }
+}
+
 impl DistributionTooling for LightSourceDistr {
     fn sample(
         &self,
-        rng: &mut ThreadRng,
+        u: [f64; 2],
+        pick: f64,
         point_from: &Vector3<f64>,
-        _normal_from: &Vector3<f64>,
-    ) -> Vector3<f64> {
-        let mut generate_rand_local_point = || -> Vector3<f64> {
-            match self.primitive.shape {
-                Shape::Plane { normal: _ } => Default::default(),
-
-                Shape::Box { s } => {
-                    let w_x = 4.0 * s.y * s.z;
-                    let w_y = 4.0 * s.x * s.z;
-                    let w_z = 4.0 * s.x * s.y;
-                    let rnd_face = rng.gen_range(0.0..(w_x + w_y + w_z));
-                    let rnd_sign = if rng.gen_bool(0.5) { 1.0 } else { -1.0 };
-                    let rnd_val1 = rng.gen_range(-1.0..1.0);
-                    let rnd_val2 = rng.gen_range(-1.0..1.0);
-                    if rnd_face < w_x {
-                        Vector3::<f64>::new(s.x * rnd_sign, s.y * rnd_val1, s.z * rnd_val2)
-                    } else if rnd_face < w_x + w_y {
-                        Vector3::<f64>::new(s.x * rnd_val1, s.y * rnd_sign, s.z * rnd_val2)
-                    } else {
-                        Vector3::<f64>::new(s.x * rnd_val1, s.y * rnd_val2, s.z * rnd_sign)
+        normal_from: &Vector3<f64>,
+    ) -> DirectionSample {
+        match self.solid_angle_light(point_from) {
+            Some(SolidAngleLight::Sphere {
+                center_direction,
+                one_minus_cos_max,
+            }) => {
+                let cos_theta = 1.0 - u[0] * one_minus_cos_max;
+                let sin_theta = (1.0 - cos_theta * cos_theta).max(0.0).sqrt();
+                let phi = 2.0 * PI * u[1];
+                let local = Vector3::new(sin_theta * phi.cos(), sin_theta * phi.sin(), cos_theta);
+                return DirectionSample::new(
+                    Frame::from_normal(&center_direction).to_world(&local),
+                    1.0 / (2.0 * PI * one_minus_cos_max),
+                    normal_from,
+                );
+            }
+            Some(SolidAngleLight::Rectangles { faces, solid_angle }) => {
+                let (face, _) =
+                    pick_weighted(pick, solid_angle, faces.iter().map(|face| face.solid_angle));
+                let local_direction = faces[face].sample(u[0], u[1]);
+                return DirectionSample::new(
+                    self.primitive.rotation.transform_vector(&local_direction),
+                    1.0 / solid_angle,
+                    normal_from,
+                );
+            }
+            None => {}
+        }
+
+        let local_point = match self.primitive.shape {
+            Shape::Plane { normal: _ } => Default::default(),
+
+            // both sides of each axis, in proportion to the face areas
+            Shape::Box { s } => {
+                let face_area = |axis: usize| 4.0 * s[(axis + 1) % 3] * s[(axis + 2) % 3];
+                let (face, _) = pick_weighted(
+                    pick,
+                    2.0 * (face_area(0) + face_area(1) + face_area(2)),
+                    (0..6).map(|face| face_area(face / 2)),
+                );
+                let (axis, sign) = (face / 2, if face % 2 == 0 { 1.0 } else { -1.0 });
+                let mut point = Vector3::zeros();
+                point[axis] = s[axis] * sign;
+                point[(axis + 1) % 3] = s[(axis + 1) % 3] * (2.0 * u[0] - 1.0);
+                point[(axis + 2) % 3] = s[(axis + 2) % 3] * (2.0 * u[1] - 1.0);
+                point
+            }
+
+            Shape::Ellipsoid { r } => uniform_on_sphere(u).component_mul(&r),
+
+            Shape::Sphere { r } => uniform_on_sphere(u) * r,
+
+            // side or caps in proportion to their areas
+            Shape::Cylinder { r, h } => {
+                let side_area = 4.0 * PI * r * h;
+                let cap_area = PI * r * r;
+                let phi = 2.0 * PI * u[0];
+                match pick_weighted(
+                    pick,
+                    side_area + 2.0 * cap_area,
+                    [side_area, cap_area, cap_area],
+                ) {
+                    (0, _) => Vector3::new(r * phi.cos(), h * (2.0 * u[1] - 1.0), r * phi.sin()),
+                    (cap, _) => {
+                        let radius = r * u[1].sqrt();
+                        let y = if cap == 1 { h } else { -h };
+                        Vector3::new(radius * phi.cos(), y, radius * phi.sin())
                     }
                 }
+            }
+
+            // the distance to the axis grows like the square root on the base and
+            // on the side alike, as both areas grow with its square
+            Shape::Cone { r, h } => {
+                let base_area = PI * r * r;
+                let phi = 2.0 * PI * u[0];
+                let radius = r * u[1].sqrt();
+                let y = if pick * surface_area(&self.primitive.shape) < base_area {
+                    -h
+                } else {
+                    h - 2.0 * h * radius / r
+                };
+                Vector3::new(radius * phi.cos(), y, radius * phi.sin())
+            }
+
+            Shape::Rectangle { s } => {
+                Vector3::<f64>::new(s.x * (2.0 * u[0] - 1.0), 0.0, s.y * (2.0 * u[1] - 1.0))
+            }
 
-                Shape::Ellipsoid { r } => generate_unit_on_sphere(rng).component_mul(&r),
+            Shape::Disc { r } => {
+                let radius = r * u[0].sqrt();
+                let phi = 2.0 * PI * u[1];
+                Vector3::<f64>::new(radius * phi.cos(), 0.0, radius * phi.sin())
+            }
+
+            Shape::TriangleMesh {
+                ref vertices,
+                ref triangles,
+                ref area_cdf,
+                ..
+            } => {
+                let (triangle, _) = sample_cdf(area_cdf, pick);
+                let [a, b, c] = triangles[triangle];
+                let sqrt_u = u[0].sqrt();
+                vertices[a] * (1.0 - sqrt_u)
+                    + vertices[b] * (sqrt_u * (1.0 - u[1]))
+                    + vertices[c] * (sqrt_u * u[1])
             }
         };
 
-        (self
-            .primitive
-            .rotation
-            .transform_vector(&generate_rand_local_point())
+        let direction = (self.primitive.rotation.transform_vector(&local_point)
             + self.primitive.position
             - point_from)
-            .normalize()
+            .normalize();
+        // other points of the surface may lie along the same direction, so the
+        // density comes from intersecting the whole shape
+        DirectionSample::new(
+            direction,
+            self.pdf(point_from, normal_from, &direction),
+            normal_from,
+        )
     }
 
     fn pdf(
@@ -109,17 +528,28 @@ impl DistributionTooling for LightSourceDistr {
         _normal_from: &Vector3<f64>,
         direction: &Vector3<f64>,
     ) -> f64 {
-        let Some(intersection) = intersect_primitive(
+        let crossings = surface_crossings(
             &Ray {
                 point: *point_from,
                 direction: *direction,
             },
             &self.primitive,
-        ) else {
+        );
+        if crossings.is_empty() {
             return 0.0;
-        };
+        }
+
+        match self.solid_angle_light(point_from) {
+            Some(SolidAngleLight::Sphere {
+                one_minus_cos_max, ..
+            }) => return 1.0 / (2.0 * PI * one_minus_cos_max),
+            Some(SolidAngleLight::Rectangles { solid_angle, .. }) => return 1.0 / solid_angle,
+            None => {}
+        }
 
-        zip(intersection.ts, intersection.normals)
+        // the area to solid angle conversion needs the true surface orientation
+        crossings
+            .into_iter()
             .map(|(t, normal)| {
                 let intersection_point = point_from + t * direction;
 
@@ -132,6 +562,12 @@ impl DistributionTooling for LightSourceDistr {
                 let local_pdf = match self.primitive.shape {
                     Shape::Plane { normal: _ } => Default::default(),
                     Shape::Box { s } => 1.0 / 8.0 / (s.x * s.y + s.x * s.z + s.y * s.z),
+                    Shape::Rectangle { s } => 1.0 / 4.0 / (s.x * s.y),
+                    Shape::Disc { r } => 1.0 / PI / (r * r),
+                    Shape::Sphere { .. } | Shape::Cylinder { .. } | Shape::Cone { .. } => {
+                        1.0 / surface_area(&self.primitive.shape)
+                    }
+                    Shape::TriangleMesh { ref area_cdf, .. } => 1.0 / area_cdf[area_cdf.len() - 1],
                     Shape::Ellipsoid { r } => {
                         let n = local_point.component_div(&r);
 
@@ -159,18 +595,26 @@ pub struct MixDistr {
 impl DistributionTooling for MixDistr {
     fn sample(
         &self,
-        rng: &mut ThreadRng,
+        u: [f64; 2],
+        pick: f64,
         point_from: &Vector3<f64>,
         normal_from: &Vector3<f64>,
-    ) -> Vector3<f64> {
-        self.distribs
-            .choose(rng)
-            .expect("Empty distribution vector in mixed distribution.")
-            .sample(rng, point_from, normal_from)
-
-        // let n = self.distribs.len();
-        // let rand_idx = rng.gen_range(0..n);
-        // self.distribs[rand_idx].sample(rng, point_from, normal_from)
+    ) -> DirectionSample {
+        let count = self.distribs.len();
+        let (index, pick) = pick_weighted(pick, count as f64, std::iter::repeat_n(1.0, count));
+        let sample = self.distribs[index].sample(u, pick, point_from, normal_from);
+        // the chosen component already knows its own density
+        let others_pdf = self
+            .distribs
+            .iter()
+            .enumerate()
+            .filter(|&(other, _)| other != index)
+            .map(|(_, distr)| distr.pdf(point_from, normal_from, &sample.direction))
+            .sum::<f64>();
+        DirectionSample {
+            pdf: (sample.pdf + others_pdf) / self.distribs.len() as f64,
+            ..sample
+        }
     }
 
     fn pdf(&self, point_from: &Vector3<f64>, normal: &Vector3<f64>, dir: &Vector3<f64>) -> f64 {
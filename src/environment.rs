@@ -0,0 +1,222 @@
+use std::f64::consts::PI;
+
+use nalgebra::Vector3;
+use rand::{Rng, SeedableRng};
+use rand_distr::{Distribution, Normal};
+use rand_pcg::Pcg32;
+
+use crate::assets::Assets;
+use crate::distribution::generate_unit_on_sphere;
+use crate::frame::Frame;
+use crate::texture::{load_texture, ColorSpace};
+
+// Equirectangular image around the scene. +Y is up: the top row looks straight up and
+// the center of the image looks along -Z. Texels are picked with probability
+// proportional to their luminance times the solid angle they cover.
+pub struct EnvironmentMap {
+    width: usize,
+    height: usize,
+    texels: Vec<Vector3<f64>>,
+    texel_probabilities: Vec<f64>,
+    // cumulative probabilities of the rows, and of the texels within each row
+    row_cdf: Vec<f64>,
+    column_cdfs: Vec<f64>,
+}
+
+pub(crate) fn luminance(color: &Vector3<f64>) -> f64 {
+    0.2126 * color.x + 0.7152 * color.y + 0.0722 * color.z
+}
+
+// Texel of a width x height equirectangular image seen in the direction.
+fn texel_index(width: usize, height: usize, direction: &Vector3<f64>) -> usize {
+    let direction = direction.normalize();
+    let u = direction.x.atan2(-direction.z) / (2.0 * PI) + 0.5;
+    let v = direction.y.clamp(-1.0, 1.0).acos() / PI;
+    let column = ((u * width as f64) as usize).min(width - 1);
+    let row = ((v * height as f64) as usize).min(height - 1);
+    row * width + column
+}
+
+// Index of the first entry of a cumulative distribution above u * total, along with
+// where u * total falls inside that entry's interval, from 0 to 1.
+pub(crate) fn sample_cdf(cdf: &[f64], u: f64) -> (usize, f64) {
+    let target = u * cdf[cdf.len() - 1];
+    let index = cdf
+        .partition_point(|&value| value <= target)
+        .min(cdf.len() - 1);
+    let start = if index == 0 { 0.0 } else { cdf[index - 1] };
+    let offset = if cdf[index] > start {
+        (target - start) / (cdf[index] - start)
+    } else {
+        0.5
+    };
+    (index, offset.clamp(0.0, 1.0 - f64::EPSILON))
+}
+
+impl EnvironmentMap {
+    pub fn new(width: usize, height: usize, texels: Vec<Vector3<f64>>) -> EnvironmentMap {
+        let mut texel_probabilities: Vec<f64> = texels
+            .iter()
+            .enumerate()
+            .map(|(index, texel)| {
+                let theta = ((index / width) as f64 + 0.5) / height as f64 * PI;
+                luminance(texel).max(0.0) * theta.sin()
+            })
+            .collect();
+        let total = texel_probabilities.iter().sum::<f64>();
+        if total > 0.0 {
+            texel_probabilities
+                .iter_mut()
+                .for_each(|probability| *probability /= total);
+        }
+
+        let mut row_cdf = Vec::with_capacity(height);
+        let mut column_cdfs = Vec::with_capacity(width * height);
+        for row in texel_probabilities.chunks(width) {
+            let row_total = row.iter().sum::<f64>();
+            row_cdf.push(row_cdf.last().unwrap_or(&0.0) + row_total);
+            let mut accumulated = 0.0;
+            for probability in row {
+                accumulated += probability;
+                column_cdfs.push(accumulated);
+            }
+        }
+
+        EnvironmentMap {
+            width,
+            height,
+            texels,
+            texel_probabilities,
+            row_cdf,
+            column_cdfs,
+        }
+    }
+
+    // Whether the map emits any light, and so can be importance sampled.
+    pub fn is_emitting(&self) -> bool {
+        self.row_cdf.last().is_some_and(|&total| total > 0.0)
+    }
+
+    fn texel_index(&self, direction: &Vector3<f64>) -> usize {
+        texel_index(self.width, self.height, direction)
+    }
+
+    pub fn radiance(&self, direction: &Vector3<f64>) -> Vector3<f64> {
+        self.texels[self.texel_index(direction)]
+    }
+
+    // Picks a texel with the first two numbers and a point inside it with the others.
+    pub fn sample(&self, u: [f64; 2]) -> Vector3<f64> {
+        let (row, row_offset) = sample_cdf(&self.row_cdf, u[0]);
+        let columns = &self.column_cdfs[row * self.width..(row + 1) * self.width];
+        let (column, column_offset) = sample_cdf(columns, u[1]);
+        let phi = 2.0 * PI * ((column as f64 + column_offset) / self.width as f64 - 0.5);
+        let theta = PI * (row as f64 + row_offset) / self.height as f64;
+        Vector3::new(
+            theta.sin() * phi.sin(),
+            theta.cos(),
+            -theta.sin() * phi.cos(),
+        )
+    }
+
+    // A texel covers (2 pi / width) * (pi / height) of (phi, theta) space, and a unit
+    // of that space spans sin(theta) of solid angle.
+    pub fn pdf(&self, direction: &Vector3<f64>) -> f64 {
+        let sin_theta = (1.0 - direction.normalize().y.powi(2)).max(0.0).sqrt();
+        if sin_theta <= f64::EPSILON {
+            return 0.0;
+        }
+        self.texel_probabilities[self.texel_index(direction)] * (self.width * self.height) as f64
+            / (2.0 * PI * PI * sin_theta)
+    }
+}
+
+pub fn load_environment_map(
+    assets: &Assets,
+    path: &str,
+    color_space: ColorSpace,
+) -> Result<EnvironmentMap, String> {
+    let image = load_texture(assets, path, color_space)
+        .map_err(|error| format!("cannot read environment map: {}", error))?;
+    Ok(EnvironmentMap::new(image.width, image.height, image.texels))
+}
+
+// Night sky generated in place of an environment map. Star fluxes follow the power law
+// of star counts, N(> flux) ~ flux^-1.5, from `brightness`, the irradiance of the
+// faintest star, up to STAR_MAX_FACTOR times that. With a band, half of the stars
+// gather along it over a glow of radiance `band` at the core.
+pub struct Starfield {
+    pub count: u32,
+    pub brightness: f64,
+    pub band: f64,
+    pub seed: u64,
+}
+
+// Every star is one texel, so this is also how sharp they are.
+const STARFIELD_WIDTH: usize = 2048;
+const STARFIELD_HEIGHT: usize = 1024;
+const STAR_COUNT_EXPONENT: f64 = 1.5;
+const STAR_MAX_FACTOR: f64 = 1000.0;
+// tints of the coolest and hottest stars, of unit luminance
+const STAR_COOL_TINT: Vector3<f64> = Vector3::new(1.25, 0.95, 0.7);
+const STAR_HOT_TINT: Vector3<f64> = Vector3::new(0.8, 0.98, 1.35);
+// the band circles the sky tilted like the Milky Way against the celestial equator,
+// brightest towards its core
+const BAND_NORMAL: Vector3<f64> = Vector3::new(0.0, 0.45, 0.89);
+const BAND_WIDTH: f64 = 0.15;
+const BAND_COLOR: Vector3<f64> = Vector3::new(0.95, 0.95, 1.05);
+
+pub fn generate_starfield(starfield: &Starfield) -> EnvironmentMap {
+    let mut rng = Pcg32::seed_from_u64(starfield.seed);
+    let band_frame = Frame::from_normal(&BAND_NORMAL.normalize());
+    let band_latitude = Normal::new(0.0, BAND_WIDTH).expect("Band width is not positive.");
+
+    let mut texels: Vec<Vector3<f64>> = (0..STARFIELD_WIDTH * STARFIELD_HEIGHT)
+        .map(|index| {
+            if starfield.band <= 0.0 {
+                return Vector3::zeros();
+            }
+            let theta = PI * ((index / STARFIELD_WIDTH) as f64 + 0.5) / STARFIELD_HEIGHT as f64;
+            let phi =
+                2.0 * PI * ((index % STARFIELD_WIDTH) as f64 + 0.5) / STARFIELD_WIDTH as f64 - PI;
+            let direction = Vector3::new(
+                theta.sin() * phi.sin(),
+                theta.cos(),
+                -theta.sin() * phi.cos(),
+            );
+            let latitude = direction.dot(&band_frame.normal).clamp(-1.0, 1.0).asin();
+            let longitude = direction
+                .dot(&band_frame.bitangent)
+                .atan2(direction.dot(&band_frame.tangent));
+            let core = ((1.0 + longitude.cos()) / 2.0).powi(2);
+            BAND_COLOR
+                * (starfield.band
+                    * (-latitude * latitude / (2.0 * BAND_WIDTH * BAND_WIDTH)).exp()
+                    * (0.4 + 0.6 * core))
+        })
+        .collect();
+
+    for _ in 0..starfield.count {
+        let direction = if starfield.band > 0.0 && rng.gen_bool(0.5) {
+            let latitude: f64 = band_latitude.sample(&mut rng);
+            let longitude = rng.gen_range(-PI..PI);
+            (band_frame.tangent * longitude.cos() + band_frame.bitangent * longitude.sin())
+                * latitude.cos()
+                + band_frame.normal * latitude.sin()
+        } else {
+            generate_unit_on_sphere(&mut rng)
+        };
+        let flux = starfield.brightness
+            * (1.0 - rng.gen::<f64>())
+                .powf(-1.0 / STAR_COUNT_EXPONENT)
+                .min(STAR_MAX_FACTOR);
+        let tint = STAR_COOL_TINT.lerp(&STAR_HOT_TINT, rng.gen());
+        // spread over the solid angle of its texel, so that it lights the scene the
+        // same wherever it lands
+        let index = texel_index(STARFIELD_WIDTH, STARFIELD_HEIGHT, &direction);
+        let theta = PI * ((index / STARFIELD_WIDTH) as f64 + 0.5) / STARFIELD_HEIGHT as f64;
+        let solid_angle = 2.0 * PI * PI * theta.sin() / (STARFIELD_WIDTH * STARFIELD_HEIGHT) as f64;
+        texels[index] += tint * (flux / solid_angle);
+    }
+    EnvironmentMap::new(STARFIELD_WIDTH, STARFIELD_HEIGHT, texels)
+}
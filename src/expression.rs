@@ -0,0 +1,164 @@
+use std::collections::HashMap;
+use std::iter::Peekable;
+use std::str::Chars;
+
+// Arithmetic over numbers and $variables with + - * /, unary signs and parentheses,
+// for numeric fields of the scene format. None when the text is not such an
+// expression or uses an undefined variable.
+pub fn evaluate(expression: &str, variables: &HashMap<String, f64>) -> Option<f64> {
+    let mut parser = Parser {
+        chars: expression.chars().peekable(),
+        variables,
+    };
+    let value = parser.sum()?;
+    parser.chars.peek().is_none().then_some(value)
+}
+
+pub fn is_variable_name(name: &str) -> bool {
+    name.chars()
+        .next()
+        .is_some_and(|first| first.is_ascii_alphabetic() || first == '_')
+        && name
+            .chars()
+            .all(|char| char.is_ascii_alphanumeric() || char == '_')
+}
+
+struct Parser<'a> {
+    chars: Peekable<Chars<'a>>,
+    variables: &'a HashMap<String, f64>,
+}
+
+impl Parser<'_> {
+    fn skip_spaces(&mut self) {
+        while self.chars.next_if(|char| char.is_whitespace()).is_some() {}
+    }
+
+    fn next_operator(&mut self, operators: &[char]) -> Option<char> {
+        self.skip_spaces();
+        self.chars.next_if(|char| operators.contains(char))
+    }
+
+    fn sum(&mut self) -> Option<f64> {
+        let mut value = self.product()?;
+        while let Some(operator) = self.next_operator(&['+', '-']) {
+            let operand = self.product()?;
+            value = if operator == '+' {
+                value + operand
+            } else {
+                value - operand
+            };
+        }
+        Some(value)
+    }
+
+    fn product(&mut self) -> Option<f64> {
+        let mut value = self.factor()?;
+        while let Some(operator) = self.next_operator(&['*', '/']) {
+            let operand = self.factor()?;
+            value = if operator == '*' {
+                value * operand
+            } else {
+                value / operand
+            };
+        }
+        Some(value)
+    }
+
+    fn factor(&mut self) -> Option<f64> {
+        self.skip_spaces();
+        match self.chars.next()? {
+            '-' => Some(-self.factor()?),
+            '+' => self.factor(),
+            '(' => {
+                let value = self.sum()?;
+                (self.next_operator(&[')']).is_some()).then_some(value)
+            }
+            '$' => {
+                let mut name = String::new();
+                while let Some(char) = self
+                    .chars
+                    .next_if(|char| char.is_ascii_alphanumeric() || *char == '_')
+                {
+                    name.push(char);
+                }
+                self.variables.get(&name).copied()
+            }
+            first if first.is_ascii_digit() || first == '.' => {
+                let mut number = first.to_string();
+                while let Some(char) = self.chars.next_if(|char| {
+                    char.is_ascii_digit()
+                        || *char == '.'
+                        || *char == 'e'
+                        || *char == 'E'
+                        || ((*char == '-' || *char == '+') && number.ends_with(['e', 'E']))
+                }) {
+                    number.push(char);
+                }
+                number.parse().ok()
+            }
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn variables() -> HashMap<String, f64> {
+        HashMap::from([("width".to_string(), 4.0), ("h_2".to_string(), 0.5)])
+    }
+
+    #[test]
+    fn numbers() {
+        assert_eq!(evaluate("42", &variables()), Some(42.0));
+        assert_eq!(evaluate(".5", &variables()), Some(0.5));
+        assert_eq!(evaluate("1.5e-3", &variables()), Some(0.0015));
+        assert_eq!(evaluate("2E+2", &variables()), Some(200.0));
+    }
+
+    #[test]
+    fn operator_precedence() {
+        assert_eq!(evaluate("1+2*3", &variables()), Some(7.0));
+        assert_eq!(evaluate("(1+2)*3", &variables()), Some(9.0));
+        assert_eq!(evaluate("8/4/2", &variables()), Some(1.0));
+        assert_eq!(evaluate("10-4-3", &variables()), Some(3.0));
+        assert_eq!(evaluate(" 1 + 2 * ( 3 - 1 ) ", &variables()), Some(5.0));
+    }
+
+    #[test]
+    fn unary_signs() {
+        assert_eq!(evaluate("-3", &variables()), Some(-3.0));
+        assert_eq!(evaluate("--3", &variables()), Some(3.0));
+        assert_eq!(evaluate("2*-3", &variables()), Some(-6.0));
+        assert_eq!(evaluate("+(1-4)", &variables()), Some(-3.0));
+    }
+
+    #[test]
+    fn variables_are_substituted() {
+        assert_eq!(evaluate("$width", &variables()), Some(4.0));
+        assert_eq!(evaluate("$width/2+$h_2", &variables()), Some(2.5));
+        assert_eq!(evaluate("-$width", &variables()), Some(-4.0));
+    }
+
+    #[test]
+    fn invalid_expressions() {
+        assert_eq!(evaluate("$depth", &variables()), None);
+        assert_eq!(evaluate("", &variables()), None);
+        assert_eq!(evaluate("1+", &variables()), None);
+        assert_eq!(evaluate("(1+2", &variables()), None);
+        assert_eq!(evaluate("1+2)", &variables()), None);
+        assert_eq!(evaluate("1 2", &variables()), None);
+        assert_eq!(evaluate("textures/wood.png", &variables()), None);
+        assert_eq!(evaluate("1.2.3", &variables()), None);
+    }
+
+    #[test]
+    fn variable_names() {
+        assert!(is_variable_name("width"));
+        assert!(is_variable_name("_h2"));
+        assert!(!is_variable_name("2h"));
+        assert!(!is_variable_name("a-b"));
+        assert!(!is_variable_name(""));
+    }
+}
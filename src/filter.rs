@@ -0,0 +1,131 @@
+use std::f64::consts::PI;
+
+const TABLE_SIZE: usize = 256;
+
+// Reconstruction filter that pixel samples are distributed by. Center keeps every
+// sample on the pixel center.
+#[derive(Clone, Copy)]
+pub enum PixelFilter {
+    Center,
+    Box,
+    Mitchell { b: f64, c: f64 },
+    BlackmanHarris,
+}
+
+pub fn parse_pixel_filter(tokens: &[String]) -> Option<PixelFilter> {
+    match tokens[0].as_str() {
+        "CENTER" => Some(PixelFilter::Center),
+        "BOX" => Some(PixelFilter::Box),
+        "MITCHELL" => {
+            // the Mitchell–Netravali recommendation B = C = 1/3 when not given
+            let b = tokens.get(1).map_or(Some(1.0 / 3.0), |b| b.parse().ok())?;
+            let c = tokens.get(2).map_or(Some(1.0 / 3.0), |c| c.parse().ok())?;
+            Some(PixelFilter::Mitchell { b, c })
+        }
+        "BLACKMAN_HARRIS" => Some(PixelFilter::BlackmanHarris),
+        _ => None,
+    }
+}
+
+fn filter_radius(filter: &PixelFilter) -> f64 {
+    match filter {
+        PixelFilter::Center => 0.0,
+        PixelFilter::Box => 0.5,
+        PixelFilter::Mitchell { b: _, c: _ } | PixelFilter::BlackmanHarris => 2.0,
+    }
+}
+
+fn mitchell(x: f64, b: f64, c: f64) -> f64 {
+    let x = x.abs();
+    if x < 1.0 {
+        ((12.0 - 9.0 * b - 6.0 * c) * x.powi(3)
+            + (-18.0 + 12.0 * b + 6.0 * c) * x.powi(2)
+            + (6.0 - 2.0 * b))
+            / 6.0
+    } else if x < 2.0 {
+        ((-b - 6.0 * c) * x.powi(3)
+            + (6.0 * b + 30.0 * c) * x.powi(2)
+            + (-12.0 * b - 48.0 * c) * x
+            + (8.0 * b + 24.0 * c))
+            / 6.0
+    } else {
+        0.0
+    }
+}
+
+// One dimension of the separable filter. Mitchell's lobes are negative for B and C
+// below 1/2.
+fn evaluate_filter(filter: &PixelFilter, x: f64) -> f64 {
+    let radius = filter_radius(filter);
+    match filter {
+        PixelFilter::Center | PixelFilter::Box => 1.0,
+        PixelFilter::Mitchell { b, c } => mitchell(x, *b, *c),
+        PixelFilter::BlackmanHarris => {
+            let t = (x + radius) / (2.0 * radius);
+            0.35875 - 0.48829 * (2.0 * PI * t).cos() + 0.14128 * (4.0 * PI * t).cos()
+                - 0.01168 * (6.0 * PI * t).cos()
+        }
+    }
+}
+
+// Tabulated inverse CDF of the filter's absolute value over [-radius, radius]. As in
+// pbrt, a sample from a negative lobe carries a negative weight, so the weighted mean
+// of the samples converges to the filtered image.
+pub struct FilterSampler {
+    radius: f64,
+    cdf: Vec<f64>,
+    negative: Vec<bool>,
+    // integral of |f| over the integral of f, which makes the weights average to 1
+    weight: f64,
+}
+
+impl FilterSampler {
+    pub fn new(filter: &PixelFilter) -> FilterSampler {
+        let radius = filter_radius(filter);
+        let mut cdf = vec![0.0];
+        let mut negative = vec![];
+        let mut signed_total = 0.0;
+        for bin in 0..TABLE_SIZE {
+            let x = -radius + 2.0 * radius * (bin as f64 + 0.5) / TABLE_SIZE as f64;
+            let value = evaluate_filter(filter, x);
+            cdf.push(cdf[bin] + value.abs());
+            negative.push(value < 0.0);
+            signed_total += value;
+        }
+        let total = cdf[TABLE_SIZE];
+        cdf.iter_mut().for_each(|value| *value /= total);
+        FilterSampler {
+            radius,
+            cdf,
+            negative,
+            weight: total / signed_total,
+        }
+    }
+
+    // Offset from the pixel center for a uniform `u`, and the weight of the sample.
+    pub fn sample(&self, u: f64) -> (f64, f64) {
+        if self.radius == 0.0 {
+            return (0.0, 1.0);
+        }
+        let bin = self
+            .cdf
+            .partition_point(|&value| value <= u)
+            .clamp(1, TABLE_SIZE)
+            - 1;
+        let width = self.cdf[bin + 1] - self.cdf[bin];
+        let within = if width > 0.0 {
+            (u - self.cdf[bin]) / width
+        } else {
+            0.5
+        };
+        let weight = if self.negative[bin] {
+            -self.weight
+        } else {
+            self.weight
+        };
+        (
+            -self.radius + 2.0 * self.radius * (bin as f64 + within) / TABLE_SIZE as f64,
+            weight,
+        )
+    }
+}
@@ -0,0 +1,31 @@
+use nalgebra::Vector3;
+
+// Orthonormal tangent frame around a unit normal.
+pub struct Frame {
+    pub tangent: Vector3<f64>,
+    pub bitangent: Vector3<f64>,
+    pub normal: Vector3<f64>,
+}
+
+impl Frame {
+    // Duff et al., "Building an Orthonormal Basis, Revisited" (2017): branchless and
+    // continuous everywhere except across the z = 0 plane.
+    pub fn from_normal(normal: &Vector3<f64>) -> Frame {
+        let sign = 1.0_f64.copysign(normal.z);
+        let a = -1.0 / (sign + normal.z);
+        let b = normal.x * normal.y * a;
+        Frame {
+            tangent: Vector3::new(
+                1.0 + sign * normal.x * normal.x * a,
+                sign * b,
+                -sign * normal.x,
+            ),
+            bitangent: Vector3::new(b, sign + normal.y * normal.y * a, -normal.y),
+            normal: *normal,
+        }
+    }
+
+    pub fn to_world(&self, local: &Vector3<f64>) -> Vector3<f64> {
+        local.x * self.tangent + local.y * self.bitangent + local.z * self.normal
+    }
+}
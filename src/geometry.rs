@@ -1,12 +1,80 @@
-use nalgebra::Vector3;
+use std::cell::Cell;
+use std::f64::consts::PI;
+use std::sync::Arc;
 
+use nalgebra::{Vector2, Vector3};
+
+use crate::bvh::Bvh;
+use crate::frame::Frame;
 use crate::scene::{Primitive, Scene};
 
 #[derive(Clone)]
 pub enum Shape {
     Plane { normal: Vector3<f64> },
     Ellipsoid { r: Vector3<f64> },
+    Sphere { r: f64 },
     Box { s: Vector3<f64> },
+    // closed solids around the local y axis, from y = -h to y = h; the cone narrows
+    // from its base at -h to its tip at h
+    Cylinder { r: f64, h: f64 },
+    Cone { r: f64, h: f64 },
+    // thin shapes in the local y = 0 plane, facing +y
+    Rectangle { s: Vector2<f64> },
+    Disc { r: f64 },
+    // triangles index into vertices and are counter-clockwise seen from outside; the
+    // buffers are shared between instances of the mesh
+    TriangleMesh {
+        vertices: Arc<Vec<Vector3<f64>>>,
+        triangles: Arc<Vec<[usize; 3]>>,
+        // per vertex shading normals, empty for flat shading
+        normals: Arc<Vec<Vector3<f64>>>,
+        // over the triangles, built by bvh::build_mesh_bvhs once the buffers are final
+        bvh: Arc<Bvh>,
+        // running sums of the triangle areas for light sampling, built along with bvh
+        area_cdf: Arc<Vec<f64>>,
+    },
+}
+
+pub fn triangle_area(vertices: &[Vector3<f64>], triangle: &[usize; 3]) -> f64 {
+    let [a, b, c] = triangle.map(|index| vertices[index]);
+    (b - a).cross(&(c - a)).norm() / 2.0
+}
+
+pub(crate) fn triangle_area_cdf(vertices: &[Vector3<f64>], triangles: &[[usize; 3]]) -> Vec<f64> {
+    triangles
+        .iter()
+        .scan(0.0, |total, triangle| {
+            *total += triangle_area(vertices, triangle);
+            Some(*total)
+        })
+        .collect()
+}
+
+pub fn surface_area(shape: &Shape) -> f64 {
+    match shape {
+        Shape::Plane { normal: _ } => f64::INFINITY,
+        Shape::Ellipsoid { r } => {
+            // Knud Thomsen's approximation, within about 1% of the exact area
+            const P: f64 = 1.6075;
+            let (a, b, c) = (r.x.powf(P), r.y.powf(P), r.z.powf(P));
+            4.0 * PI * ((a * b + a * c + b * c) / 3.0).powf(1.0 / P)
+        }
+        Shape::Sphere { r } => 4.0 * PI * r * r,
+        Shape::Box { s } => 8.0 * (s.x * s.y + s.x * s.z + s.y * s.z),
+        Shape::Cylinder { r, h } => 4.0 * PI * r * h + 2.0 * PI * r * r,
+        Shape::Cone { r, h } => PI * r * r + PI * r * (r * r + 4.0 * h * h).sqrt(),
+        // both faces emit
+        Shape::Rectangle { s } => 8.0 * s.x * s.y,
+        Shape::Disc { r } => 2.0 * PI * r * r,
+        Shape::TriangleMesh {
+            vertices,
+            triangles,
+            ..
+        } => triangles
+            .iter()
+            .map(|triangle| triangle_area(vertices, triangle))
+            .sum(),
+    }
 }
 
 pub struct Ray {
@@ -37,6 +105,8 @@ fn solve_quadratic_equation(a: f64, b: f64, c: f64) -> Option<(f64, f64)> {
 pub struct Intersection {
     pub ts: Vec<f64>,
     pub normals: Vec<Vector3<f64>>,
+    // set only when the normals above are shading normals (smooth meshes)
+    pub geometric_normals: Option<Vec<Vector3<f64>>>,
     pub outside: bool,
 }
 
@@ -50,6 +120,190 @@ fn normalize(v: Vector3<f64>) -> Vector3<f64> {
     }
 }
 
+fn intersect_flat(ray: &Ray, contains: impl Fn(f64, f64) -> bool) -> Option<Intersection> {
+    if ray.direction.y.abs() <= 0.00001 {
+        return None;
+    }
+    let t = -ray.point.y / ray.direction.y;
+    let p = ray.point + ray.direction * t;
+    if t < 0.0 || !contains(p.x, p.z) {
+        return None;
+    }
+    let outside = ray.direction.y < 0.0;
+    Some(Intersection {
+        ts: vec![t],
+        normals: vec![Vector3::new(0.0, if outside { 1.0 } else { -1.0 }, 0.0)],
+        geometric_normals: None,
+        outside,
+    })
+}
+
+// Ray parameter and outward normal of a point where a ray crosses the surface of a
+// convex solid. Sides the ray never crosses are at an infinite parameter, with no
+// normal.
+type Crossing = (f64, Vector3<f64>);
+
+const UNBOUNDED: (Crossing, Crossing) = (
+    (f64::NEG_INFINITY, Vector3::new(0.0, 0.0, 0.0)),
+    (f64::INFINITY, Vector3::new(0.0, 0.0, 0.0)),
+);
+
+// Where the ray enters and leaves both of two convex regions.
+fn clip_crossings(a: (Crossing, Crossing), b: (Crossing, Crossing)) -> (Crossing, Crossing) {
+    (
+        if a.0 .0 >= b.0 .0 { a.0 } else { b.0 },
+        if a.1 .0 <= b.1 .0 { a.1 } else { b.1 },
+    )
+}
+
+// The slab -h <= y <= h that caps cylinders and cones.
+fn slab_crossings(ray: &Ray, h: f64) -> Option<(Crossing, Crossing)> {
+    if ray.direction.y.abs() <= 0.00001 {
+        return (ray.point.y.abs() <= h).then_some(UNBOUNDED);
+    }
+    let top = ((h - ray.point.y) / ray.direction.y, Vector3::y());
+    let bottom = ((-h - ray.point.y) / ray.direction.y, -Vector3::y());
+    Some(if top.0 < bottom.0 {
+        (top, bottom)
+    } else {
+        (bottom, top)
+    })
+}
+
+// Intersection with a convex solid from where the ray enters and leaves it, in the
+// same form as for boxes and ellipsoids.
+fn convex_intersection((entry, exit): (Crossing, Crossing)) -> Option<Intersection> {
+    if entry.0 > exit.0 || exit.0 < 0.0 || !exit.0.is_finite() {
+        None
+    } else if entry.0 >= 0.0 {
+        Some(Intersection {
+            ts: vec![entry.0, exit.0],
+            normals: vec![entry.1, exit.1],
+            geometric_normals: None,
+            outside: true,
+        })
+    } else {
+        Some(Intersection {
+            ts: vec![exit.0],
+            normals: vec![-exit.1],
+            geometric_normals: None,
+            outside: false,
+        })
+    }
+}
+
+fn intersect_cylinder(ray: &Ray, r: f64, h: f64) -> Option<Intersection> {
+    let (p, d) = (&ray.point, &ray.direction);
+    let a = d.x * d.x + d.z * d.z;
+    let c = p.x * p.x + p.z * p.z - r * r;
+    let side = if a <= 0.00001 {
+        // parallel to the axis
+        (c <= 0.0).then_some(UNBOUNDED)?
+    } else {
+        let (t0, t1) = solve_quadratic_equation(a, 2.0 * (p.x * d.x + p.z * d.z), c)?;
+        let normal = |t: f64| {
+            let point = p + d * t;
+            Vector3::new(point.x, 0.0, point.z) / r
+        };
+        ((t0, normal(t0)), (t1, normal(t1)))
+    };
+    convex_intersection(clip_crossings(side, slab_crossings(ray, h)?))
+}
+
+// The side is part of the double cone x^2 + z^2 = k^2 (h - y)^2 with k = r / 2h, whose
+// inside along a line is one interval, or everything outside of one, as the line is
+// steeper or flatter than the cone. The slab then cuts away the upper nappe.
+fn intersect_cone(ray: &Ray, r: f64, h: f64) -> Option<Intersection> {
+    let (p, d) = (&ray.point, &ray.direction);
+    let k2 = (r / (2.0 * h)).powi(2);
+    let w = h - p.y;
+    let a = d.x * d.x + d.z * d.z - k2 * d.y * d.y;
+    let b = 2.0 * (p.x * d.x + p.z * d.z + k2 * w * d.y);
+    let c = p.x * p.x + p.z * p.z - k2 * w * w;
+    let normal = |t: f64| {
+        let point = p + d * t;
+        Vector3::new(point.x, k2 * (h - point.y), point.z).normalize()
+    };
+    let (before, after) = UNBOUNDED;
+    let pieces = if a.abs() <= 0.00001 {
+        // parallel to the slant, the inside is on one side of a single crossing
+        if b.abs() <= 0.00001 {
+            if c > 0.0 {
+                return None;
+            }
+            vec![UNBOUNDED]
+        } else {
+            let t = -c / b;
+            if b > 0.0 {
+                vec![(before, (t, normal(t)))]
+            } else {
+                vec![((t, normal(t)), after)]
+            }
+        }
+    } else {
+        match solve_quadratic_equation(a, b, c) {
+            Some((t0, t1)) if a > 0.0 => vec![((t0, normal(t0)), (t1, normal(t1)))],
+            Some((t0, t1)) => vec![(before, (t0, normal(t0))), ((t1, normal(t1)), after)],
+            None if a > 0.0 => return None,
+            None => vec![UNBOUNDED],
+        }
+    };
+    let slab = slab_crossings(ray, h)?;
+    pieces
+        .into_iter()
+        .map(|piece| clip_crossings(piece, slab))
+        .max_by(|x, y| (x.1 .0 - x.0 .0).total_cmp(&(y.1 .0 - y.0 .0)))
+        .and_then(convex_intersection)
+}
+
+// Möller–Trumbore; returns the ray parameter and the unnormalized geometric normal.
+fn intersect_triangle(
+    ray: &Ray,
+    a: &Vector3<f64>,
+    b: &Vector3<f64>,
+    c: &Vector3<f64>,
+) -> Option<(f64, Vector3<f64>, f64, f64)> {
+    let edge1 = b - a;
+    let edge2 = c - a;
+    let p = ray.direction.cross(&edge2);
+    let det = edge1.dot(&p);
+    if det.abs() <= f64::EPSILON {
+        return None;
+    }
+    let to_origin = ray.point - a;
+    let u = to_origin.dot(&p) / det;
+    if !(0.0..=1.0).contains(&u) {
+        return None;
+    }
+    let q = to_origin.cross(&edge1);
+    let v = ray.direction.dot(&q) / det;
+    if v < 0.0 || u + v > 1.0 {
+        return None;
+    }
+    let t = edge2.dot(&q) / det;
+    (t >= 0.0).then(|| (t, edge1.cross(&edge2), u, v))
+}
+
+// Ray parameter, geometric normal and shading normal where a ray hits a triangle of a
+// mesh, the shading normal kept on the side of the geometric one.
+fn intersect_mesh_triangle(
+    ray: &Ray,
+    vertices: &[Vector3<f64>],
+    normals: &[Vector3<f64>],
+    [a, b, c]: [usize; 3],
+) -> Option<(f64, Vector3<f64>, Vector3<f64>)> {
+    let (t, normal, u, v) = intersect_triangle(ray, &vertices[a], &vertices[b], &vertices[c])?;
+    if normals.is_empty() {
+        return Some((t, normal, normal));
+    }
+    let shading_normal = normals[a] * (1.0 - u - v) + normals[b] * u + normals[c] * v;
+    if shading_normal.dot(&normal) < 0.0 {
+        Some((t, normal, -shading_normal))
+    } else {
+        Some((t, normal, shading_normal))
+    }
+}
+
 pub fn intersect_shape(ray: &Ray, shape: &Shape) -> Option<Intersection> {
     match shape {
         Shape::Plane { normal } => {
@@ -66,6 +320,7 @@ pub fn intersect_shape(ray: &Ray, shape: &Shape) -> Option<Intersection> {
                 Some(Intersection {
                     ts: vec![t],
                     normals: vec![normal_conjugated.normalize()],
+                    geometric_normals: None,
                     outside,
                 })
             }
@@ -101,9 +356,18 @@ pub fn intersect_shape(ray: &Ray, shape: &Shape) -> Option<Intersection> {
                         }
                     })
                     .collect(),
+                geometric_normals: None,
                 outside,
             })
         }
+        Shape::Sphere { r } => intersect_shape(
+            ray,
+            &Shape::Ellipsoid {
+                r: Vector3::repeat(*r),
+            },
+        ),
+        Shape::Cylinder { r, h } => intersect_cylinder(ray, *r, *h),
+        Shape::Cone { r, h } => intersect_cone(ray, *r, *h),
         Shape::Box { s } => {
             let calc_in_and_out = |s_proj: f64, point_proj, dir_proj| {
                 let t0 = (s_proj - point_proj) / dir_proj;
@@ -135,6 +399,69 @@ pub fn intersect_shape(ray: &Ray, shape: &Shape) -> Option<Intersection> {
                         }
                     })
                     .collect(),
+                geometric_normals: None,
+                outside,
+            })
+        }
+        Shape::Rectangle { s } => intersect_flat(ray, |x, z| x.abs() <= s.x && z.abs() <= s.y),
+        Shape::Disc { r } => intersect_flat(ray, |x, z| x * x + z * z <= r * r),
+        Shape::TriangleMesh {
+            vertices,
+            triangles,
+            normals,
+            bvh,
+            ..
+        } => {
+            let hit = |index: usize| {
+                intersect_mesh_triangle(ray, vertices, normals, triangles[index])
+            };
+            // like convex shapes, the entry and, from outside, the last exit
+            let mut entry: Option<(f64, Vector3<f64>, Vector3<f64>)> = None;
+            bvh.traverse(ray, |index| {
+                if let Some(hit) = hit(index) {
+                    if entry.as_ref().is_none_or(|entry| hit.0 < entry.0) {
+                        entry = Some(hit);
+                    }
+                }
+                (0.0, entry.as_ref().map_or(f64::INFINITY, |entry| entry.0))
+            });
+            let entry = entry?;
+            let outside = ray.direction.dot(&entry.1) < 0.0;
+            let mut exit: Option<(f64, Vector3<f64>, Vector3<f64>)> = None;
+            if outside {
+                bvh.traverse(ray, |index| {
+                    if let Some(hit) = hit(index) {
+                        if hit.0 > exit.as_ref().map_or(entry.0, |exit| exit.0) {
+                            exit = Some(hit);
+                        }
+                    }
+                    (exit.as_ref().map_or(entry.0, |exit| exit.0), f64::INFINITY)
+                });
+            }
+            let hits: Vec<_> = [Some(entry), exit].into_iter().flatten().collect();
+            Some(Intersection {
+                ts: hits.iter().map(|(t, _, _)| *t).collect(),
+                normals: hits
+                    .iter()
+                    .map(|(_, _, normal)| {
+                        if outside {
+                            normal.normalize()
+                        } else {
+                            -normal.normalize()
+                        }
+                    })
+                    .collect(),
+                geometric_normals: (!normals.is_empty()).then(|| {
+                    hits.iter()
+                        .map(|(_, normal, _)| {
+                            if outside {
+                                normal.normalize()
+                            } else {
+                                -normal.normalize()
+                            }
+                        })
+                        .collect()
+                }),
                 outside,
             })
         }
@@ -162,34 +489,137 @@ pub fn intersect_primitive(ray: &Ray, primitive: &Primitive) -> Option<Intersect
             .iter()
             .map(|normal| primitive.rotation.transform_vector(normal))
             .collect(), 
+        geometric_normals: intersection.geometric_normals.map(|normals| {
+            normals
+                .iter()
+                .map(|normal| primitive.rotation.transform_vector(normal))
+                .collect()
+        }),
     })
 }
 
+// Every point where a ray crosses the surface of a primitive with its geometric normal,
+// for densities that count all points of the surface along a direction. Intersections
+// stop at the exit of meshes, which need not be convex.
+pub fn surface_crossings(ray: &Ray, primitive: &Primitive) -> Vec<(f64, Vector3<f64>)> {
+    let Shape::TriangleMesh {
+        vertices,
+        triangles,
+        normals,
+        bvh,
+        ..
+    } = &primitive.shape
+    else {
+        return intersect_primitive(ray, primitive).map_or(vec![], |intersection| {
+            let normals = intersection
+                .geometric_normals
+                .unwrap_or(intersection.normals);
+            intersection.ts.into_iter().zip(normals).collect()
+        });
+    };
+    let local_ray = Ray {
+        point: primitive
+            .rotation
+            .conjugate()
+            .transform_vector(&(ray.point - primitive.position)),
+        direction: primitive.rotation.conjugate().transform_vector(&ray.direction),
+    };
+    let mut crossings = vec![];
+    bvh.traverse(&local_ray, |index| {
+        crossings.extend(
+            intersect_mesh_triangle(&local_ray, vertices, normals, triangles[index]).map(
+                |(t, normal, _)| (t, primitive.rotation.transform_vector(&normal.normalize())),
+            ),
+        );
+        (0.0, f64::INFINITY)
+    });
+    crossings
+}
+
+thread_local! {
+    // rays this thread has intersected with a scene, for the render statistics
+    static TRACED_RAYS: Cell<u64> = const { Cell::new(0) };
+}
+
+pub(crate) fn traced_rays() -> u64 {
+    TRACED_RAYS.with(Cell::get)
+}
+
 pub fn intersect_scene<'a>(
     ray: &Ray,
     scene: &'a Scene,
     distance_cap: Option<f64>,
 ) -> Option<(Intersection, &'a Primitive)> {
+    intersect_scene_index(ray, scene, distance_cap)
+        .map(|(intersection, index)| (intersection, &scene.primitives[index]))
+}
+
+// Like intersect_scene, with the index of the hit primitive in the scene.
+pub fn intersect_scene_index(
+    ray: &Ray,
+    scene: &Scene,
+    distance_cap: Option<f64>,
+) -> Option<(Intersection, usize)> {
+    TRACED_RAYS.with(|count| count.set(count.get() + 1));
     scene
-        .primitives
-        .iter()
-        .filter_map(|primitive| {
-            intersect_primitive(&ray, &primitive).map(|intersection| (intersection, primitive))
-        })
-        .min_by(|x, y| {
-            x.0.ts[0]
-                .partial_cmp(&y.0.ts[0])
-                .expect("Nan on intersection.")
-        })
-        .and_then(|(intersection, primitive)| {
+        .bvh
+        .intersect(ray, &scene.primitives)
+        .and_then(|(intersection, index)| {
             if let Some(val) = distance_cap {
                 if intersection.ts[0] * ray.direction.norm() > val {
                     None
                 } else {
-                    Some((intersection, primitive))
+                    Some((intersection, index))
                 }
             } else {
-                Some((intersection, primitive))
+                Some((intersection, index))
             }
         })
 }
+
+// Texture coordinates of a point in the shape's local space: planar with one repeat
+// per unit length for planes, per face for boxes, spherical for ellipsoids and spheres,
+// cylindrical for cylinders and cones. Meshes carry no texture coordinates yet.
+pub fn texture_coordinates(shape: &Shape, local_point: &Vector3<f64>) -> Option<Vector2<f64>> {
+    match shape {
+        Shape::Plane { normal } => {
+            let frame = Frame::from_normal(&normal.normalize());
+            Some(Vector2::new(
+                local_point.dot(&frame.tangent),
+                local_point.dot(&frame.bitangent),
+            ))
+        }
+        Shape::Ellipsoid { r } => {
+            let direction = local_point.component_div(r).normalize();
+            Some(Vector2::new(
+                direction.x.atan2(-direction.z) / (2.0 * PI) + 0.5,
+                direction.y.clamp(-1.0, 1.0).acos() / PI,
+            ))
+        }
+        Shape::Sphere { r } => texture_coordinates(
+            &Shape::Ellipsoid {
+                r: Vector3::repeat(*r),
+            },
+            local_point,
+        ),
+        Shape::Cylinder { h, .. } | Shape::Cone { h, .. } => Some(Vector2::new(
+            local_point.x.atan2(-local_point.z) / (2.0 * PI) + 0.5,
+            (h - local_point.y) / (2.0 * h),
+        )),
+        Shape::Box { s } => {
+            let point = local_point.component_div(s);
+            let axis = point.iamax();
+            let (u, v) = ((axis + 1) % 3, (axis + 2) % 3);
+            Some(Vector2::new((point[u] + 1.0) / 2.0, (point[v] + 1.0) / 2.0))
+        }
+        Shape::Rectangle { s } => Some(Vector2::new(
+            (local_point.x / s.x + 1.0) / 2.0,
+            (local_point.z / s.y + 1.0) / 2.0,
+        )),
+        Shape::Disc { r } => Some(Vector2::new(
+            (local_point.x / r + 1.0) / 2.0,
+            (local_point.z / r + 1.0) / 2.0,
+        )),
+        Shape::TriangleMesh { .. } => None,
+    }
+}
@@ -0,0 +1,246 @@
+use std::sync::Arc;
+
+use nalgebra::{Vector2, Vector3};
+
+use crate::bvh::{Aabb, Bvh, BvhNodeKind};
+use crate::scene::{Material, MaterialMix, MixWeight, Scene};
+use crate::tessellation::tessellate_shape;
+use crate::texture::Texture;
+
+// Flat little-endian binary of the tessellated scene for GPU kernels and external
+// viewers. Every record is a multiple of 16 bytes so the sections can be bound as
+// storage buffers as they are:
+//   header      "RTGS", version, then the vertex, triangle, material, node, texture
+//               and texel counts as u32, 32 bytes
+//   vertices    world space position f32 x 3 and texture u; world space shading
+//               normal f32 x 3 (zero for flat shaded meshes) and texture v. The
+//               primitive's UV transform is already applied to u and v
+//   triangles   vertex indices u32 x 3 and the material index, in BVH leaf order
+//   materials   one per primitive, then one per mix material: kind u32 (0 diffuse,
+//               1 metallic, 2 dielectric, 3 car paint, 4 holdout), texture i32 (-1
+//               for none, the mask for a mix); for a primitive the index of its mix
+//               material i32 (-1 for none) and a u32 padding, for a mix material the
+//               weight kind u32 (0 constant, 1 mask, 2 Fresnel) and the constant
+//               weight or Fresnel IOR f32; color f32 x 3 and ior or flake size;
+//               emission f32 x 3 (zero for a mix) and Abbe number (0 without
+//               dispersion) or flake density; flake color or dielectric absorption
+//               f32 x 3 and padding
+//   nodes       bounds min f32 x 3 and a u32, max f32 x 3 and a u32: the children of
+//               interior nodes, the first triangle and count | LEAF_FLAG of leaves
+//   textures    width, height, offset of the first texel, padding, all u32
+//   texels      linear RGB f32 x 3 and 1, all textures row by row one after another
+const MAGIC: &[u8; 4] = b"RTGS";
+const VERSION: u32 = 2;
+const LEAF_FLAG: u32 = 1 << 31;
+
+fn push_u32(bytes: &mut Vec<u8>, value: u32) {
+    bytes.extend_from_slice(&value.to_le_bytes());
+}
+
+fn push_f32(bytes: &mut Vec<u8>, value: f64) {
+    bytes.extend_from_slice(&(value as f32).to_le_bytes());
+}
+
+fn push_vector(bytes: &mut Vec<u8>, vector: &Vector3<f64>) {
+    vector.iter().for_each(|&value| push_f32(bytes, value));
+}
+
+fn push_i32(bytes: &mut Vec<u8>, value: Option<usize>) {
+    bytes.extend_from_slice(&value.map_or(-1, |value| value as i32).to_le_bytes());
+}
+
+// The two words after the texture index differ between primitives and mix materials,
+// so the caller writes them.
+fn push_material(
+    bytes: &mut Vec<u8>,
+    material: &Material,
+    texture: Option<usize>,
+    push_mix_words: impl FnOnce(&mut Vec<u8>),
+    color: &Vector3<f64>,
+    emission: &Vector3<f64>,
+) {
+    let (kind, first, second, flake_color) = match material {
+        Material::DIFFUSE => (0, 0.0, 0.0, Vector3::zeros()),
+        Material::METALLIC => (1, 0.0, 0.0, Vector3::zeros()),
+        Material::DIELECTRIC {
+            ior,
+            abbe,
+            absorption,
+        } => (2, *ior, abbe.unwrap_or(0.0), *absorption),
+        Material::CARPAINT {
+            flake_size,
+            flake_density,
+            flake_color,
+        } => (3, *flake_size, *flake_density, *flake_color),
+        Material::HOLDOUT => (4, 0.0, 0.0, Vector3::zeros()),
+    };
+    push_u32(bytes, kind);
+    push_i32(bytes, texture);
+    push_mix_words(bytes);
+    push_vector(bytes, color);
+    push_f32(bytes, first);
+    push_vector(bytes, emission);
+    push_f32(bytes, second);
+    push_vector(bytes, &flake_color);
+    push_f32(bytes, 0.0);
+}
+
+fn texture_index<'a>(textures: &mut Vec<&'a Arc<Texture>>, texture: &'a Arc<Texture>) -> usize {
+    match textures
+        .iter()
+        .position(|known| Arc::ptr_eq(known, texture))
+    {
+        Some(index) => index,
+        None => {
+            textures.push(texture);
+            textures.len() - 1
+        }
+    }
+}
+
+// Tessellates every primitive into world space like the OBJ export and lays out the
+// binary described above. Materials of the primitives are indexed like the scene
+// primitives, the mix materials follow in the order of their primitives.
+pub fn flatten_scene_to_gpu_binary(scene: &Scene, resolution: usize) -> Vec<u8> {
+    let mut vertices: Vec<Vector3<f64>> = vec![];
+    let mut normals: Vec<Vector3<f64>> = vec![];
+    let mut uvs: Vec<Vector2<f64>> = vec![];
+    let mut triangles: Vec<[usize; 4]> = vec![];
+    for (index, primitive) in scene.primitives.iter().enumerate() {
+        let list = tessellate_shape(&primitive.shape, resolution);
+        let offset = vertices.len();
+        vertices.extend(
+            list.vertices
+                .iter()
+                .map(|vertex| primitive.rotation.transform_vector(vertex) + primitive.position),
+        );
+        normals.extend(
+            list.normals
+                .iter()
+                .map(|normal| primitive.rotation.transform_vector(normal)),
+        );
+        uvs.extend(list.uvs.iter().map(|uv| primitive.uv_transform.apply(uv)));
+        triangles.extend(
+            list.triangles
+                .iter()
+                .map(|[a, b, c]| [offset + a, offset + b, offset + c, index]),
+        );
+    }
+    let bvh = Bvh::build_over(triangles.iter().map(|triangle| {
+        let mut bounds = Aabb::empty();
+        triangle[..3]
+            .iter()
+            .for_each(|&vertex| bounds.grow(&vertices[vertex]));
+        Some(bounds)
+    }));
+
+    // textures shared by several primitives or masks are stored once
+    let mut textures: Vec<&Arc<Texture>> = vec![];
+    let texture_indices: Vec<Option<usize>> = scene
+        .primitives
+        .iter()
+        .map(|primitive| {
+            primitive
+                .texture
+                .as_ref()
+                .map(|texture| texture_index(&mut textures, texture))
+        })
+        .collect();
+    let mixes: Vec<(&MaterialMix, Option<usize>)> = scene
+        .primitives
+        .iter()
+        .filter_map(|primitive| primitive.mix.as_ref())
+        .map(|mix| match &mix.weight {
+            MixWeight::Mask(texture) => (mix, Some(texture_index(&mut textures, texture))),
+            _ => (mix, None),
+        })
+        .collect();
+    let texel_count: usize = textures.iter().map(|texture| texture.texels.len()).sum();
+
+    let mut bytes = vec![];
+    bytes.extend_from_slice(MAGIC);
+    for count in [
+        VERSION as usize,
+        vertices.len(),
+        triangles.len(),
+        scene.primitives.len() + mixes.len(),
+        bvh.nodes().len(),
+        textures.len(),
+        texel_count,
+    ] {
+        push_u32(&mut bytes, count as u32);
+    }
+
+    for ((vertex, normal), uv) in vertices.iter().zip(&normals).zip(&uvs) {
+        push_vector(&mut bytes, vertex);
+        push_f32(&mut bytes, uv.x);
+        push_vector(&mut bytes, normal);
+        push_f32(&mut bytes, uv.y);
+    }
+    for &index in bvh.indices() {
+        for value in triangles[index] {
+            push_u32(&mut bytes, value as u32);
+        }
+    }
+    let mut mix_index = scene.primitives.len();
+    for (primitive, texture) in scene.primitives.iter().zip(&texture_indices) {
+        let mix = primitive.mix.as_ref().map(|_| {
+            mix_index += 1;
+            mix_index - 1
+        });
+        push_material(
+            &mut bytes,
+            &primitive.material,
+            *texture,
+            |bytes| {
+                push_i32(bytes, mix);
+                push_u32(bytes, 0);
+            },
+            &primitive.color,
+            &primitive.emission,
+        );
+    }
+    for (mix, mask) in &mixes {
+        let (weight_kind, weight) = match mix.weight {
+            MixWeight::Constant(weight) => (0, weight),
+            MixWeight::Mask(_) => (1, 0.0),
+            MixWeight::Fresnel(ior) => (2, ior),
+        };
+        push_material(
+            &mut bytes,
+            &mix.material,
+            *mask,
+            |bytes| {
+                push_u32(bytes, weight_kind);
+                push_f32(bytes, weight);
+            },
+            &mix.color,
+            &Vector3::zeros(),
+        );
+    }
+    for node in bvh.nodes() {
+        let (a, b) = match node.kind {
+            BvhNodeKind::Leaf { first, count } => (first as u32, count as u32 | LEAF_FLAG),
+            BvhNodeKind::Interior { left, right } => (left as u32, right as u32),
+        };
+        push_vector(&mut bytes, &node.bounds.min);
+        push_u32(&mut bytes, a);
+        push_vector(&mut bytes, &node.bounds.max);
+        push_u32(&mut bytes, b);
+    }
+
+    let mut texel_offset = 0;
+    for texture in &textures {
+        for value in [texture.width, texture.height, texel_offset, 0] {
+            push_u32(&mut bytes, value as u32);
+        }
+        texel_offset += texture.texels.len();
+    }
+    for texture in &textures {
+        for texel in &texture.texels {
+            push_vector(&mut bytes, texel);
+            push_f32(&mut bytes, 1.0);
+        }
+    }
+    bytes
+}
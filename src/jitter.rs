@@ -0,0 +1,150 @@
+use std::iter::zip;
+
+use nalgebra::Vector3;
+use rand::{Rng, RngCore};
+
+use crate::scene::{Material, Scene};
+
+// Uniform ranges variations are drawn from. The defaults leave everything as parsed,
+// so only the parameters asked for vary.
+pub struct JitterRanges {
+    // largest offset of the camera position along each axis
+    pub camera_offset: f64,
+    // factors lights and emitters are scaled by, one per light
+    pub light_scale: (f64, f64),
+    // largest offset of each color channel, the result is clamped to 0..1
+    pub color_offset: f64,
+    // largest offset of dielectric IORs, the result is kept at 1 or above
+    pub ior_offset: f64,
+    // factors dielectric absorption is scaled by, one per material
+    pub absorption_scale: (f64, f64),
+    // factors car paint flake sizes and densities are scaled by, the density is
+    // clamped to 0..1
+    pub flake_scale: (f64, f64),
+}
+
+impl Default for JitterRanges {
+    fn default() -> Self {
+        JitterRanges {
+            camera_offset: 0.0,
+            light_scale: (1.0, 1.0),
+            color_offset: 0.0,
+            ior_offset: 0.0,
+            absorption_scale: (1.0, 1.0),
+            flake_scale: (1.0, 1.0),
+        }
+    }
+}
+
+// Parameters of the scene as parsed, which every variation starts from.
+pub struct JitterBase {
+    camera_position: Vector3<f64>,
+    light_intensities: Vec<Vector3<f64>>,
+    emissions: Vec<Vector3<f64>>,
+    colors: Vec<Vector3<f64>>,
+    // of the primitives and of their mix materials
+    materials: Vec<(Material, Option<Material>)>,
+}
+
+impl JitterBase {
+    pub fn capture(scene: &Scene) -> JitterBase {
+        JitterBase {
+            camera_position: scene.camera.position,
+            light_intensities: scene.lights.iter().map(|light| light.intensity).collect(),
+            emissions: scene
+                .primitives
+                .iter()
+                .map(|primitive| primitive.emission)
+                .collect(),
+            colors: scene
+                .primitives
+                .iter()
+                .map(|primitive| primitive.color)
+                .collect(),
+            materials: scene
+                .primitives
+                .iter()
+                .map(|primitive| {
+                    (
+                        primitive.material.clone(),
+                        primitive.mix.as_ref().map(|mix| mix.material.clone()),
+                    )
+                })
+                .collect(),
+        }
+    }
+}
+
+fn jitter_material(
+    material: &mut Material,
+    base: &Material,
+    ranges: &JitterRanges,
+    rng: &mut dyn RngCore,
+) {
+    let ior_offset = ranges.ior_offset;
+    let (min_absorption, max_absorption) = ranges.absorption_scale;
+    let (min_flake, max_flake) = ranges.flake_scale;
+    match (material, base) {
+        (
+            Material::DIELECTRIC {
+                ior, absorption, ..
+            },
+            Material::DIELECTRIC {
+                ior: base_ior,
+                absorption: base_absorption,
+                ..
+            },
+        ) => {
+            *ior = (base_ior + rng.gen_range(-ior_offset..=ior_offset)).max(1.0);
+            *absorption = base_absorption * rng.gen_range(min_absorption..=max_absorption);
+        }
+        (
+            Material::CARPAINT {
+                flake_size,
+                flake_density,
+                ..
+            },
+            Material::CARPAINT {
+                flake_size: base_size,
+                flake_density: base_density,
+                ..
+            },
+        ) => {
+            *flake_size = base_size * rng.gen_range(min_flake..=max_flake);
+            *flake_density = (base_density * rng.gen_range(min_flake..=max_flake)).clamp(0.0, 1.0);
+        }
+        _ => {}
+    }
+}
+
+// Moves the camera, rescales the lights and perturbs the colors and materials of the
+// scene captured in `base`. Geometry is left alone, so the acceleration structure stays valid.
+pub fn jitter_scene(
+    scene: &mut Scene,
+    base: &JitterBase,
+    ranges: &JitterRanges,
+    rng: &mut dyn RngCore,
+) {
+    let (min_scale, max_scale) = ranges.light_scale;
+    let camera_offset = ranges.camera_offset;
+    let color_offset = ranges.color_offset;
+
+    scene.camera.position = base.camera_position
+        + Vector3::from_fn(|_, _| rng.gen_range(-camera_offset..=camera_offset));
+    for (light, intensity) in zip(&mut scene.lights, &base.light_intensities) {
+        light.intensity = intensity * rng.gen_range(min_scale..=max_scale);
+    }
+    for (primitive, (emission, color)) in
+        zip(&mut scene.primitives, zip(&base.emissions, &base.colors))
+    {
+        primitive.emission = emission * rng.gen_range(min_scale..=max_scale);
+        primitive.color = color
+            .map(|channel| (channel + rng.gen_range(-color_offset..=color_offset)).clamp(0.0, 1.0));
+    }
+    for (primitive, (material, mix_material)) in zip(&mut scene.primitives, &base.materials) {
+        jitter_material(&mut primitive.material, material, ranges, rng);
+        if let (Some(mix), Some(mix_material)) = (&mut primitive.mix, mix_material) {
+            jitter_material(&mut mix.material, mix_material, ranges, rng);
+        }
+    }
+}
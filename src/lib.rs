@@ -0,0 +1,40 @@
+// The path tracer behind the practice binary. Scenes are either parsed from the text
+// format with scene::parse_scene or put together in code with SceneBuilder.
+extern crate nalgebra as na;
+
+pub mod assets;
+pub mod bvh;
+pub mod chi_square;
+pub mod geometry;
+pub mod rendering;
+pub mod scene;
+pub mod scene_builder;
+pub mod scene_dump;
+pub mod denoise;
+pub mod distribution;
+pub mod environment;
+pub mod expression;
+pub mod filter;
+pub mod frame;
+pub mod gpu_scene;
+pub mod jitter;
+pub mod manifold;
+pub mod matpreview;
+pub mod medium;
+pub mod metadata;
+pub mod obj;
+pub mod path_export;
+pub mod probes;
+pub mod report;
+pub mod rng;
+pub mod sampler;
+pub mod sensor;
+pub mod tessellation;
+pub mod texture;
+
+pub use geometry::Shape;
+pub use rendering::{render_image, render_scene};
+pub use scene::{
+    Camera, CameraType, Light, LightType, Material, Primitive, Scene, SceneParseError,
+};
+pub use scene_builder::SceneBuilder;
@@ -1,57 +1,753 @@
-mod geometry;
-mod rendering;
-mod scene;
-mod distribution;
-
 extern crate nalgebra as na;
 use std::env;
 use std::fs;
+use std::io::BufWriter;
 use std::io::Write;
+use std::path::Path;
+use std::process;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 
+use exr::block::writer::ChunksWriter;
+use exr::block::{BlockIndex, UncompressedBlock};
+use exr::meta::header::Header;
+use exr::meta::BlockDescription;
+use exr::prelude::{
+    AttributeValue, ChannelDescription, Compression, LineOrder, SampleType, SpecificChannels, Text,
+    Vec2, WritableImage,
+};
+use image::codecs::hdr::HdrEncoder;
 use image::ImageFormat;
+use image::Rgb;
 use image::RgbImage;
+use na::Vector3;
+
+use practice::assets::{open_archive, Assets};
+use practice::chi_square::run_chi_square_tests;
+use practice::denoise::denoise_image;
+use practice::gpu_scene::flatten_scene_to_gpu_binary;
+use practice::jitter::{jitter_scene, JitterBase, JitterRanges};
+use practice::matpreview::{build_preview_scene, preview_environment};
+use practice::metadata::{render_metadata, scene_hash};
+use practice::path_export::{segments_to_obj, segments_to_ply};
+use practice::probes::{bake_probes, probes_to_json};
+use practice::rendering::{
+    parse_aov, parse_aov_resolve, render_aov, render_scene, render_scene_with_guides, tonemap,
+    trace_pixel_paths, Aov, AovResolve, RenderStatistics,
+};
+use practice::report::{report_to_json, RenderReport};
+use practice::rng::create_rng;
+use practice::scene::{
+    apply_material_override, apply_matte, parse_material_override, parse_scene, scene_warnings,
+    unknown_keyword_errors, vertical_fov, Scene,
+};
+use practice::scene_dump::{dump_scene_graph_dot, dump_scene_graph_json};
+use practice::tessellation::flatten_scene_to_obj;
+
+const USAGE: &str = "\
+Usage:
+  practice [--scene] SCENE [--output] OUTPUT [FLAGS]
+  practice matpreview MATERIAL OUTPUT [FLAGS]
+  practice bakeprobes SCENE OUTPUT [FLAGS]
+  practice flatten SCENE OUTPUT [FLAGS]
+  practice chisquare
+
+SCENE is a scene file or a .rtscene archive. The OUTPUT extension picks the format:
+.png, .exr, .hdr, anything else writes PPM. PNG and EXR images record the scene hash,
+seed, samples, integrator, render time and version. flatten writes a GPU-ready binary
+to .bin and OBJ otherwise.
+
+Render settings, overriding the scene file:
+  --samples N                      samples per pixel, the most a pixel takes when adaptive
+  --adaptive-threshold E           stop sampling a pixel at relative standard error E
+  --time-budget S                  render for about S seconds, sampling the tiles with
+                                   the largest error first
+  --clamp V                        scale scattered rays down to at most V per channel
+  --median-of-means K              median of the means of K sample groups per pixel
+  --width N, --height N            image size, the horizontal field of view is kept
+  --ray-depth N                    maximum number of bounces
+  --threads N                      render threads, all cores by default
+  --seed N                         fixed random seed, makes renders reproducible
+
+Dataset variations:
+  --variations N                   render N variations, numbering every output file
+  --jitter-camera R                move the camera by up to R along each axis
+  --jitter-light MIN MAX           scale each light and emitter by MIN to MAX
+  --jitter-color R                 offset each color channel by up to R
+  --jitter-ior R                   offset each dielectric IOR by up to R
+  --jitter-absorption MIN MAX      scale each dielectric absorption by MIN to MAX
+  --jitter-flakes MIN MAX          scale each car paint flake size and density by MIN
+                                   to MAX
+
+Other flags:
+  --asset-dir DIR                  also look for assets in DIR
+  --strict                         fail on lines starting with an unknown keyword
+                                   instead of warning about them
+  --override-material clay         replace all materials
+  --matte PATTERN                  render objects named like PATTERN as holdouts,
+                                   * and ? match any characters and any one character
+  --matte-inverse PATTERN          render all other objects as holdouts instead
+  --dump-scene-graph PATH          write the parsed scene as .json or .dot
+  --aov NAME PATH                  also write coverage, normal, albedo, emission,
+                                   depth, id, or the direct or indirect light as PFM
+  --aov-tonemapped                 write AOVs as tone mapped PPM instead
+  --aov-resolve MODE               pick the depth and id of a pixel from its nearest
+                                   sample (default) or its majority, or write every
+                                   sample with samples, one pixel SAMPLES values wide
+  --denoise                        filter the noise out of the image, guided by the
+                                   normal and albedo of the first hits
+  --report PATH                    write timings, memory, ray counts, convergence and
+                                   warnings of the render as JSON
+  --trace-pixel X Y                trace the paths of a pixel, repeatable
+  --trace-output PATH              write the traced paths as .obj or .ply
+  --tessellation N                 flatten resolution of curved shapes
+  --help                           print this message";
+
+fn usage_error(message: &str) -> ! {
+    eprintln!("Usage error, {}.\n\n{}", message, USAGE);
+    process::exit(2);
+}
+
+fn flag_value<'a>(flags: &mut impl Iterator<Item = &'a String>, flag: &str) -> &'a String {
+    flags
+        .next()
+        .unwrap_or_else(|| usage_error(&format!("{} needs a value", flag)))
+}
+
+fn flag_number<'a>(flags: &mut impl Iterator<Item = &'a String>, flag: &str) -> f64 {
+    flag_value(flags, flag)
+        .parse()
+        .ok()
+        .filter(|number: &f64| *number >= 0.0)
+        .unwrap_or_else(|| usage_error(&format!("{} needs a non-negative number", flag)))
+}
 
-use rendering::render_scene;
-use scene::parse_scene;
+// Counts such as samples or the image size, which have to be positive.
+fn flag_count<'a, T: FromStr + PartialOrd + Default>(
+    flags: &mut impl Iterator<Item = &'a String>,
+    flag: &str,
+) -> T {
+    flag_value(flags, flag)
+        .parse()
+        .ok()
+        .filter(|count| *count > T::default())
+        .unwrap_or_else(|| usage_error(&format!("{} needs a positive integer", flag)))
+}
 
 fn main() {
-    let args: Vec<String> = env::args().collect();
+    let args: Vec<String> = env::args().skip(1).collect();
+    let (command, flag_args) = match args.first().map(String::as_str) {
+        Some(command @ ("chisquare" | "matpreview" | "bakeprobes" | "flatten")) => {
+            (Some(command), &args[1..])
+        }
+        _ => (None, &args[..]),
+    };
+
+    if command == Some("chisquare") {
+        let passed = run_chi_square_tests();
+        process::exit(if passed { 0 } else { 1 });
+    }
+
+    let mut positional = vec![];
+    let mut input_path = None;
+    let mut output_path = None;
+    let mut asset_dirs = vec![];
+    let mut samples = None;
+    let mut adaptive_threshold = None;
+    let mut time_budget = None;
+    let mut clamp = None;
+    let mut median_of_means = None;
+    let mut width = None;
+    let mut height = None;
+    let mut ray_depth = None;
+    let mut threads = None;
+    let mut seed = None;
+    let mut variations = None;
+    let mut jitter_ranges = JitterRanges::default();
+    let mut material_override = None;
+    let mut matte = None;
+    let mut scene_graph_path = None;
+    let mut report_path = None;
+    let mut trace_pixels: Vec<(u32, u32)> = vec![];
+    let mut trace_output_path = None;
+    let mut aovs = vec![];
+    let mut aov_tonemapped = false;
+    let mut aov_resolve = AovResolve::Nearest;
+    let mut denoise = false;
+    let mut strict = false;
+    let mut tessellation_resolution = 32;
+    let mut flags = flag_args.iter();
+    while let Some(flag) = flags.next() {
+        match flag.as_str() {
+            "--help" => {
+                println!("{}", USAGE);
+                return;
+            }
+            "--scene" => input_path = Some(flag_value(&mut flags, flag)),
+            "--output" => output_path = Some(flag_value(&mut flags, flag)),
+            "--samples" => samples = Some(flag_count(&mut flags, flag)),
+            "--adaptive-threshold" => {
+                let threshold = flag_number(&mut flags, flag);
+                if threshold == 0.0 {
+                    usage_error("--adaptive-threshold needs a positive number");
+                }
+                adaptive_threshold = Some(threshold);
+            }
+            "--time-budget" => {
+                let seconds = flag_number(&mut flags, flag);
+                if seconds == 0.0 {
+                    usage_error("--time-budget needs a positive number");
+                }
+                time_budget = Some(seconds);
+            }
+            "--clamp" => {
+                let value = flag_number(&mut flags, flag);
+                if value == 0.0 {
+                    usage_error("--clamp needs a positive number");
+                }
+                clamp = Some(value);
+            }
+            "--median-of-means" => median_of_means = Some(flag_count(&mut flags, flag)),
+            "--width" => width = Some(flag_count(&mut flags, flag)),
+            "--height" => height = Some(flag_count(&mut flags, flag)),
+            "--ray-depth" => ray_depth = Some(flag_count(&mut flags, flag)),
+            "--threads" => threads = Some(flag_count(&mut flags, flag)),
+            "--seed" => {
+                seed = Some(
+                    flag_value(&mut flags, flag)
+                        .parse()
+                        .unwrap_or_else(|_| usage_error("--seed needs a non-negative integer")),
+                )
+            }
+            "--variations" => variations = Some(flag_count(&mut flags, flag)),
+            "--jitter-camera" => jitter_ranges.camera_offset = flag_number(&mut flags, flag),
+            "--jitter-light" => {
+                jitter_ranges.light_scale =
+                    (flag_number(&mut flags, flag), flag_number(&mut flags, flag));
+                if jitter_ranges.light_scale.0 > jitter_ranges.light_scale.1 {
+                    usage_error("--jitter-light needs MIN no larger than MAX");
+                }
+            }
+            "--jitter-color" => jitter_ranges.color_offset = flag_number(&mut flags, flag),
+            "--jitter-ior" => jitter_ranges.ior_offset = flag_number(&mut flags, flag),
+            "--jitter-absorption" => {
+                jitter_ranges.absorption_scale =
+                    (flag_number(&mut flags, flag), flag_number(&mut flags, flag));
+                if jitter_ranges.absorption_scale.0 > jitter_ranges.absorption_scale.1 {
+                    usage_error("--jitter-absorption needs MIN no larger than MAX");
+                }
+            }
+            "--jitter-flakes" => {
+                jitter_ranges.flake_scale =
+                    (flag_number(&mut flags, flag), flag_number(&mut flags, flag));
+                if jitter_ranges.flake_scale.0 > jitter_ranges.flake_scale.1 {
+                    usage_error("--jitter-flakes needs MIN no larger than MAX");
+                }
+            }
+            "--override-material" => {
+                material_override = Some(
+                    parse_material_override(flag_value(&mut flags, flag))
+                        .unwrap_or_else(|| usage_error("unknown material override")),
+                )
+            }
+            "--matte" => matte = Some((flag_value(&mut flags, flag), false)),
+            "--matte-inverse" => matte = Some((flag_value(&mut flags, flag), true)),
+            "--dump-scene-graph" => scene_graph_path = Some(flag_value(&mut flags, flag)),
+            "--report" => report_path = Some(flag_value(&mut flags, flag)),
+            "--trace-pixel" => trace_pixels.push((
+                flag_value(&mut flags, flag)
+                    .parse()
+                    .unwrap_or_else(|_| usage_error("invalid trace pixel column")),
+                flag_value(&mut flags, flag)
+                    .parse()
+                    .unwrap_or_else(|_| usage_error("invalid trace pixel row")),
+            )),
+            "--trace-output" => trace_output_path = Some(flag_value(&mut flags, flag)),
+            "--aov" => aovs.push((
+                parse_aov(flag_value(&mut flags, flag))
+                    .unwrap_or_else(|| usage_error("unknown AOV")),
+                flag_value(&mut flags, flag).clone(),
+            )),
+            "--aov-tonemapped" => aov_tonemapped = true,
+            "--denoise" => denoise = true,
+            "--strict" => strict = true,
+            "--aov-resolve" => {
+                aov_resolve = parse_aov_resolve(flag_value(&mut flags, flag))
+                    .unwrap_or_else(|| usage_error("unknown AOV resolve"))
+            }
+            "--asset-dir" => asset_dirs.push(flag_value(&mut flags, flag)),
+            "--tessellation" => tessellation_resolution = flag_count(&mut flags, flag),
+            _ if flag.starts_with("--") => usage_error(&format!("unknown flag {}", flag)),
+            _ => positional.push(flag),
+        }
+    }
+
+    // the scene and output may also be given in this order without their flags
+    let mut positional = positional.into_iter();
+    let input_path = input_path
+        .or_else(|| positional.next())
+        .unwrap_or_else(|| usage_error("no scene given"));
+    let output_path = output_path
+        .or_else(|| positional.next())
+        .unwrap_or_else(|| usage_error("no output path given"));
+    if let Some(extra) = positional.next() {
+        usage_error(&format!("unexpected argument {}", extra));
+    }
+
+    if let Some(threads) = threads {
+        rayon::ThreadPoolBuilder::new()
+            .num_threads(threads)
+            .build_global()
+            .unwrap();
+    }
+
+    let parse_start = Instant::now();
+    let (scene_content, mut assets) = if command == Some("matpreview") {
+        let material_definition =
+            fs::read_to_string(input_path).expect("No material definition file provided.");
+        (
+            build_preview_scene(&material_definition),
+            Assets::next_to(input_path),
+        )
+    } else {
+        read_scene(input_path)
+    };
+    for asset_dir in asset_dirs {
+        assets.add_search_root(asset_dir);
+    }
+    let content_hash = scene_hash(&scene_content);
+
+    let mut scene = match parse_scene(scene_content, &assets) {
+        Ok(scene) => scene,
+        Err(error) => {
+            eprintln!("Scene file error, {}.", error);
+            process::exit(1);
+        }
+    };
+    // the material definition may bring its own environment
+    if command == Some("matpreview") && scene.environment_map.is_none() {
+        scene.environment_map = Some(Arc::new(preview_environment()));
+    }
+    if strict {
+        let errors = unknown_keyword_errors(&scene);
+        for error in &errors {
+            eprintln!("Scene file error, {}.", error);
+        }
+        if !errors.is_empty() {
+            process::exit(1);
+        }
+    }
+    let mut warnings = scene_warnings(&scene);
+    if let Some(material_override) = material_override {
+        apply_material_override(&mut scene, &material_override);
+    }
+    if let Some((pattern, inverse)) = matte {
+        if apply_matte(&mut scene, pattern, inverse) == 0 {
+            warnings.push(format!("no object is named like {}", pattern));
+        }
+    }
+    for warning in &warnings {
+        eprintln!("Scene file warning, {}.", warning);
+    }
+    if let Some(samples) = samples {
+        scene.samples = samples;
+    }
+    if adaptive_threshold.is_some() {
+        scene.adaptive_threshold = adaptive_threshold;
+    }
+    if time_budget.is_some() {
+        scene.time_budget = time_budget;
+    }
+    if clamp.is_some() {
+        scene.clamp = clamp;
+    }
+    if median_of_means.is_some() {
+        scene.median_of_means = median_of_means;
+    }
+    if let Some(ray_depth) = ray_depth {
+        scene.ray_depth = ray_depth;
+    }
+    if seed.is_some() {
+        scene.seed = seed;
+    }
+    if width.is_some() || height.is_some() {
+        scene.width = width.unwrap_or(scene.width);
+        scene.height = height.unwrap_or(scene.height);
+        scene.camera.fov_y = vertical_fov(scene.camera.fov_x, scene.width, scene.height);
+    }
+    let parse_time = parse_start.elapsed();
+
+    // variations dump their own scene graphs, which record the drawn parameters
+    if let (Some(scene_graph_path), None) = (scene_graph_path, variations) {
+        write_scene_graph(&scene, scene_graph_path);
+    }
+
+    // checked against the image size after the --width and --height overrides
+    for &(column, row) in &trace_pixels {
+        if column >= scene.width || row >= scene.height {
+            usage_error(&format!(
+                "--trace-pixel {} {} is outside the {}x{} image",
+                column, row, scene.width, scene.height
+            ));
+        }
+    }
+    if let Some(trace_output_path) = trace_output_path {
+        let segments = trace_pixel_paths(&scene, &trace_pixels);
+        let trace = if trace_output_path.ends_with(".ply") {
+            segments_to_ply(&segments)
+        } else {
+            segments_to_obj(&segments)
+        };
+        fs::write(trace_output_path, trace).unwrap();
+    }
+
+    if command == Some("bakeprobes") {
+        fs::write(output_path, probes_to_json(&scene, &bake_probes(&scene))).unwrap();
+        return;
+    }
+
+    if command == Some("flatten") {
+        if output_path.ends_with(".bin") {
+            fs::write(
+                output_path,
+                flatten_scene_to_gpu_binary(&scene, tessellation_resolution),
+            )
+            .unwrap();
+        } else {
+            fs::write(
+                output_path,
+                flatten_scene_to_obj(&scene, tessellation_resolution),
+            )
+            .unwrap();
+        }
+        return;
+    }
 
-    let scene_path = &args[1];
-    let output_path = &args[2];
+    let Some(variations) = variations else {
+        let report = write_renders(
+            &scene,
+            content_hash,
+            output_path,
+            &aovs,
+            aov_tonemapped,
+            aov_resolve,
+            denoise,
+        );
+        if let Some(report_path) = report_path {
+            write_report(&scene, report, parse_time, &warnings, report_path);
+        }
+        return;
+    };
+    let base = JitterBase::capture(&scene);
+    let mut rng = create_rng(scene.rng_backend, scene.seed);
+    for index in 0..variations {
+        jitter_scene(&mut scene, &base, &jitter_ranges, rng.as_mut());
+        if let Some(scene_graph_path) = scene_graph_path {
+            write_scene_graph(&scene, &variation_path(scene_graph_path, index));
+        }
+        let aovs: Vec<(Aov, String)> = aovs
+            .iter()
+            .map(|(aov, aov_path)| (*aov, variation_path(aov_path, index)))
+            .collect();
+        let report = write_renders(
+            &scene,
+            content_hash,
+            &variation_path(output_path, index),
+            &aovs,
+            aov_tonemapped,
+            aov_resolve,
+            denoise,
+        );
+        if let Some(report_path) = report_path {
+            let report_path = variation_path(report_path, index);
+            write_report(&scene, report, parse_time, &warnings, &report_path);
+        }
+    }
+}
 
-    let scene = parse_scene(fs::read_to_string(scene_path).expect("No scene scene file provided."));
+// out.png becomes out_0007.png for the variation with index 7.
+fn variation_path(path: &str, index: u32) -> String {
+    let path = Path::new(path);
+    let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+    let name = match path.extension() {
+        Some(extension) => format!("{}_{:04}.{}", stem, index, extension.to_string_lossy()),
+        None => format!("{}_{:04}", stem, index),
+    };
+    path.with_file_name(name).to_string_lossy().into_owned()
+}
 
-    let rendered_scene = render_scene(&scene);
-    dump_to_ppm(scene.height, scene.width, &rendered_scene, output_path);
+fn write_scene_graph(scene: &Scene, path: &str) {
+    let scene_graph = if path.ends_with(".json") {
+        dump_scene_graph_json(scene)
+    } else {
+        dump_scene_graph_dot(scene)
+    };
+    fs::write(path, scene_graph).unwrap();
 }
 
-fn dump_to_png(height: u32, width: u32, rendered_scene: &Vec<u8>, output_path: &String) {
-    let mut image = RgbImage::new(width, height);
-    for x in 0..width {
-        for y in 0..height {
-            for i in 0..3 {
-                image.get_pixel_mut(x as u32, y as u32).0[i] =
-                    rendered_scene[(y * width * 3 + x * 3) as usize + i];
+fn write_renders(
+    scene: &Scene,
+    scene_hash: u64,
+    output_path: &str,
+    aovs: &[(Aov, String)],
+    aov_tonemapped: bool,
+    aov_resolve: AovResolve,
+    denoise: bool,
+) -> RenderReport {
+    let mut stages = vec![];
+    if !aovs.is_empty() {
+        let start = Instant::now();
+        for (aov, aov_path) in aovs {
+            let values = render_aov(scene, *aov, aov_resolve);
+            // SAMPLES values per pixel when every sample is written
+            let aov_width = (values.len() / scene.height as usize) as u32;
+            if aov_tonemapped {
+                dump_to_ppm(scene.height, aov_width, &tonemap(&values), aov_path);
+            } else {
+                dump_to_pfm(scene.height, aov_width, &values, aov_path);
             }
         }
+        stages.push(("aovs", start.elapsed()));
+    }
+
+    // AOVs are not part of the render time
+    let start = Instant::now();
+    let format = ImageFormat::from_path(output_path);
+    // PPM and EXR rows are written as they are rendered, the other formats need the
+    // whole image
+    let whole_image = matches!(format, Ok(ImageFormat::Png | ImageFormat::Hdr));
+    if !whole_image && !denoise {
+        let statistics = if let Ok(ImageFormat::OpenExr) = format {
+            // the header is written before the render, so it cannot tell the render time
+            let metadata: Vec<_> = render_metadata(scene, scene_hash, Duration::ZERO)
+                .into_iter()
+                .filter(|(key, _)| *key != "Render time")
+                .collect();
+            render_to_exr(scene, &metadata, output_path)
+        } else {
+            let mut output = open_ppm(scene.height, scene.width, output_path);
+            let statistics = render_scene(scene, |row| output.write_all(&tonemap(row)).unwrap());
+            output.flush().unwrap();
+            statistics
+        };
+        stages.push(("render", start.elapsed()));
+        return RenderReport {
+            scene_hash,
+            stages,
+            statistics,
+            warnings: vec![],
+        };
+    }
+
+    let mut values = Vec::with_capacity(scene.width as usize * scene.height as usize);
+    let (mut normals, mut albedos) = (vec![], vec![]);
+    let statistics = if denoise {
+        render_scene_with_guides(scene, |row, guides| {
+            values.extend_from_slice(row);
+            normals.extend(guides.iter().map(|guide| guide.normal));
+            albedos.extend(guides.iter().map(|guide| guide.albedo));
+        })
+    } else {
+        render_scene(scene, |row| values.extend_from_slice(row))
+    };
+    stages.push(("render", start.elapsed()));
+    if denoise {
+        let denoise_start = Instant::now();
+        values = denoise_image(
+            scene.width as usize,
+            scene.height as usize,
+            &values,
+            &normals,
+            &albedos,
+        );
+        stages.push(("denoise", denoise_start.elapsed()));
+    }
+    let metadata = render_metadata(scene, scene_hash, start.elapsed());
+    let write_start = Instant::now();
+    match format {
+        Ok(format @ (ImageFormat::OpenExr | ImageFormat::Hdr)) => dump_to_float_image(
+            scene.height,
+            scene.width,
+            &values,
+            format,
+            &metadata,
+            output_path,
+        ),
+        Ok(ImageFormat::Png) => {
+            let image = RgbImage::from_raw(scene.width, scene.height, tonemap(&values))
+                .expect("Rendered image has the wrong size.");
+            dump_to_png(&image, &metadata, output_path)
+        }
+        _ => dump_to_ppm(scene.height, scene.width, &tonemap(&values), output_path),
+    }
+    stages.push(("write", write_start.elapsed()));
+    RenderReport {
+        scene_hash,
+        stages,
+        statistics,
+        warnings: vec![],
     }
-    image
-        .save_with_format(output_path, ImageFormat::Png)
-        .unwrap();
 }
 
-fn dump_to_ppm(height: u32, width: u32, rendered_scene: &Vec<u8>, output_path: &String) {
-    let mut output_file = fs::OpenOptions::new()
-        .write(true)
-        .append(true)
-        .create(true)
-        .open(output_path)
-        .unwrap();
-    output_file.write(b"P6\n").unwrap();
-    output_file
-        .write(format!("{} {}\n", width, height).as_bytes())
+// The report of a render, with the parsing that all renders of the scene share and the
+// warnings of the scene.
+fn write_report(
+    scene: &Scene,
+    mut report: RenderReport,
+    parse_time: Duration,
+    warnings: &[String],
+    report_path: &str,
+) {
+    report.stages.insert(0, ("parse", parse_time));
+    report.warnings = warnings.to_vec();
+    fs::write(report_path, report_to_json(scene, &report)).unwrap();
+}
+
+// Scene description and the files it refers to, loose or packed in a .rtscene archive.
+fn read_scene(path: &str) -> (String, Assets) {
+    if path.ends_with(".rtscene") {
+        open_archive(path).unwrap_or_else(|error| {
+            eprintln!("Scene archive error, {}.", error);
+            process::exit(1);
+        })
+    } else {
+        (
+            fs::read_to_string(path).expect("No scene scene file provided."),
+            Assets::next_to(path),
+        )
+    }
+}
+
+fn open_ppm(height: u32, width: u32, output_path: &str) -> BufWriter<fs::File> {
+    let mut output = BufWriter::new(fs::File::create(output_path).unwrap());
+    output.write_all(b"P6\n").unwrap();
+    output
+        .write_all(format!("{} {}\n", width, height).as_bytes())
         .unwrap();
-    output_file.write(b"255\n").unwrap();
-    output_file.write(rendered_scene.as_slice()).unwrap();
+    output.write_all(b"255\n").unwrap();
+    output
+}
+
+fn dump_to_ppm(height: u32, width: u32, rendered_scene: &[u8], output_path: &str) {
+    let mut output = open_ppm(height, width, output_path);
+    output.write_all(rendered_scene).unwrap();
+    output.flush().unwrap();
+}
+
+// Portable float map: little-endian f32 RGB, rows stored bottom to top.
+fn dump_to_pfm(height: u32, width: u32, values: &[Vector3<f64>], output_path: &str) {
+    let mut output = format!("PF\n{} {}\n-1.0\n", width, height).into_bytes();
+    for row in values.chunks(width as usize).rev() {
+        for value in row {
+            for channel in value.iter() {
+                output.extend_from_slice(&(*channel as f32).to_le_bytes());
+            }
+        }
+    }
+    fs::write(output_path, output).unwrap();
+}
+
+// Tone mapped 8-bit PNG with the metadata as tEXt chunks.
+fn dump_to_png(image: &RgbImage, metadata: &[(&str, String)], output_path: &str) {
+    let output = BufWriter::new(fs::File::create(output_path).unwrap());
+    let mut encoder = png::Encoder::new(output, image.width(), image.height());
+    encoder.set_color(png::ColorType::Rgb);
+    encoder.set_depth(png::BitDepth::Eight);
+    for (key, value) in metadata {
+        encoder
+            .add_text_chunk(key.to_string(), value.clone())
+            .unwrap();
+    }
+    let mut writer = encoder.write_header().unwrap();
+    writer.write_image_data(image.as_raw()).unwrap();
+}
+
+// Linear radiance for post-processing, as OpenEXR with the metadata as header
+// attributes, or as Radiance HDR, whose encoder writes a fixed header.
+// Each row goes into the file as soon as it is rendered, as a scan line block of its
+// own, so the image is never held in memory as a whole.
+fn render_to_exr(
+    scene: &Scene,
+    metadata: &[(&str, String)],
+    output_path: &str,
+) -> RenderStatistics {
+    let width = scene.width as usize;
+    // EXR stores channels in alphabetical order
+    let channels = ["B", "G", "R"]
+        .into_iter()
+        .map(|name| ChannelDescription::named(name, SampleType::F32))
+        .collect();
+    let mut header = Header::new(Text::from("rgb"), (width, scene.height as usize), channels)
+        .with_encoding(
+            Compression::RLE,
+            BlockDescription::ScanLines,
+            LineOrder::Increasing,
+        );
+    header.own_attributes.layer_name = None;
+    for (key, value) in metadata {
+        header.shared_attributes.other.insert(
+            Text::from(*key),
+            AttributeValue::Text(Text::from(value.as_str())),
+        );
+    }
+    let output = BufWriter::new(fs::File::create(output_path).unwrap());
+    let mut statistics = RenderStatistics::default();
+    exr::block::write(output, vec![header].into(), true, |meta, chunk_writer| {
+        let mut compressor = chunk_writer.sequential_blocks_compressor(&meta);
+        let mut y = 0;
+        statistics = render_scene(scene, |row| {
+            let index = BlockIndex {
+                layer: 0,
+                pixel_position: Vec2(0, y),
+                pixel_size: Vec2(width, 1),
+                level: Vec2(0, 0),
+            };
+            let block = UncompressedBlock::from_lines(&meta.headers[0].channels, index, |line| {
+                let channel = line.location.channel;
+                line.write_samples(|x| [row[x].z, row[x].y, row[x].x][channel] as f32)
+                    .unwrap()
+            });
+            compressor.compress_block(y, block).unwrap();
+            y += 1;
+        });
+        Ok(())
+    })
+    .unwrap();
+    statistics
+}
+
+fn dump_to_float_image(
+    height: u32,
+    width: u32,
+    values: &[Vector3<f64>],
+    format: ImageFormat,
+    metadata: &[(&str, String)],
+    output_path: &str,
+) {
+    if format == ImageFormat::Hdr {
+        let pixels: Vec<Rgb<f32>> = values
+            .iter()
+            .map(|value| Rgb([value.x as f32, value.y as f32, value.z as f32]))
+            .collect();
+        let output = BufWriter::new(fs::File::create(output_path).unwrap());
+        HdrEncoder::new(output)
+            .encode(&pixels, width as usize, height as usize)
+            .unwrap();
+    } else {
+        let mut image = exr::prelude::Image::from_channels(
+            (width as usize, height as usize),
+            SpecificChannels::rgb(|position: Vec2<usize>| {
+                let value = values[position.y() * width as usize + position.x()];
+                (value.x as f32, value.y as f32, value.z as f32)
+            }),
+        );
+        for (key, value) in metadata {
+            image.attributes.other.insert(
+                Text::from(*key),
+                AttributeValue::Text(Text::from(value.as_str())),
+            );
+        }
+        image.write().to_file(output_path).unwrap();
+    }
 }
@@ -0,0 +1,222 @@
+use nalgebra::{Matrix2, Vector2, Vector3};
+
+use crate::frame::Frame;
+use crate::geometry::{build_shifted_ray, intersect_scene, intersect_scene_index};
+use crate::medium::Medium;
+use crate::rendering::{refract, schlick_reflectance, surface_color};
+use crate::scene::{Material, Scene};
+
+// Newton steps before a connection is given up
+const MAX_ITERATIONS: usize = 20;
+// offset of the directions the derivatives are taken at, in radians
+const DERIVATIVE_STEP: f64 = 1e-6;
+// miss of the light, relative to its distance, that counts as reaching it
+const TOLERANCE: f64 = 1e-7;
+// largest turn of the direction in one step, in radians, so that a poor guess does not
+// throw the walk off the glass it has to pass
+const MAX_STEP: f64 = 0.1;
+
+// A path from a point to a point light that refracts at every smooth glass interface
+// on the way.
+pub(crate) struct Connection {
+    // unit direction the path leaves the point in
+    pub direction: Vector3<f64>,
+    // the last interface before the light
+    pub last_point: Vector3<f64>,
+    // Fresnel transmittance, glass color and absorption inside the glass, and the
+    // transmittance of the medium around the point outside of it
+    pub throughput: Vector3<f64>,
+    // solid angle around `direction` per area the path sweeps across the last segment
+    // at the light, which stands in for the inverse square of a straight connection
+    pub solid_angle_density: f64,
+    pub length: f64,
+}
+
+// A ray from the point refracted through a fixed number of interfaces.
+struct Walk {
+    primitives: Vec<usize>,
+    point: Vector3<f64>,
+    direction: Vector3<f64>,
+    throughput: Vector3<f64>,
+    length: f64,
+    // absorption of the glass the walk is inside of, the innermost last
+    inside: Vec<Vector3<f64>>,
+}
+
+impl Walk {
+    // Dims the throughput over a segment from the walk's point: by the absorption of
+    // the glass around it, by the medium around the point outside of any.
+    fn cross(&mut self, distance: f64, medium: Option<&dyn Medium>) {
+        match self.inside.last() {
+            Some(absorption) => {
+                self.throughput = self
+                    .throughput
+                    .component_mul(&(-absorption * distance).map(f64::exp))
+            }
+            None => {
+                self.throughput *= medium.map_or(1.0, |medium| {
+                    medium.transmittance(&self.point, &self.direction, distance)
+                })
+            }
+        }
+        self.length += distance;
+    }
+}
+
+// The smooth glass primitives the straight line from the point to the light passes,
+// in order. None when anything else is in the way or there are more than
+// `max_interfaces` of them.
+fn glass_on_line(
+    scene: &Scene,
+    point: &Vector3<f64>,
+    light_position: &Vector3<f64>,
+    max_interfaces: u32,
+) -> Option<Vec<usize>> {
+    let direction = (light_position - point).normalize();
+    let mut primitives = Vec::new();
+    let mut from = *point;
+    while let Some((intersection, index)) = intersect_scene_index(
+        &build_shifted_ray(from, direction),
+        scene,
+        Some((light_position - from).norm()),
+    ) {
+        let primitive = &scene.primitives[index];
+        if primitive.mix.is_some()
+            || !matches!(primitive.material, Material::DIELECTRIC { .. })
+            || primitives.len() as u32 == max_interfaces
+        {
+            return None;
+        }
+        primitives.push(index);
+        from = build_shifted_ray(from, direction).point + direction * intersection.ts[0];
+    }
+    Some(primitives)
+}
+
+// Follows the unit direction from the point through `interfaces` refractions. None
+// when it misses them, meets anything but smooth glass or reflects totally. Dispersive
+// glass refracts with its IOR at the d line.
+fn walk(
+    scene: &Scene,
+    point: &Vector3<f64>,
+    direction: &Vector3<f64>,
+    interfaces: usize,
+    medium: Option<&dyn Medium>,
+) -> Option<Walk> {
+    let mut walk = Walk {
+        primitives: Vec::with_capacity(interfaces),
+        point: *point,
+        direction: *direction,
+        throughput: Vector3::repeat(1.0),
+        length: 0.0,
+        inside: Vec::new(),
+    };
+    for _ in 0..interfaces {
+        let ray = build_shifted_ray(walk.point, walk.direction);
+        let (intersection, index) = intersect_scene_index(&ray, scene, None)?;
+        let primitive = &scene.primitives[index];
+        let Material::DIELECTRIC {
+            ior, absorption, ..
+        } = primitive.material
+        else {
+            return None;
+        };
+        if primitive.mix.is_some() {
+            return None;
+        }
+        let hit_point = ray.point + ray.direction * intersection.ts[0];
+        walk.cross((hit_point - walk.point).norm(), medium);
+        let normal = &intersection.normals[0];
+        let (nu_1, nu_2) = if intersection.outside {
+            (1.0, ior)
+        } else {
+            (ior, 1.0)
+        };
+        let refracted = refract(&walk.direction, normal, nu_1, nu_2)?;
+        walk.throughput *= 1.0 - schlick_reflectance(-normal.dot(&walk.direction), nu_1, nu_2);
+        if intersection.outside {
+            walk.throughput = walk
+                .throughput
+                .component_mul(&surface_color(primitive, &hit_point));
+            walk.inside.push(absorption);
+        } else {
+            walk.inside.pop();
+        }
+        walk.primitives.push(index);
+        walk.point = hit_point;
+        walk.direction = refracted.normalize();
+    }
+    Some(walk)
+}
+
+// Manifold next event estimation (Hanika et al., "Manifold Next Event Estimation",
+// 2015): the glass on the straight line to the light is kept, and Newton's method
+// turns the direction leaving the point until the refracted path through that glass
+// ends at the light. The derivatives are finite differences of the walk. Only the
+// solution nearest the straight line is found, so the other images of the light a
+// lens can form are left to the paths that bounce into it.
+pub(crate) fn connect(
+    scene: &Scene,
+    point: &Vector3<f64>,
+    light_position: &Vector3<f64>,
+    max_interfaces: u32,
+    medium: Option<&dyn Medium>,
+) -> Option<Connection> {
+    let primitives = glass_on_line(scene, point, light_position, max_interfaces)?;
+    if primitives.is_empty() {
+        return None;
+    }
+    let distance = (light_position - point).norm();
+    let walk_along = |direction: &Vector3<f64>| {
+        walk(scene, point, direction, primitives.len(), medium)
+            .filter(|walk| walk.primitives == primitives)
+    };
+    let mut direction = (light_position - point) / distance;
+    for _ in 0..MAX_ITERATIONS {
+        let mut walked = walk_along(&direction)?;
+        // where the last segment of a walk crosses the plane through the light across
+        // this walk's last segment, relative to the light
+        let across = Frame::from_normal(&walked.direction);
+        let miss = |walk: &Walk| {
+            let along = walk.direction.dot(&across.normal);
+            let t = (light_position - walk.point).dot(&across.normal) / along;
+            (along > 0.0 && t > 0.0).then(|| {
+                let offset = walk.point + t * walk.direction - light_position;
+                Vector2::new(offset.dot(&across.tangent), offset.dot(&across.bitangent))
+            })
+        };
+        let offset = miss(&walked)?;
+        let turns = Frame::from_normal(&direction);
+        let derivative = |axis: &Vector3<f64>| {
+            let moved = walk_along(&(direction + DERIVATIVE_STEP * axis).normalize())?;
+            Some((miss(&moved)? - offset) / DERIVATIVE_STEP)
+        };
+        let jacobian =
+            Matrix2::from_columns(&[derivative(&turns.tangent)?, derivative(&turns.bitangent)?]);
+        if offset.norm() < TOLERANCE * distance {
+            let to_light = light_position - walked.point;
+            let last_distance = to_light.norm();
+            if intersect_scene(
+                &build_shifted_ray(walked.point, walked.direction),
+                scene,
+                Some(last_distance),
+            )
+            .is_some()
+            {
+                return None;
+            }
+            walked.cross(last_distance, medium);
+            return Some(Connection {
+                direction,
+                last_point: walked.point,
+                throughput: walked.throughput,
+                solid_angle_density: 1.0 / jacobian.determinant().abs(),
+                length: walked.length,
+            });
+        }
+        let step = jacobian.try_inverse()? * -offset;
+        let step = step * (MAX_STEP / step.norm()).min(1.0);
+        direction = (direction + step.x * turns.tangent + step.y * turns.bitangent).normalize();
+    }
+    None
+}
@@ -0,0 +1,82 @@
+use std::f64::consts::PI;
+
+use nalgebra::Vector3;
+
+use crate::environment::EnvironmentMap;
+
+// Built-in shader-ball scene. The material definition is appended right after
+// the ball's NEW_PRIMITIVE, so any per-primitive token (COLOR, METALLIC, IOR,
+// EMISSION, ...) in it applies to the ball. preview_environment replaces the black
+// background once the scene is parsed.
+const PREVIEW_SCENE: &str = "
+DIMENSIONS 400 300
+BG_COLOR 0 0 0
+CAMERA_POSITION 0 1.8 -5
+CAMERA_RIGHT 1 0 0
+CAMERA_UP 0 0.988 0.156
+CAMERA_FORWARD 0 -0.156 0.988
+CAMERA_FOV_X 0.9
+RAY_DEPTH 8
+SAMPLES 64
+
+NEW_PRIMITIVE
+PLANE 0 1 0
+COLOR 0.5 0.5 0.5
+
+NEW_PRIMITIVE
+BOX 1.5 0.05 1.5
+POSITION -3 5 -3
+ROTATION 0.3 0 -0.3 0.9
+EMISSION 6 6 6
+
+NEW_PRIMITIVE
+BOX 1 0.05 1
+POSITION 4 3 -1
+ROTATION 0 0 0.38 0.92
+EMISSION 2 2 2.2
+
+NEW_PRIMITIVE
+ELLIPSOID 1 1 1
+POSITION 0 1 0
+";
+
+pub fn build_preview_scene(material_definition: &str) -> String {
+    format!("{}{}\n", PREVIEW_SCENE, material_definition)
+}
+
+// Studio sky the preview is lit by: a gradient from the horizon up to the zenith over a
+// dim floor, and a round softbox above and behind the camera for metals and glass to
+// reflect.
+const ENVIRONMENT_WIDTH: usize = 256;
+const ENVIRONMENT_HEIGHT: usize = 128;
+const HORIZON_COLOR: Vector3<f64> = Vector3::new(0.7, 0.72, 0.75);
+const ZENITH_COLOR: Vector3<f64> = Vector3::new(0.45, 0.55, 0.7);
+const FLOOR_COLOR: Vector3<f64> = Vector3::new(0.2, 0.19, 0.18);
+const SOFTBOX_DIRECTION: Vector3<f64> = Vector3::new(-0.4, 0.7, -0.6);
+const SOFTBOX_RADIUS: f64 = 0.3;
+const SOFTBOX_COLOR: Vector3<f64> = Vector3::new(4.0, 4.0, 4.0);
+
+pub fn preview_environment() -> EnvironmentMap {
+    let softbox = SOFTBOX_DIRECTION.normalize();
+    let texels = (0..ENVIRONMENT_WIDTH * ENVIRONMENT_HEIGHT)
+        .map(|index| {
+            let theta = PI * ((index / ENVIRONMENT_WIDTH) as f64 + 0.5) / ENVIRONMENT_HEIGHT as f64;
+            let phi = 2.0 * PI * ((index % ENVIRONMENT_WIDTH) as f64 + 0.5)
+                / ENVIRONMENT_WIDTH as f64
+                - PI;
+            let direction = Vector3::new(
+                theta.sin() * phi.sin(),
+                theta.cos(),
+                -theta.sin() * phi.cos(),
+            );
+            if direction.dot(&softbox) > SOFTBOX_RADIUS.cos() {
+                SOFTBOX_COLOR
+            } else if direction.y < 0.0 {
+                FLOOR_COLOR
+            } else {
+                HORIZON_COLOR.lerp(&ZENITH_COLOR, direction.y)
+            }
+        })
+        .collect();
+    EnvironmentMap::new(ENVIRONMENT_WIDTH, ENVIRONMENT_HEIGHT, texels)
+}
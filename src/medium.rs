@@ -0,0 +1,126 @@
+use std::f64::consts::PI;
+
+use nalgebra::Vector3;
+use rand::RngCore;
+
+use crate::frame::Frame;
+
+// What the integrator needs from a participating medium. Positions and directions are
+// passed along so that media with a varying density can implement it too; directions
+// are unit vectors pointing along the light's travel.
+pub trait Medium: Send + Sync {
+    // Fraction of the light that crosses `distance` from `point` without being
+    // absorbed or scattered away.
+    fn transmittance(&self, point: &Vector3<f64>, direction: &Vector3<f64>, distance: f64) -> f64;
+
+    // Where a ray interacts before reaching the surface at `max_distance`, as the
+    // distance and the weight of the light scattered there. None when the ray reaches
+    // the surface, which is then seen unweighted. `u` drives the first step, tracking
+    // through a varying density draws any further ones from `rng`.
+    fn sample_interaction(
+        &self,
+        point: &Vector3<f64>,
+        direction: &Vector3<f64>,
+        max_distance: f64,
+        u: f64,
+        rng: &mut dyn RngCore,
+    ) -> Option<(f64, f64)>;
+
+    // Density over the sphere of scattering from `direction` into `scattered`.
+    fn phase(&self, direction: &Vector3<f64>, scattered: &Vector3<f64>) -> f64;
+
+    // Scattered direction distributed exactly like the phase function, so the phase
+    // over the pdf is always 1.
+    fn sample_phase(&self, direction: &Vector3<f64>, u: [f64; 2]) -> Vector3<f64>;
+
+    fn shadow_rays(&self) -> ShadowRays;
+}
+
+// How a medium spends shadow rays. Each scatter event takes `splits` light samples of
+// the emitters and the light from outside the scene, zero leaves them to the phase
+// function. Shadow rays towards point and spot lights whose transmittance is below
+// `roulette_threshold` are traced with probability transmittance / threshold, which
+// saves most rays through dense media for a little noise.
+#[derive(Clone, Copy)]
+pub struct ShadowRays {
+    pub splits: u32,
+    pub roulette_threshold: f64,
+}
+
+impl Default for ShadowRays {
+    fn default() -> Self {
+        ShadowRays {
+            splits: 1,
+            roulette_threshold: 0.0,
+        }
+    }
+}
+
+// Constant coefficients per unit length and the Henyey-Greenstein asymmetry g:
+// positive scatters forward, negative backward.
+#[derive(Clone, Copy)]
+pub struct HomogeneousMedium {
+    pub sigma_a: f64,
+    pub sigma_s: f64,
+    pub g: f64,
+    pub shadow_rays: ShadowRays,
+}
+
+impl HomogeneousMedium {
+    pub fn sigma_t(&self) -> f64 {
+        self.sigma_a + self.sigma_s
+    }
+}
+
+impl Medium for HomogeneousMedium {
+    fn transmittance(&self, _: &Vector3<f64>, _: &Vector3<f64>, distance: f64) -> f64 {
+        (-self.sigma_t() * distance).exp()
+    }
+
+    // Distances are drawn with the density sigma_t * transmittance, which leaves the
+    // albedo as the weight of an interaction and no weight on reaching the surface.
+    fn sample_interaction(
+        &self,
+        _: &Vector3<f64>,
+        _: &Vector3<f64>,
+        max_distance: f64,
+        u: f64,
+        _: &mut dyn RngCore,
+    ) -> Option<(f64, f64)> {
+        let distance = -(1.0 - u).ln() / self.sigma_t();
+        (distance < max_distance).then(|| (distance, self.sigma_s / self.sigma_t()))
+    }
+
+    fn phase(&self, direction: &Vector3<f64>, scattered: &Vector3<f64>) -> f64 {
+        henyey_greenstein(self.g, direction.dot(scattered))
+    }
+
+    fn sample_phase(&self, direction: &Vector3<f64>, u: [f64; 2]) -> Vector3<f64> {
+        sample_henyey_greenstein(self.g, direction, u)
+    }
+
+    fn shadow_rays(&self) -> ShadowRays {
+        self.shadow_rays
+    }
+}
+
+pub fn henyey_greenstein(g: f64, cos_theta: f64) -> f64 {
+    let denominator = 1.0 + g * g - 2.0 * g * cos_theta;
+    (1.0 - g * g) / (4.0 * PI * denominator * denominator.sqrt())
+}
+
+pub fn sample_henyey_greenstein(g: f64, direction: &Vector3<f64>, u: [f64; 2]) -> Vector3<f64> {
+    let cos_theta = if g.abs() < 1e-3 {
+        1.0 - 2.0 * u[0]
+    } else {
+        let ratio = (1.0 - g * g) / (1.0 - g + 2.0 * g * u[0]);
+        ((1.0 + g * g - ratio * ratio) / (2.0 * g)).clamp(-1.0, 1.0)
+    };
+    let sin_theta = (1.0 - cos_theta * cos_theta).max(0.0).sqrt();
+    let phi = 2.0 * PI * u[1];
+    Frame::from_normal(direction).to_world(&Vector3::new(
+        sin_theta * phi.cos(),
+        sin_theta * phi.sin(),
+        cos_theta,
+    ))
+}
@@ -0,0 +1,64 @@
+use std::time::Duration;
+
+use crate::rng::RngBackend;
+use crate::sampler::SamplerType;
+use crate::scene::Scene;
+
+// 64-bit FNV-1a of the scene text, stable across builds unlike the std hasher.
+pub fn scene_hash(scene_content: &str) -> u64 {
+    scene_content
+        .bytes()
+        .fold(0xcbf29ce484222325, |hash, byte| {
+            (hash ^ byte as u64).wrapping_mul(0x100000001b3)
+        })
+}
+
+fn sampler_name(sampler_type: SamplerType) -> &'static str {
+    match sampler_type {
+        SamplerType::Independent => "INDEPENDENT",
+        SamplerType::Stratified => "STRATIFIED",
+        SamplerType::Halton => "HALTON",
+        SamplerType::Sobol => "SOBOL",
+    }
+}
+
+fn rng_backend_name(rng_backend: RngBackend) -> &'static str {
+    match rng_backend {
+        RngBackend::Thread => "THREAD",
+        RngBackend::Pcg32 => "PCG32",
+        RngBackend::Xoshiro256PlusPlus => "XOSHIRO256PP",
+    }
+}
+
+// How an image was produced, as key and value pairs for PNG text chunks and OpenEXR
+// header attributes. Settings are taken after the command line overrides.
+pub fn render_metadata(
+    scene: &Scene,
+    scene_hash: u64,
+    render_time: Duration,
+) -> Vec<(&'static str, String)> {
+    vec![
+        (
+            "Software",
+            format!("{} {}", env!("CARGO_PKG_NAME"), env!("CARGO_PKG_VERSION")),
+        ),
+        ("Scene hash", format!("{:016x}", scene_hash)),
+        (
+            "Seed",
+            scene
+                .seed
+                .map_or("none".to_string(), |seed| seed.to_string()),
+        ),
+        ("Samples", scene.samples.to_string()),
+        (
+            "Integrator",
+            format!(
+                "path tracer, ray depth {}, {} sampler, {} rng",
+                scene.ray_depth,
+                sampler_name(scene.sampler_type),
+                rng_backend_name(scene.rng_backend)
+            ),
+        ),
+        ("Render time", format!("{:.3} s", render_time.as_secs_f64())),
+    ]
+}
@@ -0,0 +1,100 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use nalgebra::Vector3;
+
+use crate::assets::Assets;
+use crate::geometry::Shape;
+
+// OBJ indices are 1-based, negative ones count back from the last element so far.
+fn resolve_index(token: &str, count: usize) -> Option<usize> {
+    let index: i64 = token.parse().ok()?;
+    let resolved = if index < 0 {
+        count as i64 + index
+    } else {
+        index - 1
+    };
+    (0..count as i64)
+        .contains(&resolved)
+        .then_some(resolved as usize)
+}
+
+// Positions, normals and faces of a Wavefront OBJ file as a triangle mesh in the
+// primitive's local space. Polygons are split into fans, everything else (texture
+// coordinates, groups, materials) is ignored. Errors are messages for the scene
+// parser to report.
+pub fn load_obj(assets: &Assets, path: &str) -> Result<Shape, String> {
+    let read_error = |error| format!("cannot read OBJ file: {}", error);
+    let content = String::from_utf8(assets.read(path).map_err(read_error)?)
+        .map_err(|_| read_error("not valid UTF-8".to_string()))?;
+    let mut positions: Vec<Vector3<f64>> = vec![];
+    let mut file_normals: Vec<Vector3<f64>> = vec![];
+    // corners with the same position and normal share a mesh vertex
+    let mut corner_vertices: HashMap<(usize, Option<usize>), usize> = HashMap::new();
+    let mut vertices: Vec<Vector3<f64>> = vec![];
+    let mut normals: Vec<Option<Vector3<f64>>> = vec![];
+    let mut triangles: Vec<[usize; 3]> = vec![];
+
+    for (line_index, line) in content.lines().enumerate() {
+        let format_error = || format!("OBJ format error on line {}", line_index + 1);
+        let tokens: Vec<&str> = line.split_whitespace().collect();
+        let parse_vector3 = || -> Result<Vector3<f64>, String> {
+            let mut values = tokens[1..].iter().map(|token| token.parse().ok());
+            match (values.next(), values.next(), values.next()) {
+                (Some(Some(x)), Some(Some(y)), Some(Some(z))) => Ok(Vector3::new(x, y, z)),
+                _ => Err(format_error()),
+            }
+        };
+        match tokens.first() {
+            Some(&"v") => positions.push(parse_vector3()?),
+            Some(&"vn") => file_normals.push(parse_vector3()?),
+            Some(&"f") => {
+                let mut corners: Vec<usize> = vec![];
+                for corner in &tokens[1..] {
+                    // v, v/vt, v//vn or v/vt/vn
+                    let mut parts = corner.split('/');
+                    let position = parts
+                        .next()
+                        .and_then(|part| resolve_index(part, positions.len()))
+                        .ok_or_else(format_error)?;
+                    let normal = match parts.nth(1).filter(|part| !part.is_empty()) {
+                        Some(part) => {
+                            Some(resolve_index(part, file_normals.len()).ok_or_else(format_error)?)
+                        }
+                        None => None,
+                    };
+                    corners.push(
+                        *corner_vertices
+                            .entry((position, normal))
+                            .or_insert_with(|| {
+                                vertices.push(positions[position]);
+                                normals.push(normal.map(|normal| file_normals[normal].normalize()));
+                                vertices.len() - 1
+                            }),
+                    );
+                }
+                if corners.len() < 3 {
+                    return Err(format_error());
+                }
+                for index in 1..corners.len() - 1 {
+                    triangles.push([corners[0], corners[index], corners[index + 1]]);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    Ok(Shape::TriangleMesh {
+        vertices: Arc::new(vertices),
+        triangles: Arc::new(triangles),
+        // smooth shading only when every corner has a normal
+        normals: Arc::new(
+            normals
+                .into_iter()
+                .collect::<Option<_>>()
+                .unwrap_or_default(),
+        ),
+        bvh: Default::default(),
+        area_cdf: Default::default(),
+    })
+}
@@ -0,0 +1,38 @@
+use std::fmt::Write;
+
+use nalgebra::Vector3;
+
+pub fn segments_to_obj(segments: &[[Vector3<f64>; 2]]) -> String {
+    let mut obj = String::new();
+    for [start, end] in segments {
+        writeln!(obj, "v {} {} {}", start.x, start.y, start.z).unwrap();
+        writeln!(obj, "v {} {} {}", end.x, end.y, end.z).unwrap();
+    }
+    // OBJ indices are 1-based
+    for index in 0..segments.len() {
+        writeln!(obj, "l {} {}", 2 * index + 1, 2 * index + 2).unwrap();
+    }
+    obj
+}
+
+pub fn segments_to_ply(segments: &[[Vector3<f64>; 2]]) -> String {
+    let mut ply = String::new();
+    writeln!(ply, "ply").unwrap();
+    writeln!(ply, "format ascii 1.0").unwrap();
+    writeln!(ply, "element vertex {}", 2 * segments.len()).unwrap();
+    writeln!(ply, "property float x").unwrap();
+    writeln!(ply, "property float y").unwrap();
+    writeln!(ply, "property float z").unwrap();
+    writeln!(ply, "element edge {}", segments.len()).unwrap();
+    writeln!(ply, "property int vertex1").unwrap();
+    writeln!(ply, "property int vertex2").unwrap();
+    writeln!(ply, "end_header").unwrap();
+    for [start, end] in segments {
+        writeln!(ply, "{} {} {}", start.x, start.y, start.z).unwrap();
+        writeln!(ply, "{} {} {}", end.x, end.y, end.z).unwrap();
+    }
+    for index in 0..segments.len() {
+        writeln!(ply, "{} {}", 2 * index, 2 * index + 1).unwrap();
+    }
+    ply
+}
@@ -0,0 +1,97 @@
+use std::f64::consts::PI;
+use std::fmt::Write;
+
+use nalgebra::Vector3;
+
+use crate::distribution::generate_unit_on_sphere;
+use crate::geometry::Ray;
+use crate::rendering::{build_global_distr, get_ray_color, start_pixel, PathContext, PathDepth};
+use crate::rng::create_rng;
+use crate::sampler::Sampler;
+use crate::scene::Scene;
+
+pub const SH_COEFFICIENTS: usize = 9;
+
+// Real spherical harmonics up to band 2, in the usual (l, m) order.
+fn sh_basis(d: &Vector3<f64>) -> [f64; SH_COEFFICIENTS] {
+    [
+        0.282095,
+        0.488603 * d.y,
+        0.488603 * d.z,
+        0.488603 * d.x,
+        1.092548 * d.x * d.y,
+        1.092548 * d.y * d.z,
+        0.315392 * (3.0 * d.z * d.z - 1.0),
+        1.092548 * d.x * d.z,
+        0.546274 * (d.x * d.x - d.y * d.y),
+    ]
+}
+
+pub fn bake_probes(scene: &Scene) -> Vec<[Vector3<f64>; SH_COEFFICIENTS]> {
+    let global_distr = &build_global_distr(scene);
+    let mut path = PathContext {
+        rng: create_rng(scene.rng_backend, scene.seed),
+        sampler: Sampler::new(scene.probe_samples, scene.sampler_type),
+        segments: None,
+        channel: None,
+        throughput: Vector3::repeat(1.0),
+        media: vec![scene.fog.clone()],
+        pass: None,
+    };
+
+    scene
+        .probes
+        .iter()
+        .enumerate()
+        .map(|(index, position)| {
+            let mut coefficients = [Vector3::<f64>::zeros(); SH_COEFFICIENTS];
+            start_pixel(scene, &mut path, index as u64);
+            for sample in 0..scene.probe_samples {
+                path.sampler.start_sample(sample);
+                let direction = generate_unit_on_sphere(path.rng.as_mut());
+                let ray = Ray {
+                    point: *position,
+                    direction,
+                };
+                let radiance = get_ray_color(
+                    scene,
+                    &mut path,
+                    global_distr,
+                    &ray,
+                    PathDepth::default(),
+                    None,
+                );
+                for (coefficient, basis) in coefficients.iter_mut().zip(sh_basis(&direction)) {
+                    *coefficient += radiance * basis;
+                }
+            }
+            // Monte Carlo estimate with the uniform sphere pdf 1 / (4 * pi)
+            coefficients.map(|coefficient| coefficient * 4.0 * PI / scene.probe_samples as f64)
+        })
+        .collect()
+}
+
+pub fn probes_to_json(scene: &Scene, probes: &[[Vector3<f64>; SH_COEFFICIENTS]]) -> String {
+    let mut json = String::new();
+    writeln!(json, "{{").unwrap();
+    writeln!(json, "  \"probes\": [").unwrap();
+    for (index, (position, coefficients)) in scene.probes.iter().zip(probes).enumerate() {
+        let coefficients: Vec<String> = coefficients
+            .iter()
+            .map(|c| format!("[{}, {}, {}]", c.x, c.y, c.z))
+            .collect();
+        writeln!(json, "    {{").unwrap();
+        writeln!(
+            json,
+            "      \"position\": [{}, {}, {}],",
+            position.x, position.y, position.z
+        )
+        .unwrap();
+        writeln!(json, "      \"sh\": [{}]", coefficients.join(", ")).unwrap();
+        let separator = if index + 1 < probes.len() { "," } else { "" };
+        writeln!(json, "    }}{}", separator).unwrap();
+    }
+    writeln!(json, "  ]").unwrap();
+    writeln!(json, "}}").unwrap();
+    json
+}
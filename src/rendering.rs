@@ -1,21 +1,49 @@
+use std::collections::BTreeMap;
 use std::f64::consts::PI;
-use std::f64::EPSILON;
+use std::ops::Range;
+use std::sync::Arc;
+use std::time::Instant;
 
-use nalgebra::Vector3;
-use rand::rngs::ThreadRng;
+use image::RgbImage;
+use nalgebra::{Vector2, Vector3};
 use rand::Rng;
+use rand::RngCore;
+use rand::SeedableRng;
+use rand_pcg::Pcg32;
+use rayon::prelude::*;
 
+use crate::distribution::generate_unit_on_sphere;
+use crate::distribution::BackgroundDistr;
 use crate::distribution::CosineWeightedDistr;
 use crate::distribution::DistributionTooling;
+use crate::distribution::EnvironmentDistr;
 use crate::distribution::LightSourceDistr;
 use crate::distribution::MixDistr;
-use crate::geometry::Intersection;
-use crate::geometry::Shape;
+use crate::filter::FilterSampler;
 use crate::geometry::Shape::Plane;
-use crate::geometry::{build_shifted_ray, intersect_scene, Ray};
-use crate::scene::{self, Scene};
+use crate::geometry::{
+    build_shifted_ray, intersect_scene, intersect_scene_index, texture_coordinates, traced_rays,
+    Intersection, Ray,
+};
+use crate::manifold;
+use crate::medium::Medium;
+use crate::rng::{create_rng, pixel_seed};
+use crate::sampler::{Dimension, Sampler};
+use crate::scene::{
+    self, get_light_characteristic_to_point, is_light_linked, light_attenuation, EmissionGradient,
+    GradientSpace, Light, LightType, MixWeight, Primitive, Scene,
+};
+use crate::sensor::simulate_sensor;
 
 const BLACK: Vector3<f64> = Vector3::<f64>::new(0.0, 0.0, 0.0);
+pub(crate) const CLEARCOAT_IOR: f64 = 1.5;
+const MISSED_RAY_LENGTH: f64 = 100.0;
+const FLAKE_TILT: f64 = 0.35;
+// Fraunhofer d, F and C lines and the wavelengths the RGB channels stand for, in µm
+const WAVELENGTH_D: f64 = 0.5876;
+const WAVELENGTH_F: f64 = 0.4861;
+const WAVELENGTH_C: f64 = 0.6563;
+const CHANNEL_WAVELENGTHS: [f64; 3] = [0.61, 0.55, 0.465];
 
 fn aces_tonemap(x: f64) -> f64 {
     const A: f64 = 2.51;
@@ -36,8 +64,8 @@ fn proportion_to_value(color: Vector3<f64>) -> [u8; 3] {
 }
 
 // fn gen_w_and_pdf(
-//     global_distr: &dyn DistributionTooling,
-//     rng: &mut ThreadRng,
+//     global_distr: &GlobalDistr,
+//     rng: &mut dyn RngCore,
 //     intersection_point: &Vector3<f64>,
 //     intersection: &Intersection,
 // ) -> (Vector3<f64>, f64) {
@@ -52,147 +80,1892 @@ fn proportion_to_value(color: Vector3<f64>) -> [u8; 3] {
 //     }
 // }
 
-fn get_ray_color(
+fn to_gradient_space(
     scene: &Scene,
-    rng: &mut ThreadRng,
-    global_distr: &dyn DistributionTooling,
+    primitive: &Primitive,
+    space: GradientSpace,
+    point: &Vector3<f64>,
+) -> Vector3<f64> {
+    match space {
+        GradientSpace::Object => primitive
+            .rotation
+            .conjugate()
+            .transform_vector(&(point - primitive.position)),
+        GradientSpace::World => *point,
+        GradientSpace::Camera => {
+            let relative = point - scene.camera.position;
+            Vector3::new(
+                relative.dot(&scene.camera.right_axis.normalize()),
+                relative.dot(&scene.camera.up_axis.normalize()),
+                relative.dot(&scene.camera.forward_axis.normalize()),
+            )
+        }
+    }
+}
+
+fn get_emission(
+    scene: &Scene,
+    primitive: &Primitive,
+    point: &Vector3<f64>,
+    scattering: Option<Scattering>,
+) -> Vector3<f64> {
+    let scattered_from = scattering.and_then(|scattering| scattering.primitive);
+    if scattered_from.is_some_and(|scattered_from| !is_light_linked(primitive, scattered_from)) {
+        return BLACK;
+    }
+    let scale = match primitive.emission_gradient {
+        EmissionGradient::Constant => 1.0,
+        EmissionGradient::Radial {
+            space,
+            center,
+            radius,
+            inner_scale,
+            outer_scale,
+        } => {
+            let distance = (to_gradient_space(scene, primitive, space, point) - center).norm();
+            let t = f64::clamp(distance / radius, 0.0, 1.0);
+            inner_scale + (outer_scale - inner_scale) * t
+        }
+        EmissionGradient::Linear {
+            space,
+            start,
+            end,
+            start_scale,
+            end_scale,
+        } => {
+            let axis = end - start;
+            let t = (to_gradient_space(scene, primitive, space, point) - start).dot(&axis)
+                / axis.norm_squared();
+            let t = f64::clamp(t, 0.0, 1.0);
+            start_scale + (end_scale - start_scale) * t
+        }
+    };
+    primitive.emission * scale
+}
+
+// Per channel IOR of the Cauchy fit n = a + b / λ² that has the given IOR at the d
+// line and the given Abbe number.
+fn dispersed_ior(ior: f64, abbe: f64, channel: usize) -> f64 {
+    let b = (ior - 1.0) / (abbe * (WAVELENGTH_F.powi(-2) - WAVELENGTH_C.powi(-2)));
+    let a = ior - b / WAVELENGTH_D.powi(2);
+    a + b / CHANNEL_WAVELENGTHS[channel].powi(2)
+}
+
+fn reflect(direction: &Vector3<f64>, normal: &Vector3<f64>) -> Vector3<f64> {
+    direction - 2.0 * normal.dot(direction) * normal
+}
+
+// Albedo at a point of the surface, looked up in the texture where there is one.
+pub(crate) fn surface_color(primitive: &Primitive, point: &Vector3<f64>) -> Vector3<f64> {
+    let local_point = primitive
+        .rotation
+        .conjugate()
+        .transform_vector(&(point - primitive.position));
+    primitive
+        .texture
+        .as_ref()
+        .zip(texture_coordinates(&primitive.shape, &local_point))
+        .map_or(primitive.color, |(texture, uv)| {
+            texture.color(&primitive.uv_transform.apply(&uv))
+        })
+}
+
+// Whether a primitive's emission is seen, which is only the case for the materials
+// shaded as diffuse. A mix material never changes it, so light sampling and the
+// surfaces rays hit always agree on the light.
+fn shows_emission(primitive: &Primitive) -> bool {
+    matches!(
+        primitive.material,
+        scene::Material::DIFFUSE | scene::Material::HOLDOUT
+    )
+}
+
+// The material shading a hit and its albedo: the mix material with the probability its
+// weight has at the point, the primitive's own otherwise.
+pub(crate) fn pick_material<'a>(
+    path: &mut PathContext,
+    primitive: &'a Primitive,
+    ray: &Ray,
+    intersection: &Intersection,
+    vertex: u32,
+) -> (&'a scene::Material, Vector3<f64>) {
+    let point = ray.point + ray.direction * intersection.ts[0];
+    let Some(mix) = &primitive.mix else {
+        return (&primitive.material, surface_color(primitive, &point));
+    };
+    let weight = match &mix.weight {
+        MixWeight::Constant(weight) => *weight,
+        MixWeight::Mask(texture) => {
+            let local_point = primitive
+                .rotation
+                .conjugate()
+                .transform_vector(&(point - primitive.position));
+            texture_coordinates(&primitive.shape, &local_point).map_or(0.0, |uv| {
+                luminance(&texture.color(&primitive.uv_transform.apply(&uv)))
+            })
+        }
+        MixWeight::Fresnel(ior) => {
+            let cos_theta = intersection.normals[0]
+                .dot(&ray.direction.normalize())
+                .abs();
+            schlick_reflectance(cos_theta, 1.0, *ior)
+        }
+    };
+    if path
+        .sampler
+        .get_1d(path.rng.as_mut(), Dimension::MaterialPick(vertex))
+        < weight
+    {
+        (&mix.material, mix.color)
+    } else {
+        (&primitive.material, surface_color(primitive, &point))
+    }
+}
+
+// Whether a direction leaving the hit stays on the side the ray came from. The shading
+// normals of smooth meshes tilt away from the true surface, so directions above them
+// can point into it, which would leak light through the mesh. Such directions
+// contribute nothing.
+fn leaves_surface(intersection: &Intersection, direction: &Vector3<f64>) -> bool {
+    intersection
+        .geometric_normals
+        .as_ref()
+        .is_none_or(|normals| normals[0].dot(direction) > 0.0)
+}
+
+#[allow(clippy::too_many_arguments)]
+fn get_diffuse_color(
+    scene: &Scene,
+    path: &mut PathContext,
+    global_distr: &GlobalDistr,
+    ray: &Ray,
+    intersection: &Intersection,
+    primitive: &Primitive,
+    color: Vector3<f64>,
+    depth: PathDepth,
+) -> Vector3<f64> {
+    // a shadow ray adds a vertex to the path just like a bounce does
+    if depth.bounce().bounces >= scene.ray_depth {
+        return BLACK;
+    }
+    let intersection_point = ray.point + ray.direction * intersection.ts[0];
+    let normal = &intersection.normals[0];
+    let shifted_point = intersection_point + 0.0001 * ray.direction;
+    let medium = current_medium(path);
+    let direct = get_direct_light_color(
+        scene,
+        path,
+        &intersection_point,
+        medium.as_deref(),
+        |to_light| {
+            if leaves_surface(intersection, to_light) {
+                to_light.dot(normal) / PI
+            } else {
+                0.0
+            }
+        },
+    );
+
+    // one direction towards the emitters and one from the BRDF, each weighted by the
+    // balance heuristic against the density the other strategy has for it
+    let mut emitted = BLACK;
+    if let Some(lights) = &global_distr.lights {
+        let u = [
+            path.sampler
+                .get_1d(path.rng.as_mut(), Dimension::LightU(depth.vertex())),
+            path.sampler
+                .get_1d(path.rng.as_mut(), Dimension::LightV(depth.vertex())),
+        ];
+        let pick = path
+            .sampler
+            .get_1d(path.rng.as_mut(), Dimension::LightPick(depth.vertex()));
+        let sample = lights.sample(u, pick, &shifted_point, normal);
+        if sample.pdf > f64::EPSILON
+            && sample.value > f64::EPSILON
+            && leaves_surface(intersection, &sample.direction)
+        {
+            let bsdf_pdf = global_distr
+                .bsdf
+                .pdf(&shifted_point, normal, &sample.direction);
+            emitted = get_light_sample_radiance(
+                scene,
+                path,
+                &build_shifted_ray(intersection_point, sample.direction),
+                Some(primitive),
+            ) * (sample.value / sample.pdf * balance_heuristic(sample.pdf, bsdf_pdf));
+        }
+    }
+
+    let u = [
+        path.sampler
+            .get_1d(path.rng.as_mut(), Dimension::BsdfU(depth.vertex())),
+        path.sampler
+            .get_1d(path.rng.as_mut(), Dimension::BsdfV(depth.vertex())),
+    ];
+    // the cosine lobe is a single part, nothing to pick
+    let sample = global_distr.bsdf.sample(u, 0.0, &shifted_point, normal);
+    let direct = if in_pass(path, depth.bounce().bounces) {
+        color.component_mul(&(direct + emitted))
+    } else {
+        BLACK
+    };
+    if sample.pdf <= f64::EPSILON
+        || sample.value <= f64::EPSILON
+        || !leaves_surface(intersection, &sample.direction)
+    {
+        direct
+    } else {
+        let light_pdf = global_distr.lights.as_ref().map_or(0.0, |lights| {
+            lights.pdf(&shifted_point, normal, &sample.direction)
+        });
+        // ratio of the BRDF's value to the sampling pdf, optionally clamped to trade
+        // a little bias for fewer fireflies
+        let pdf_ratio = sample.value / sample.pdf;
+        let pdf_ratio = scene
+            .max_pdf_ratio
+            .map_or(pdf_ratio, |max_ratio| pdf_ratio.min(max_ratio));
+        trace_scattered(
+            scene,
+            path,
+            global_distr,
+            &build_shifted_ray(intersection_point, sample.direction),
+            depth.diffuse(),
+            Scattering {
+                primitive: Some(primitive),
+                emission_weight: balance_heuristic(sample.pdf, light_pdf),
+            },
+            color * pdf_ratio,
+        ) + direct
+    }
+}
+
+fn balance_heuristic(pdf: f64, other_pdf: f64) -> f64 {
+    pdf / (pdf + other_pdf)
+}
+
+// Radiance arriving along a light sample taken at a surface of `scattered_from`, or in
+// a medium when None: the emission of the first surface the ray hits, dimmed by the
+// medium on the way, or the light from outside the scene when it hits nothing. Only surfaces that get_ray_color
+// lets emit count, so that both strategies see the same light.
+fn get_light_sample_radiance(
+    scene: &Scene,
+    path: &PathContext,
     ray: &Ray,
-    depth: u32,
+    scattered_from: Option<&Primitive>,
 ) -> Vector3<f64> {
-    if depth >= scene.ray_depth {
+    let Some((intersection, primitive)) = intersect_scene(ray, scene, None) else {
+        return background_radiance(scene, &ray.direction) + scene.ambient_light;
+    };
+    if !shows_emission(primitive) {
         return BLACK;
     }
+    let point = ray.point + ray.direction * intersection.ts[0];
+    let transmittance = current_medium(path).map_or(1.0, |medium| {
+        medium.transmittance(
+            &ray.point,
+            &ray.direction.normalize(),
+            intersection.ts[0] * ray.direction.norm(),
+        )
+    });
+    get_emission(
+        scene,
+        primitive,
+        &point,
+        Some(Scattering {
+            primitive: scattered_from,
+            emission_weight: 1.0,
+        }),
+    ) * transmittance
+}
 
-    intersect_scene(&ray, scene, None)
-        .map(|(intersection, primitive)| {
-            let intersection_point = ray.point + ray.direction * intersection.ts[0];
-            match &primitive.material {
-                scene::Material::DIFFUSE => {
-                    let shifted_point = intersection_point + 0.0001 * ray.direction;
-                    let w = global_distr.sample(rng, &shifted_point, &intersection.normals[0]);
+// Light from point and directed lights, which bounces can never hit, so they are
+// sampled with a shadow ray each. `response` weighs the light coming from a direction:
+// the cosine over pi at a surface, leaving the albedo to the caller, or the phase
+// function in a medium. The medium around the point dims the light of point lights,
+// directed lights shine from outside the scene and so from outside the medium. Its
+// roulette threshold decides which shadow rays are only traced by chance.
+fn get_direct_light_color(
+    scene: &Scene,
+    path: &mut PathContext,
+    point: &Vector3<f64>,
+    medium: Option<&dyn Medium>,
+    response: impl Fn(&Vector3<f64>) -> f64,
+) -> Vector3<f64> {
+    let roulette_threshold = medium.map_or(0.0, |medium| medium.shadow_rays().roulette_threshold);
+    scene
+        .lights
+        .iter()
+        .map(|light| {
+            let (to_light, irradiance, distance) = get_light_characteristic_to_point(light, point);
+            let transmittance = medium.zip(distance).map_or(1.0, |(medium, distance)| {
+                medium.transmittance(point, &to_light, distance)
+            });
+            let survival = if transmittance < roulette_threshold {
+                transmittance / roulette_threshold
+            } else {
+                1.0
+            };
+            if survival < 1.0 && path.rng.gen::<f64>() >= survival {
+                return BLACK;
+            }
+            let to_light_response = response(&to_light);
+            let light = if to_light_response <= 0.0
+                || intersect_scene(&build_shifted_ray(*point, to_light), scene, distance).is_some()
+            {
+                get_light_through_glass(scene, light, point, medium, &response)
+            } else {
+                irradiance * to_light_response * transmittance
+            };
+            light / survival
+        })
+        .sum()
+}
 
-                    let pdf = global_distr.pdf(&shifted_point, &intersection.normals[0], &w);
+// Light of a point or spot light behind smooth glass, a light bulb or a headlight,
+// which no shadow ray reaches and no bounce can find. It arrives along the refracted
+// path manifold::connect finds, with the spot and the falloff taken over the last
+// segment's direction and the whole path's length.
+fn get_light_through_glass(
+    scene: &Scene,
+    light: &Light,
+    point: &Vector3<f64>,
+    medium: Option<&dyn Medium>,
+    response: impl Fn(&Vector3<f64>) -> f64,
+) -> Vector3<f64> {
+    let (LightType::Point { position } | LightType::Spot { position, .. }) = light.light_type
+    else {
+        return BLACK;
+    };
+    let max_interfaces = scene.transparent_depth.unwrap_or(scene.ray_depth);
+    let Some(connection) = manifold::connect(scene, point, &position, max_interfaces, medium)
+    else {
+        return BLACK;
+    };
+    let response = response(&connection.direction);
+    if response <= 0.0 {
+        return BLACK;
+    }
+    let (_, irradiance, last_distance) =
+        get_light_characteristic_to_point(light, &connection.last_point);
+    let last_attenuation = light_attenuation(light, last_distance.unwrap_or_default());
+    let falloff = last_attenuation / light_attenuation(light, connection.length)
+        * connection.length.powi(2)
+        * connection.solid_angle_density;
+    irradiance.component_mul(&connection.throughput) * falloff * response
+}
 
-                    if pdf <= EPSILON || w.dot(&intersection.normals[0]) <= EPSILON {
-                        primitive.emission
+// Light scattered towards the ray's origin at `point` inside the medium: point and
+// directed lights, the medium's splits of light samples and one ray in a direction
+// drawn from the phase function, all scaled by the weight the medium gave the
+// interaction. The light samples and the phase ray share the emission they find by
+// the balance heuristic, with the phase function as one more sample.
+#[allow(clippy::too_many_arguments)]
+fn get_medium_color(
+    scene: &Scene,
+    path: &mut PathContext,
+    global_distr: &GlobalDistr,
+    ray: &Ray,
+    point: Vector3<f64>,
+    medium: &dyn Medium,
+    weight: f64,
+    depth: PathDepth,
+) -> Vector3<f64> {
+    let direction = ray.direction.normalize();
+    let lights_sampled =
+        depth.bounce().bounces < scene.ray_depth && in_pass(path, depth.bounce().bounces);
+    let mut direct = if lights_sampled {
+        get_direct_light_color(scene, path, &point, Some(medium), |to_light| {
+            medium.phase(&direction, to_light)
+        })
+    } else {
+        BLACK
+    };
+    let lights = global_distr.lights.as_ref().filter(|_| lights_sampled);
+    let splits = medium.shadow_rays().splits;
+    if let Some(lights) = lights {
+        for split in 0..splits {
+            // the first split follows the sampler, the others are independent
+            let (u, pick) = if split == 0 {
+                (
+                    [
+                        path.sampler
+                            .get_1d(path.rng.as_mut(), Dimension::LightU(depth.vertex())),
+                        path.sampler
+                            .get_1d(path.rng.as_mut(), Dimension::LightV(depth.vertex())),
+                    ],
+                    path.sampler
+                        .get_1d(path.rng.as_mut(), Dimension::LightPick(depth.vertex())),
+                )
+            } else {
+                ([path.rng.gen(), path.rng.gen()], path.rng.gen())
+            };
+            // a medium has no normal, the light's own density is all that matters
+            let sample = lights.sample(u, pick, &point, &direction);
+            if sample.pdf <= f64::EPSILON {
+                continue;
+            }
+            let phase = medium.phase(&direction, &sample.direction);
+            direct += get_light_sample_radiance(
+                scene,
+                path,
+                &Ray {
+                    point,
+                    direction: sample.direction,
+                },
+                None,
+            ) * (phase / (splits as f64 * sample.pdf + phase));
+        }
+    }
+    let u = [
+        path.sampler
+            .get_1d(path.rng.as_mut(), Dimension::BsdfU(depth.vertex())),
+        path.sampler
+            .get_1d(path.rng.as_mut(), Dimension::BsdfV(depth.vertex())),
+    ];
+    let scattered = Ray {
+        point,
+        direction: medium.sample_phase(&direction, u),
+    };
+    let emission_weight = lights.filter(|_| splits > 0).map_or(1.0, |lights| {
+        let phase = medium.phase(&direction, &scattered.direction);
+        let light_pdf = lights.pdf(&point, &direction, &scattered.direction);
+        phase / (phase + splits as f64 * light_pdf)
+    });
+    let indirect = trace_scattered(
+        scene,
+        path,
+        global_distr,
+        &scattered,
+        depth.diffuse(),
+        Scattering {
+            primitive: None,
+            emission_weight,
+        },
+        Vector3::repeat(weight),
+    );
+    direct * weight + indirect
+}
+
+// Flakes are hashed from the cell of a regular grid containing the point, so each
+// flake keeps its orientation across samples and sparkles consistently.
+fn get_flake_normal(
+    local_point: &Vector3<f64>,
+    normal: &Vector3<f64>,
+    flake_size: f64,
+    flake_density: f64,
+) -> Option<Vector3<f64>> {
+    let cell = (local_point / flake_size).map(|x| x.floor() as i64);
+    let mut cell_rng = Pcg32::seed_from_u64(
+        (cell.x.wrapping_mul(73856093)
+            ^ cell.y.wrapping_mul(19349663)
+            ^ cell.z.wrapping_mul(83492791)) as u64,
+    );
+    if cell_rng.gen::<f64>() >= flake_density {
+        return None;
+    }
+    Some((normal + FLAKE_TILT * generate_unit_on_sphere(&mut cell_rng)).normalize())
+}
+
+// Schlick's approximation of the Fresnel reflectance at the boundary from IOR nu_1 to
+// IOR nu_2, for the cosine of the angle of incidence.
+pub(crate) fn schlick_reflectance(cos_theta: f64, nu_1: f64, nu_2: f64) -> f64 {
+    let r_0 = ((nu_1 - nu_2) / (nu_1 + nu_2)).powi(2);
+    r_0 + (1.0 - r_0) * (1.0 - cos_theta).powi(5)
+}
+
+pub(crate) enum DielectricLobe {
+    Reflected(Vector3<f64>),
+    Refracted(Vector3<f64>),
+}
+
+// How a ray along the unit `direction` leaves a smooth dielectric boundary from IOR
+// nu_1 to IOR nu_2: refracted with the Schlick transmittance, reflected otherwise and
+// always under total internal reflection. `u` picks the lobe and is only drawn when
+// there is a choice.
+pub(crate) fn sample_dielectric(
+    direction: &Vector3<f64>,
+    normal: &Vector3<f64>,
+    nu_1: f64,
+    nu_2: f64,
+    u: impl FnOnce() -> f64,
+) -> DielectricLobe {
+    let cos_tetta_1 = -normal.dot(direction);
+    match refract(direction, normal, nu_1, nu_2) {
+        Some(refracted) if u() > schlick_reflectance(cos_tetta_1, nu_1, nu_2) => {
+            DielectricLobe::Refracted(refracted)
+        }
+        _ => DielectricLobe::Reflected(direction + 2.0 * cos_tetta_1 * normal),
+    }
+}
+
+// Snell's law for the unit `direction` crossing from IOR nu_1 to IOR nu_2, with the
+// normal facing it. None under total internal reflection.
+pub(crate) fn refract(
+    direction: &Vector3<f64>,
+    normal: &Vector3<f64>,
+    nu_1: f64,
+    nu_2: f64,
+) -> Option<Vector3<f64>> {
+    let cos_tetta_1 = -normal.dot(direction);
+    let sin_tetta_2 = nu_1 / nu_2 * (1.0 - cos_tetta_1.powi(2)).sqrt();
+    (sin_tetta_2 <= 1.0).then(|| {
+        let cos_tetta_2 = (1.0 - sin_tetta_2.powi(2)).sqrt();
+        nu_1 / nu_2 * direction + (nu_1 / nu_2 * cos_tetta_1 - cos_tetta_2) * normal
+    })
+}
+
+pub(crate) enum CarPaintLobe {
+    Clearcoat(Vector3<f64>),
+    Flake(Vector3<f64>),
+    // the diffuse paint under the clearcoat
+    Base,
+}
+
+// The clearcoat reflects with its Schlick reflectance, picked by `u`. Light through it
+// reflects off the flake of the point's cell when there is one and its reflection
+// leaves the surface, off the base paint otherwise.
+pub(crate) fn sample_car_paint(
+    ray: &Ray,
+    intersection: &Intersection,
+    local_point: &Vector3<f64>,
+    flake_size: f64,
+    flake_density: f64,
+    u: f64,
+) -> CarPaintLobe {
+    let normal = intersection.normals[0];
+    let cos_tetta = -normal.dot(&ray.direction.normalize());
+    if u < schlick_reflectance(cos_tetta, 1.0, CLEARCOAT_IOR) {
+        return CarPaintLobe::Clearcoat(reflect(&ray.direction, &normal));
+    }
+    get_flake_normal(local_point, &normal, flake_size, flake_density)
+        .map(|flake_normal| reflect(&ray.direction, &flake_normal))
+        .filter(|direction| direction.dot(&normal) > 0.0 && leaves_surface(intersection, direction))
+        .map_or(CarPaintLobe::Base, CarPaintLobe::Flake)
+}
+
+// Refraction through smooth dielectrics is counted separately so that stacks of
+// glass are limited by TRANSPARENT_DEPTH rather than eating into RAY_DEPTH.
+#[derive(Clone, Copy, Default)]
+pub struct PathDepth {
+    pub bounces: u32,
+    pub transmissions: u32,
+    // false while the path from the camera has only met mirrors and smooth glass
+    pub diffused: bool,
+}
+
+impl PathDepth {
+    fn bounce(self) -> PathDepth {
+        PathDepth {
+            bounces: self.bounces + 1,
+            ..self
+        }
+    }
+
+    fn diffuse(self) -> PathDepth {
+        PathDepth {
+            diffused: true,
+            ..self.bounce()
+        }
+    }
+
+    fn vertex(self) -> u32 {
+        self.bounces + self.transmissions
+    }
+
+    fn transmit(self) -> PathDepth {
+        PathDepth {
+            transmissions: self.transmissions + 1,
+            ..self
+        }
+    }
+}
+
+// The event that sent a ray on, None for camera rays.
+#[derive(Clone, Copy)]
+pub struct Scattering<'a> {
+    // None when a medium scattered the ray
+    pub primitive: Option<&'a Primitive>,
+    // multiple importance sampling weight of the emission and background the ray
+    // finds, below 1 only after a diffuse bounce, whose light sample competes for them
+    pub emission_weight: f64,
+}
+
+impl<'a> Scattering<'a> {
+    // A delta event: a mirror, smooth glass, the clearcoat or a flake. No light sample
+    // can produce its direction, so the emission the ray finds is not shared with one
+    // and keeps its full weight.
+    fn specular(primitive: &'a Primitive) -> Scattering<'a> {
+        Scattering {
+            primitive: Some(primitive),
+            emission_weight: 1.0,
+        }
+    }
+}
+
+// Per-path mutable state threaded through the integrator.
+pub struct PathContext {
+    pub rng: Box<dyn RngCore>,
+    pub sampler: Sampler,
+    // when set, every traced ray is appended as a (start, end) segment
+    pub segments: Option<Vec<[Vector3<f64>; 2]>>,
+    // color channel the rest of the path is restricted to by a dispersive dielectric
+    pub channel: Option<usize>,
+    // product of the weights along the path so far, which Russian roulette is based on
+    pub throughput: Vector3<f64>,
+    // media of the dielectrics the path is inside, innermost last, above the fog
+    pub media: Vec<Option<Arc<dyn Medium>>>,
+    // part of the light the path keeps, all of it when None
+    pub pass: Option<LightPass>,
+}
+
+// Split of the light for compositing by the bounces it took to reach the camera.
+// Reflections off metal and glass count as bounces, refractions don't, like for
+// RAY_DEPTH.
+#[derive(Clone, Copy)]
+pub enum LightPass {
+    // emission the camera sees and light reaching the first surface straight from
+    // its source
+    Direct,
+    // everything else, so that the two passes add up to the image
+    Indirect,
+}
+
+// Whether light that took `bounces` bounces is kept by the path's pass.
+fn in_pass(path: &PathContext, bounces: u32) -> bool {
+    match path.pass {
+        None => true,
+        Some(LightPass::Direct) => bounces <= 1,
+        Some(LightPass::Indirect) => bounces > 1,
+    }
+}
+
+// The medium the ray currently travels through, None in vacuum.
+fn current_medium(path: &PathContext) -> Option<Arc<dyn Medium>> {
+    path.media.last().cloned().flatten()
+}
+
+fn luminance(color: &Vector3<f64>) -> f64 {
+    0.2126 * color.x + 0.7152 * color.y + 0.0722 * color.z
+}
+
+// Bounds of the roulette survival probability. The floor keeps survivors of dark
+// surfaces from being scaled up into fireflies, the cap lets bright paths end too.
+const MIN_SURVIVAL: f64 = 0.05;
+const MAX_SURVIVAL: f64 = 0.95;
+
+// Traces a ray scattered with the given weight and returns its weighted radiance.
+// Past ROULETTE_DEPTH the ray only survives with a probability given by the luminance
+// of the path throughput times the weight, which carries the albedo of the surface, so
+// paths off dark surfaces end early. Survivors are scaled up by that probability so
+// that the estimate stays unbiased. With CLAMP the weighted radiance is scaled down so
+// that no component exceeds it, which cuts fireflies at the cost of some energy.
+#[allow(clippy::too_many_arguments)]
+fn trace_scattered(
+    scene: &Scene,
+    path: &mut PathContext,
+    global_distr: &GlobalDistr,
+    ray: &Ray,
+    depth: PathDepth,
+    scattering: Scattering,
+    weight: Vector3<f64>,
+) -> Vector3<f64> {
+    let parent_throughput = path.throughput;
+    let mut survival = 1.0;
+    if scene
+        .roulette_depth
+        .is_some_and(|roulette_depth| depth.vertex() >= roulette_depth)
+    {
+        survival =
+            luminance(&parent_throughput.component_mul(&weight)).clamp(MIN_SURVIVAL, MAX_SURVIVAL);
+        if path.sampler.get_1d(
+            path.rng.as_mut(),
+            Dimension::RussianRoulette(depth.vertex()),
+        ) >= survival
+        {
+            return BLACK;
+        }
+    }
+    path.throughput = parent_throughput.component_mul(&weight) / survival;
+    let radiance = get_ray_color(scene, path, global_distr, ray, depth, Some(scattering));
+    path.throughput = parent_throughput;
+    let radiance = radiance.component_mul(&weight) / survival;
+    match scene.clamp {
+        Some(clamp) if radiance.max() > clamp => radiance * (clamp / radiance.max()),
+        _ => radiance,
+    }
+}
+
+pub fn get_ray_color(
+    scene: &Scene,
+    path: &mut PathContext,
+    global_distr: &GlobalDistr,
+    ray: &Ray,
+    depth: PathDepth,
+    scattering: Option<Scattering>,
+) -> Vector3<f64> {
+    let transparent_depth = scene.transparent_depth.unwrap_or(scene.ray_depth);
+    if depth.bounces >= scene.ray_depth || depth.transmissions >= transparent_depth {
+        return BLACK;
+    }
+
+    let emission_weight = scattering.map_or(1.0, |scattering| scattering.emission_weight);
+    let hit = intersect_scene(ray, scene, None);
+    // the medium only fills the space up to the next surface, rays leaving the scene
+    // leave it too
+    let medium_point = match (current_medium(path), &hit) {
+        (Some(medium), Some((intersection, _))) => {
+            let direction = ray.direction.normalize();
+            let u = path
+                .sampler
+                .get_1d(path.rng.as_mut(), Dimension::MediumDistance(depth.vertex()));
+            medium
+                .sample_interaction(
+                    &ray.point,
+                    &direction,
+                    intersection.ts[0] * ray.direction.norm(),
+                    u,
+                    path.rng.as_mut(),
+                )
+                .map(|(distance, weight)| (ray.point + direction * distance, medium, weight))
+        }
+        _ => None,
+    };
+    if let Some(segments) = &mut path.segments {
+        let end = match (&medium_point, &hit) {
+            (Some((point, _, _)), _) => *point,
+            (None, Some((intersection, _))) => ray.point + ray.direction * intersection.ts[0],
+            (None, None) => ray.point + ray.direction.normalize() * MISSED_RAY_LENGTH,
+        };
+        segments.push([ray.point, end]);
+    }
+    if let Some((point, medium, weight)) = medium_point {
+        return get_medium_color(
+            scene,
+            path,
+            global_distr,
+            ray,
+            point,
+            medium.as_ref(),
+            weight,
+            depth,
+        );
+    }
+
+    hit.map(|(intersection, primitive)| {
+        let intersection_point = ray.point + ray.direction * intersection.ts[0];
+        // stands in for a real object of the backplate, so it still shadows and bounces
+        // light but is left for the plate wherever the camera sees it. Like emission it
+        // follows the base material, so that the coverage AOV agrees. Seen
+        // through mirrors and smooth glass it is blanked just the same.
+        if !depth.diffused && matches!(primitive.material, scene::Material::HOLDOUT) {
+            return BLACK;
+        }
+        let (material, color) = pick_material(path, primitive, ray, &intersection, depth.vertex());
+        let emission = if shows_emission(primitive) && in_pass(path, depth.bounces) {
+            get_emission(scene, primitive, &intersection_point, scattering) * emission_weight
+        } else {
+            BLACK
+        };
+        emission
+            + match material {
+                scene::Material::DIFFUSE | scene::Material::HOLDOUT => get_diffuse_color(
+                    scene,
+                    path,
+                    global_distr,
+                    ray,
+                    &intersection,
+                    primitive,
+                    color,
+                    depth,
+                ),
+                scene::Material::METALLIC => {
+                    let reflected_direction = reflect(&ray.direction, &intersection.normals[0]);
+                    if leaves_surface(&intersection, &reflected_direction) {
+                        trace_scattered(
+                            scene,
+                            path,
+                            global_distr,
+                            &build_shifted_ray(intersection_point, reflected_direction),
+                            depth.bounce(),
+                            Scattering::specular(primitive),
+                            color,
+                        )
                     } else {
-                        primitive.emission
-                            + (primitive.color / PI).component_mul(&get_ray_color(
-                                scene,
-                                rng,
-                                global_distr,
-                                &build_shifted_ray(intersection_point, w),
-                                depth + 1,
-                            )) * (w.dot(&intersection.normals[0]))
-                                / pdf
+                        BLACK
                     }
                 }
-                scene::Material::METALLIC => {
-                    let reflected_direction = ray.direction
-                        - 2.0
-                            * intersection.normals[0].dot(&ray.direction)
-                            * intersection.normals[0];
-                    primitive.color.component_mul(&get_ray_color(
-                        scene,
-                        rng,
-                        global_distr,
-                        &build_shifted_ray(intersection_point, reflected_direction),
-                        depth + 1,
-                    ))
-                }
-                scene::Material::DIELECTRIC { ior } => {
+                scene::Material::DIELECTRIC {
+                    ior,
+                    abbe,
+                    absorption,
+                } => {
+                    // a dispersive dielectric restricts the rest of the path to one color
+                    // channel, picked uniformly and weighted by 3 to stay unbiased
+                    let picked_channel = match (abbe, path.channel) {
+                        (Some(_), None) => {
+                            let pick = path
+                                .sampler
+                                .get_1d(path.rng.as_mut(), Dimension::ChannelPick(depth.vertex()));
+                            Some(((pick * 3.0) as usize).min(2))
+                        }
+                        _ => None,
+                    };
+                    if picked_channel.is_some() {
+                        path.channel = picked_channel;
+                    }
+                    let ior = match (abbe, path.channel) {
+                        (Some(abbe), Some(channel)) => dispersed_ior(*ior, *abbe, channel),
+                        _ => *ior,
+                    };
                     let (nu_1, nu_2): (f64, f64) = if intersection.outside {
-                        (1.0, *ior)
+                        (1.0, ior)
                     } else {
-                        (*ior, 1.0)
+                        (ior, 1.0)
                     };
-                    let normalized_ray_direction = ray.direction.normalize();
-                    // let cos_tetta_1 = -intersection.normal.dot(&normalized_ray_direction);
-                    let cos_tetta_1 = -intersection.normals[0].dot(&normalized_ray_direction);
-                    let sin_tetta_2 = nu_1 / nu_2 * (1.0 - cos_tetta_1.powi(2)).sqrt();
-                    let reflected_dir =
-                        normalized_ray_direction + 2.0 * cos_tetta_1 * intersection.normals[0];
-                    let r_0 = ((nu_1 - nu_2) / (nu_1 + nu_2)).powi(2);
-                    let reflected_coef = r_0 + (1.0 - r_0) * (1.0 - cos_tetta_1).powi(5);
-                    let reflected_color = get_ray_color(
-                        scene,
-                        rng,
-                        global_distr,
-                        &build_shifted_ray(intersection_point, reflected_dir),
-                        depth + 1,
+                    let lobe = sample_dielectric(
+                        &ray.direction.normalize(),
+                        &intersection.normals[0],
+                        nu_1,
+                        nu_2,
+                        || {
+                            path.sampler
+                                .get_1d(path.rng.as_mut(), Dimension::Lobe(depth.vertex()))
+                        },
                     );
-                    if sin_tetta_2 <= 1.0 && rand::thread_rng().gen::<f64>() > reflected_coef {
-                        let cos_tetta_2 = (1.0 - sin_tetta_2.powi(2)).sqrt();
-                        let refracted_dir = nu_1 / nu_2 * normalized_ray_direction
-                            + (nu_1 / nu_2 * cos_tetta_1 - cos_tetta_2) * intersection.normals[0];
-                        let refracted_color = get_ray_color(
+                    let color = match lobe {
+                        DielectricLobe::Refracted(refracted_dir) => {
+                            // entering a dielectric pushes its medium, leaving pops the
+                            // innermost one, the fog at the bottom is never left
+                            let left_medium = if intersection.outside {
+                                path.media.push(primitive.medium.clone());
+                                None
+                            } else {
+                                (path.media.len() > 1).then(|| path.media.pop()).flatten()
+                            };
+                            let color = trace_scattered(
+                                scene,
+                                path,
+                                global_distr,
+                                &build_shifted_ray(intersection_point, refracted_dir),
+                                depth.transmit(),
+                                Scattering::specular(primitive),
+                                if intersection.outside {
+                                    color
+                                } else {
+                                    Vector3::repeat(1.0)
+                                },
+                            );
+                            if intersection.outside {
+                                path.media.pop();
+                            } else if let Some(medium) = left_medium {
+                                path.media.push(medium);
+                            }
+                            color
+                        }
+                        DielectricLobe::Reflected(reflected_dir) => trace_scattered(
                             scene,
-                            rng,
+                            path,
                             global_distr,
-                            &build_shifted_ray(intersection_point, refracted_dir),
-                            depth + 1,
-                        );
-                        if intersection.outside {
-                            refracted_color.component_mul(&primitive.color)
+                            &build_shifted_ray(intersection_point, reflected_dir),
+                            depth.bounce(),
+                            Scattering::specular(primitive),
+                            Vector3::repeat(1.0),
+                        ),
+                    };
+                    let color = match picked_channel {
+                        Some(channel) => {
+                            path.channel = None;
+                            color.component_mul(&Vector3::ith(channel, 3.0))
+                        }
+                        None => color,
+                    };
+                    // Beer-Lambert absorption along the inside segment ending at this hit
+                    if intersection.outside {
+                        color
+                    } else {
+                        let length = intersection.ts[0] * ray.direction.norm();
+                        color.component_mul(&(-absorption * length).map(f64::exp))
+                    }
+                }
+                scene::Material::CARPAINT {
+                    flake_size,
+                    flake_density,
+                    flake_color,
+                } => {
+                    let cos_tetta = -intersection.normals[0].dot(&ray.direction.normalize());
+                    let local_point = primitive
+                        .rotation
+                        .conjugate()
+                        .transform_vector(&(intersection_point - primitive.position));
+                    let lobe = sample_car_paint(
+                        ray,
+                        &intersection,
+                        &local_point,
+                        *flake_size,
+                        *flake_density,
+                        path.sampler
+                            .get_1d(path.rng.as_mut(), Dimension::Lobe(depth.vertex())),
+                    );
+
+                    if let CarPaintLobe::Clearcoat(clearcoat_dir) = lobe {
+                        if leaves_surface(&intersection, &clearcoat_dir) {
+                            trace_scattered(
+                                scene,
+                                path,
+                                global_distr,
+                                &build_shifted_ray(intersection_point, clearcoat_dir),
+                                depth.bounce(),
+                                Scattering::specular(primitive),
+                                Vector3::repeat(1.0),
+                            )
                         } else {
-                            refracted_color
+                            BLACK
                         }
+                    } else if let CarPaintLobe::Flake(flake_reflected_dir) = lobe {
+                        // pearlescent shift from the flake color at normal incidence
+                        // towards the base color at grazing angles
+                        let flake_tint = flake_color.lerp(&color, 1.0 - cos_tetta);
+                        trace_scattered(
+                            scene,
+                            path,
+                            global_distr,
+                            &build_shifted_ray(intersection_point, flake_reflected_dir),
+                            depth.bounce(),
+                            Scattering::specular(primitive),
+                            flake_tint,
+                        )
                     } else {
-                        reflected_color
+                        get_diffuse_color(
+                            scene,
+                            path,
+                            global_distr,
+                            ray,
+                            &intersection,
+                            primitive,
+                            color,
+                            depth,
+                        )
                     }
                 }
             }
+    })
+    .unwrap_or_else(|| {
+        let background = background_radiance(scene, &ray.direction);
+        if !in_pass(path, depth.bounces) {
+            return BLACK;
+        }
+        // ambient light only shows in what surfaces reflect
+        match scattering {
+            Some(_) => (background + scene.ambient_light) * emission_weight,
+            None => background,
+        }
+    })
+}
+
+fn background_radiance(scene: &Scene, direction: &Vector3<f64>) -> Vector3<f64> {
+    scene
+        .environment_map
+        .as_ref()
+        .map_or(scene.background_color, |map| map.radiance(direction))
+}
+
+// What diffuse bounces sample: the BRDF's own cosine lobe and, combined with it by
+// multiple importance sampling, everything that emits light. Scenes lit only by point
+// and directed lights, which get shadow rays of their own, have no lights here.
+pub struct GlobalDistr {
+    pub bsdf: CosineWeightedDistr,
+    pub lights: Option<MixDistr>,
+}
+
+pub fn build_global_distr(scene: &Scene) -> GlobalDistr {
+    // planes can't be sampled, their emission is only found by BRDF samples
+    let mut lights: Vec<Box<dyn DistributionTooling>> = scene
+        .primitives
+        .iter()
+        .filter(|primitive| {
+            primitive.emission != BLACK
+                && shows_emission(primitive)
+                && !matches!(primitive.shape, Plane { normal: _ })
         })
-        .unwrap_or(scene.background_color)
-}
-
-pub fn render_scene(scene: &Scene) -> Vec<u8> {
-    let global_distr = &MixDistr {
-        distribs: vec![
-            Box::new(CosineWeightedDistr {}),
-            Box::new(MixDistr {
-                distribs: scene
-                    .primitives
-                    .iter()
-                    .filter(|primitive| match primitive.shape {
-                        Plane { normal: _ } => false,
-                        _ => true,
-                    })
-                    .map(|primitive| {
-                        Box::new(LightSourceDistr {
-                            primitive: primitive.clone(),
-                        }) as Box<dyn DistributionTooling>
-                    })
-                    .collect(),
-            }),
-        ],
-    };
-
-    let mut rng = rand::thread_rng();
-    let mut result = Vec::<u8>::new();
-    for row in 0..scene.height {
-        for column in 0..scene.width {
-            let x_local = column as f64 + 0.5;
-            let y_local = row as f64 + 0.5;
-            let x_global =
-                (2.0 * x_local / scene.width as f64 - 1.0) * (scene.camera.fov_x / 2.0).tan();
-            let y_global = (2.0 * y_local / scene.height as f64 - 1.0)
-                * (scene.camera.fov_y / 2.0).tan()
-                * (-1.0); // to reverse y asix
-            let ray = Ray {
-                point: scene.camera.position,
-                direction: x_global * scene.camera.right_axis
-                    + y_global * scene.camera.up_axis
-                    + scene.camera.forward_axis,
+        .map(|primitive| {
+            Box::new(LightSourceDistr {
+                primitive: primitive.clone(),
+            }) as Box<dyn DistributionTooling>
+        })
+        .collect();
+    // the constant part of the light coming from outside the scene
+    let constant_light = match &scene.environment_map {
+        Some(map) => {
+            if map.is_emitting() {
+                lights.push(Box::new(EnvironmentDistr { map: map.clone() }));
+            }
+            scene.ambient_light
+        }
+        None => scene.background_color + scene.ambient_light,
+    };
+    if constant_light != BLACK {
+        lights.push(Box::new(BackgroundDistr {}));
+    }
+    GlobalDistr {
+        bsdf: CosineWeightedDistr {},
+        lights: (!lights.is_empty()).then_some(MixDistr { distribs: lights }),
+    }
+}
+
+// Ray through a point of the image given in pixels, None where the projection sees
+// nothing.
+pub(crate) fn build_camera_ray(scene: &Scene, x_local: f64, y_local: f64) -> Option<Ray> {
+    let camera = &scene.camera;
+    // from -1 to 1 across the image
+    let x_image = 2.0 * x_local / scene.width as f64 - 1.0;
+    let y_image = -(2.0 * y_local / scene.height as f64 - 1.0); // to reverse y asix
+    let aspect = scene.height as f64 / scene.width as f64;
+    let right = camera.right_axis.normalize();
+    let up = camera.up_axis.normalize();
+    let forward = camera.forward_axis.normalize();
+    let direction = match camera.camera_type {
+        scene::CameraType::Perspective => {
+            x_image * (camera.fov_x / 2.0).tan() * camera.right_axis
+                + y_image * (camera.fov_y / 2.0).tan() * camera.up_axis
+                + camera.forward_axis
+        }
+        scene::CameraType::Orthographic { width } => {
+            return Some(Ray {
+                point: camera.position + width / 2.0 * (x_image * right + y_image * aspect * up),
+                direction: forward,
+            });
+        }
+        scene::CameraType::Fisheye => {
+            let (x, y) = (x_image, y_image * aspect);
+            let radius = (x * x + y * y).sqrt();
+            let angle = radius * camera.fov_x / 2.0;
+            if angle > PI {
+                return None;
+            }
+            let side = if radius > 0.0 {
+                (x * right + y * up) / radius
+            } else {
+                Vector3::zeros()
             };
+            angle.cos() * forward + angle.sin() * side
+        }
+        scene::CameraType::Equirectangular => {
+            let phi = PI * x_image;
+            let theta = PI * y_local / scene.height as f64;
+            theta.sin() * phi.sin() * right + theta.cos() * up + theta.sin() * phi.cos() * forward
+        }
+    };
+    Some(Ray {
+        point: camera.position,
+        direction,
+    })
+}
+
+// Camera ray through the pixel, offset from its center by the pixel filter, along with
+// the filter weight of the sample. With an aperture it starts on the lens disk instead,
+// or on the opening of the aperture mask, aimed at the point of the focus plane the
+// pinhole ray would reach. Fisheye and
+// equirectangular cameras are always pinholes.
+fn sample_camera_ray(
+    scene: &Scene,
+    path: &mut PathContext,
+    filter: &FilterSampler,
+    column: u32,
+    row: u32,
+) -> Option<(Ray, f64)> {
+    let (offset_x, weight_x) =
+        filter.sample(path.sampler.get_1d(path.rng.as_mut(), Dimension::PixelX));
+    let (offset_y, weight_y) =
+        filter.sample(path.sampler.get_1d(path.rng.as_mut(), Dimension::PixelY));
+    let weight = weight_x * weight_y;
+    let ray = build_camera_ray(
+        scene,
+        column as f64 + 0.5 + offset_x,
+        row as f64 + 0.5 + offset_y,
+    )?;
+    let camera = &scene.camera;
+    let wide_camera = matches!(
+        camera.camera_type,
+        scene::CameraType::Fisheye | scene::CameraType::Equirectangular
+    );
+    if camera.aperture <= 0.0 || wide_camera {
+        return Some((ray, weight));
+    }
+
+    let forward = camera.forward_axis.normalize();
+    let focus_point =
+        ray.point + ray.direction * (camera.focus_distance / ray.direction.dot(&forward));
+    let u = [
+        path.sampler.get_1d(path.rng.as_mut(), Dimension::LensU),
+        path.sampler.get_1d(path.rng.as_mut(), Dimension::LensV),
+    ];
+    let lens_offset = match &camera.aperture_mask {
+        Some(mask) => camera.aperture * mask.sample(u),
+        None => {
+            let radius = camera.aperture / 2.0 * u[0].sqrt();
+            let phi = 2.0 * PI * u[1];
+            radius * Vector2::new(phi.cos(), phi.sin())
+        }
+    };
+    let lens_point = ray.point
+        + lens_offset.x * camera.right_axis.normalize()
+        + lens_offset.y * camera.up_axis.normalize();
+    Some((
+        Ray {
+            point: lens_point,
+            direction: focus_point - lens_point,
+        },
+        weight,
+    ))
+}
+
+// Moves the sampler to a pixel. With a seed the RNG restarts from one derived from the
+// pixel, so the pixel comes out the same whichever thread and tile renders it.
+pub fn start_pixel(scene: &Scene, path: &mut PathContext, pixel: u64) {
+    path.sampler.start_pixel(pixel);
+    if let Some(seed) = scene.seed {
+        path.rng = create_rng(scene.rng_backend, Some(pixel_seed(seed, pixel)));
+    }
+}
+
+// Samples a pixel takes before its variance estimate is trusted.
+const ADAPTIVE_MIN_SAMPLES: u32 = 16;
+
+// Running mean and variance of the luminance of a pixel's samples (Welford).
+#[derive(Default)]
+struct PixelStatistics {
+    count: u32,
+    mean: f64,
+    squared_deviations: f64,
+}
+
+impl PixelStatistics {
+    fn add(&mut self, color: &Vector3<f64>) {
+        let luminance = luminance(color);
+        self.count += 1;
+        let delta = luminance - self.mean;
+        self.mean += delta / self.count as f64;
+        self.squared_deviations += delta * (luminance - self.mean);
+    }
+
+    fn standard_error(&self) -> f64 {
+        let variance = self.squared_deviations / (self.count - 1) as f64;
+        (variance / self.count as f64).sqrt()
+    }
+
+    // Whether the standard error of the mean is below `threshold` times the mean. Pixels
+    // that saw the same value every time, such as the background, converge at once.
+    fn has_converged(&self, threshold: f64) -> bool {
+        if self.count < ADAPTIVE_MIN_SAMPLES {
+            return false;
+        }
+        self.standard_error() <= threshold * self.mean.abs()
+    }
+
+    // None for black pixels and pixels with a single sample.
+    fn relative_error(&self) -> Option<f64> {
+        (self.count > 1 && self.mean != 0.0).then(|| self.standard_error() / self.mean.abs())
+    }
+}
+
+// What a render did, summed over its tiles.
+#[derive(Clone, Copy, Default)]
+pub struct RenderStatistics {
+    pub pixels: u64,
+    // camera samples, fewer than SAMPLES per pixel where pixels converged early
+    pub samples: u64,
+    // every ray intersected with the scene, shadow rays and light samples included
+    pub rays: u64,
+    // pixels the adaptive threshold stopped before SAMPLES
+    pub converged_pixels: u64,
+    // standard error of the mean luminance over the mean, summed over the pixels
+    // that have one
+    pub relative_error_sum: f64,
+    pub relative_error_pixels: u64,
+}
+
+impl RenderStatistics {
+    fn add(&mut self, other: &RenderStatistics) {
+        self.pixels += other.pixels;
+        self.samples += other.samples;
+        self.rays += other.rays;
+        self.converged_pixels += other.converged_pixels;
+        self.relative_error_sum += other.relative_error_sum;
+        self.relative_error_pixels += other.relative_error_pixels;
+    }
+
+    pub fn mean_relative_error(&self) -> Option<f64> {
+        (self.relative_error_pixels > 0)
+            .then(|| self.relative_error_sum / self.relative_error_pixels as f64)
+    }
+}
+
+// Mean of the group of samples whose mean has the median luminance, or of the two
+// middle groups for an even number of them. A few extremely bright samples only raise
+// the means of their own groups, so they are rejected as long as they are in fewer
+// than half of the groups.
+fn median_of_means(groups: &[(Vector3<f64>, u32)]) -> Vector3<f64> {
+    let mut means: Vec<Vector3<f64>> = groups
+        .iter()
+        .filter(|(_, count)| *count > 0)
+        .map(|(sum, count)| sum / *count as f64)
+        .collect();
+    if means.is_empty() {
+        return BLACK;
+    }
+    means.sort_by(|x, y| luminance(x).total_cmp(&luminance(y)));
+    let middle = means.len() / 2;
+    if means.len() % 2 == 1 {
+        means[middle]
+    } else {
+        (means[middle - 1] + means[middle]) / 2.0
+    }
+}
+
+// Side of the square tiles that are rendered in parallel.
+const TILE_SIZE: u32 = 16;
+
+// First-hit normal and albedo of a pixel, averaged over the samples that hit, for the
+// denoiser.
+#[derive(Clone, Copy)]
+pub struct DenoiseGuide {
+    pub normal: Vector3<f64>,
+    pub albedo: Vector3<f64>,
+}
+
+// What a pixel has gathered so far, kept between the rounds of a budgeted render.
+struct PixelState {
+    // samples are dealt out to the groups in turn
+    group_sums: Vec<(Vector3<f64>, u32)>,
+    statistics: PixelStatistics,
+    guide_hits: u32,
+    normal_sum: Vector3<f64>,
+    albedo_sum: Vector3<f64>,
+}
+
+impl PixelState {
+    fn new(scene: &Scene) -> PixelState {
+        PixelState {
+            group_sums: vec![(BLACK, 0); scene.median_of_means.unwrap_or(1) as usize],
+            statistics: PixelStatistics::default(),
+            guide_hits: 0,
+            normal_sum: BLACK,
+            albedo_sum: BLACK,
+        }
+    }
+
+    // Whether the pixel took SAMPLES or met the adaptive threshold.
+    fn is_done(&self, scene: &Scene) -> bool {
+        self.statistics.count >= scene.samples
+            || scene
+                .adaptive_threshold
+                .is_some_and(|threshold| self.statistics.has_converged(threshold))
+    }
+}
+
+// Adds samples to a pixel until it has `until` of them or is done. With a seed the RNG
+// of a pixel that already has samples restarts from one derived from their number too,
+// so that the new samples do not repeat the first ones.
+#[allow(clippy::too_many_arguments)]
+fn sample_pixel(
+    scene: &Scene,
+    path: &mut PathContext,
+    global_distr: &GlobalDistr,
+    filter: &FilterSampler,
+    guides: bool,
+    column: u32,
+    row: u32,
+    state: &mut PixelState,
+    until: u32,
+) {
+    let pixel = row as u64 * scene.width as u64 + column as u64;
+    let first_sample = state.statistics.count;
+    start_pixel(scene, path, pixel);
+    if let (Some(seed), true) = (scene.seed, first_sample > 0) {
+        path.rng = create_rng(
+            scene.rng_backend,
+            Some(pixel_seed(pixel_seed(seed, pixel), first_sample as u64)),
+        );
+    }
+    for sample in first_sample..until.min(scene.samples) {
+        if state.is_done(scene) {
+            break;
+        }
+        path.sampler.start_sample(sample);
+        let color = match sample_camera_ray(scene, path, filter, column, row) {
+            Some((ray, weight)) => {
+                if let Some(hit) = guides.then(|| first_hit(scene, &ray)).flatten() {
+                    state.guide_hits += 1;
+                    state.normal_sum += hit.normal;
+                    state.albedo_sum += hit.albedo;
+                }
+                get_ray_color(scene, path, global_distr, &ray, PathDepth::default(), None) * weight
+            }
+            None => BLACK,
+        };
+        let groups = state.group_sums.len();
+        let group = &mut state.group_sums[sample as usize % groups];
+        group.0 += color;
+        group.1 += 1;
+        state.statistics.add(&color);
+    }
+}
+
+// The exposed value and denoise guide of a pixel that took all the samples it gets,
+// with its counts added to the tile's statistics.
+fn finish_pixel(
+    scene: &Scene,
+    path: &mut PathContext,
+    pass: Option<LightPass>,
+    state: &PixelState,
+    tile_statistics: &mut RenderStatistics,
+) -> (Vector3<f64>, DenoiseGuide) {
+    let statistics = &state.statistics;
+    tile_statistics.pixels += 1;
+    tile_statistics.samples += statistics.count as u64;
+    // a time budget also stops pixels before SAMPLES
+    if statistics.count < scene.samples
+        && scene
+            .adaptive_threshold
+            .is_some_and(|threshold| statistics.has_converged(threshold))
+    {
+        tile_statistics.converged_pixels += 1;
+    }
+    if let Some(error) = statistics.relative_error() {
+        tile_statistics.relative_error_sum += error;
+        tile_statistics.relative_error_pixels += 1;
+    }
+    let pixel_color = match scene.median_of_means {
+        Some(_) => median_of_means(&state.group_sums),
+        None => state.group_sums[0].0 / statistics.count.max(1) as f64,
+    };
+    let mut exposed_color = pixel_color * scene.camera.exposure;
+    // sensor noise would keep the passes from adding up to the image
+    if let (Some(sensor), None) = (&scene.camera.sensor, pass) {
+        exposed_color = simulate_sensor(&exposed_color, sensor, path.rng.as_mut());
+    }
+    let hits = state.guide_hits.max(1) as f64;
+    let guide = DenoiseGuide {
+        normal: state.normal_sum / hits,
+        albedo: state.albedo_sum / hits,
+    };
+    (exposed_color, guide)
+}
+
+fn create_path(scene: &Scene, pass: Option<LightPass>) -> PathContext {
+    PathContext {
+        rng: create_rng(scene.rng_backend, scene.seed),
+        sampler: Sampler::new(scene.samples, scene.sampler_type),
+        segments: None,
+        channel: None,
+        throughput: Vector3::repeat(1.0),
+        media: vec![scene.fog.clone()],
+        pass,
+    }
+}
+
+// Pixel values of the tile row by row, each tile with its own RNG so threads never
+// share one. A tile runs on one thread from start to end, so the rays that thread
+// traced in between are the tile's. Denoise guides come from the same camera rays and
+// are only collected when asked for.
+fn render_tile(
+    scene: &Scene,
+    global_distr: &GlobalDistr,
+    filter: &FilterSampler,
+    pass: Option<LightPass>,
+    guides: bool,
+    columns: Range<u32>,
+    rows: Range<u32>,
+) -> (Vec<Vector3<f64>>, Vec<DenoiseGuide>, RenderStatistics) {
+    let rays_before = traced_rays();
+    let mut tile_statistics = RenderStatistics::default();
+    let mut path = create_path(scene, pass);
+    let mut tile_values = Vec::<Vector3<f64>>::with_capacity(columns.len() * rows.len());
+    let mut tile_guides = Vec::<DenoiseGuide>::new();
+    for row in rows {
+        for column in columns.clone() {
+            let mut state = PixelState::new(scene);
+            sample_pixel(
+                scene,
+                &mut path,
+                global_distr,
+                filter,
+                guides,
+                column,
+                row,
+                &mut state,
+                scene.samples,
+            );
+            let (value, guide) = finish_pixel(scene, &mut path, pass, &state, &mut tile_statistics);
+            tile_values.push(value);
+            if guides {
+                tile_guides.push(guide);
+            }
+        }
+    }
+    tile_statistics.rays = traced_rays() - rays_before;
+    (tile_values, tile_guides, tile_statistics)
+}
+
+// A tile of a budgeted render, with the state of its pixels row by row.
+struct BudgetTile {
+    columns: Range<u32>,
+    rows: Range<u32>,
+    pixels: Vec<PixelState>,
+    rays: u64,
+}
 
-            let sum_pixel_color = (0..scene.samples)
-                .map(|_| get_ray_color(scene, &mut rng, global_distr, &ray, 0))
-                .sum::<Vector3<f64>>()
-                / scene.samples as f64;
+impl BudgetTile {
+    // Sum of the relative errors of the pixels that still take samples, None once they
+    // are all done. Black pixels have none and only get samples after the others.
+    fn error(&self, scene: &Scene) -> Option<f64> {
+        let mut open = self
+            .pixels
+            .iter()
+            .filter(|pixel| !pixel.is_done(scene))
+            .peekable();
+        open.peek()?;
+        Some(
+            open.filter_map(|pixel| pixel.statistics.relative_error())
+                .sum(),
+        )
+    }
 
-            result.extend(proportion_to_value(sum_pixel_color))
+    // One more round of at most ADAPTIVE_MIN_SAMPLES samples for every pixel.
+    fn sample(
+        &mut self,
+        scene: &Scene,
+        global_distr: &GlobalDistr,
+        filter: &FilterSampler,
+        pass: Option<LightPass>,
+        guides: bool,
+    ) {
+        let rays_before = traced_rays();
+        let mut path = create_path(scene, pass);
+        let width = self.columns.len();
+        for (index, state) in self.pixels.iter_mut().enumerate() {
+            let column = self.columns.start + (index % width) as u32;
+            let row = self.rows.start + (index / width) as u32;
+            let until = state.statistics.count + ADAPTIVE_MIN_SAMPLES;
+            sample_pixel(
+                scene,
+                &mut path,
+                global_distr,
+                filter,
+                guides,
+                column,
+                row,
+                state,
+                until,
+            );
+        }
+        self.rays += traced_rays() - rays_before;
+    }
+}
+
+// Renders in rounds for as long as the scene's time budget allows: every tile takes a
+// round first, then the tiles with the largest error, as many as there are threads,
+// take one more at a time. The image is held whole and handed out row by row at the
+// end.
+fn render_budgeted(
+    scene: &Scene,
+    global_distr: &GlobalDistr,
+    filter: &FilterSampler,
+    pass: Option<LightPass>,
+    guides: bool,
+    budget: f64,
+    mut emit_row: impl FnMut(&[Vector3<f64>], &[DenoiseGuide]),
+) -> RenderStatistics {
+    let start = Instant::now();
+    let mut tiles: Vec<BudgetTile> = (0..scene.height)
+        .step_by(TILE_SIZE as usize)
+        .flat_map(|row_start| {
+            (0..scene.width)
+                .step_by(TILE_SIZE as usize)
+                .map(move |column_start| {
+                    let columns = column_start..(column_start + TILE_SIZE).min(scene.width);
+                    let rows = row_start..(row_start + TILE_SIZE).min(scene.height);
+                    BudgetTile {
+                        pixels: (0..columns.len() * rows.len())
+                            .map(|_| PixelState::new(scene))
+                            .collect(),
+                        columns,
+                        rows,
+                        rays: 0,
+                    }
+                })
+        })
+        .collect();
+    let mut round: Vec<&mut BudgetTile> = tiles.iter_mut().collect();
+    while !round.is_empty() {
+        round
+            .par_iter_mut()
+            .for_each(|tile| tile.sample(scene, global_distr, filter, pass, guides));
+        if start.elapsed().as_secs_f64() >= budget {
+            break;
+        }
+        let mut open: Vec<(f64, &mut BudgetTile)> = tiles
+            .iter_mut()
+            .filter_map(|tile| tile.error(scene).map(|error| (error, tile)))
+            .collect();
+        open.sort_by(|(error, _), (other, _)| other.total_cmp(error));
+        round = open
+            .into_iter()
+            .take(rayon::current_num_threads())
+            .map(|(_, tile)| tile)
+            .collect();
+    }
+
+    let mut statistics = RenderStatistics::default();
+    let mut path = create_path(scene, pass);
+    let mut values = vec![BLACK; scene.width as usize * scene.height as usize];
+    let mut pixel_guides = Vec::<DenoiseGuide>::new();
+    if guides {
+        pixel_guides.resize(
+            values.len(),
+            DenoiseGuide {
+                normal: BLACK,
+                albedo: BLACK,
+            },
+        );
+    }
+    for tile in &tiles {
+        let width = tile.columns.len();
+        for (index, state) in tile.pixels.iter().enumerate() {
+            let column = tile.columns.start as usize + index % width;
+            let row = tile.rows.start as usize + index / width;
+            let pixel = row * scene.width as usize + column;
+            let (value, guide) = finish_pixel(scene, &mut path, pass, state, &mut statistics);
+            values[pixel] = value;
+            if guides {
+                pixel_guides[pixel] = guide;
+            }
+        }
+        statistics.rays += tile.rays;
+    }
+    for (row, row_values) in values.chunks(scene.width as usize).enumerate() {
+        let row_guides = if guides {
+            &pixel_guides[row * scene.width as usize..][..scene.width as usize]
+        } else {
+            &[]
+        };
+        emit_row(row_values, row_guides);
+    }
+    statistics
+}
+
+// Tiles of one band of rows are rendered in parallel, then the rows of the band are
+// handed to `emit_row` in order, so the image never has to be held in memory as a
+// whole. Rows hold exposed linear radiance, before tone mapping.
+pub fn render_scene(scene: &Scene, mut emit_row: impl FnMut(&[Vector3<f64>])) -> RenderStatistics {
+    render_rows(scene, None, false, |row, _| emit_row(row))
+}
+
+// Like render_scene, with the denoise guides of each row next to its values.
+pub fn render_scene_with_guides(
+    scene: &Scene,
+    emit_row: impl FnMut(&[Vector3<f64>], &[DenoiseGuide]),
+) -> RenderStatistics {
+    render_rows(scene, None, true, emit_row)
+}
+
+fn render_rows(
+    scene: &Scene,
+    pass: Option<LightPass>,
+    guides: bool,
+    mut emit_row: impl FnMut(&[Vector3<f64>], &[DenoiseGuide]),
+) -> RenderStatistics {
+    let mut statistics = RenderStatistics::default();
+    let global_distr = &build_global_distr(scene);
+    let filter = &FilterSampler::new(&scene.pixel_filter);
+    if let Some(budget) = scene.time_budget {
+        return render_budgeted(scene, global_distr, filter, pass, guides, budget, emit_row);
+    }
+
+    let mut row_values = Vec::<Vector3<f64>>::with_capacity(scene.width as usize);
+    let mut row_guides = Vec::<DenoiseGuide>::new();
+    for band_start in (0..scene.height).step_by(TILE_SIZE as usize) {
+        let rows = band_start..(band_start + TILE_SIZE).min(scene.height);
+        let tiles: Vec<_> = (0..scene.width)
+            .into_par_iter()
+            .step_by(TILE_SIZE as usize)
+            .map(|column_start| {
+                let columns = column_start..(column_start + TILE_SIZE).min(scene.width);
+                let (values, guides, tile_statistics) = render_tile(
+                    scene,
+                    global_distr,
+                    filter,
+                    pass,
+                    guides,
+                    columns.clone(),
+                    rows.clone(),
+                );
+                (columns, values, guides, tile_statistics)
+            })
+            .collect();
+        for row_in_band in 0..rows.len() {
+            row_values.clear();
+            row_guides.clear();
+            for (columns, values, guides, _) in &tiles {
+                let tile_row_size = columns.len();
+                row_values.extend(&values[row_in_band * tile_row_size..][..tile_row_size]);
+                if !guides.is_empty() {
+                    row_guides.extend(&guides[row_in_band * tile_row_size..][..tile_row_size]);
+                }
+            }
+            emit_row(&row_values, &row_guides);
+        }
+        for (_, _, _, tile_statistics) in &tiles {
+            statistics.add(tile_statistics);
+        }
+    }
+    statistics
+}
+
+// The whole tone mapped image, for callers that want a buffer rather than rows.
+pub fn render_image(scene: &Scene) -> RgbImage {
+    let mut values = Vec::with_capacity(scene.width as usize * scene.height as usize);
+    render_scene(scene, |row| values.extend_from_slice(row));
+    RgbImage::from_raw(scene.width, scene.height, tonemap(&values))
+        .expect("Rendered image has the wrong size.")
+}
+
+// Data passes are linear first-hit values, written without exposure or tone mapping.
+// Normal and albedo also guide the denoiser. The light passes are rendered like the
+// image, exposure included, and are one value per pixel whatever the resolve.
+#[derive(Clone, Copy)]
+pub enum Aov {
+    // fraction of camera samples that hit any primitive other than a holdout
+    Coverage,
+    // averaged world space normal of the first hit
+    Normal,
+    // averaged surface color of the first hit, texture included
+    Albedo,
+    // averaged emission of the first hit
+    Emission,
+    // distance to the first hit the resolve picks, infinite when it picks a miss
+    Depth,
+    // index of the primitive the resolve picks, -1 when it picks a miss
+    PrimitiveId,
+    // the part of the image's light the pass keeps
+    Light(LightPass),
+}
+
+pub fn parse_aov(name: &str) -> Option<Aov> {
+    match name {
+        "coverage" => Some(Aov::Coverage),
+        "normal" => Some(Aov::Normal),
+        "albedo" => Some(Aov::Albedo),
+        "emission" => Some(Aov::Emission),
+        "depth" => Some(Aov::Depth),
+        "id" => Some(Aov::PrimitiveId),
+        "direct" => Some(Aov::Light(LightPass::Direct)),
+        "indirect" => Some(Aov::Light(LightPass::Indirect)),
+        _ => None,
+    }
+}
+
+// How the samples of a pixel combine in the passes that can't be averaged, depth and
+// id. Coverage and normals are averaged either way.
+#[derive(Clone, Copy)]
+pub enum AovResolve {
+    // the nearest hit of any sample
+    Nearest,
+    // the primitive most samples hit, or the background when most miss, at its
+    // nearest hit; ties go to the nearer one
+    Majority,
+    // every sample on its own, each pixel becomes SAMPLES values wide
+    Samples,
+}
+
+pub fn parse_aov_resolve(name: &str) -> Option<AovResolve> {
+    match name {
+        "nearest" => Some(AovResolve::Nearest),
+        "majority" => Some(AovResolve::Majority),
+        "samples" => Some(AovResolve::Samples),
+        _ => None,
+    }
+}
+
+// First hit of the camera ray of one sample.
+struct AovHit {
+    distance: f64,
+    index: usize,
+    normal: Vector3<f64>,
+    albedo: Vector3<f64>,
+    emission: Vector3<f64>,
+    covered: bool,
+}
+
+fn first_hit(scene: &Scene, ray: &Ray) -> Option<AovHit> {
+    intersect_scene_index(ray, scene, None).map(|(intersection, index)| {
+        let primitive = &scene.primitives[index];
+        let point = ray.point + ray.direction * intersection.ts[0];
+        AovHit {
+            distance: intersection.ts[0] * ray.direction.norm(),
+            index,
+            normal: intersection.normals[0],
+            albedo: surface_color(primitive, &point),
+            emission: if shows_emission(primitive) {
+                get_emission(scene, primitive, &point, None)
+            } else {
+                BLACK
+            },
+            covered: !matches!(primitive.material, scene::Material::HOLDOUT),
+        }
+    })
+}
+
+fn nearest_hit<'a>(
+    hits: impl Iterator<Item = &'a Option<AovHit>>,
+    index: Option<usize>,
+) -> Option<&'a AovHit> {
+    hits.flatten()
+        .filter(|hit| index.is_none_or(|index| hit.index == index))
+        .min_by(|a, b| a.distance.total_cmp(&b.distance))
+}
+
+fn resolve_hit(hits: &[Option<AovHit>], resolve: AovResolve) -> Option<&AovHit> {
+    match resolve {
+        AovResolve::Nearest | AovResolve::Samples => nearest_hit(hits.iter(), None),
+        AovResolve::Majority => {
+            // sample count and nearest distance per primitive, None for misses
+            let mut groups = BTreeMap::<Option<usize>, (usize, f64)>::new();
+            for hit in hits {
+                let group = groups
+                    .entry(hit.as_ref().map(|hit| hit.index))
+                    .or_insert((0, f64::INFINITY));
+                group.0 += 1;
+                group.1 = group
+                    .1
+                    .min(hit.as_ref().map_or(f64::INFINITY, |hit| hit.distance));
+            }
+            let (winner, _) = groups
+                .into_iter()
+                .max_by(|(_, a), (_, b)| a.0.cmp(&b.0).then(b.1.total_cmp(&a.1)))?;
+            nearest_hit(hits.iter(), Some(winner?))
+        }
+    }
+}
+
+fn aov_value(aov: Aov, hits: &[Option<AovHit>], resolve: AovResolve) -> Vector3<f64> {
+    match aov {
+        Aov::Coverage => {
+            let covered = hits.iter().flatten().filter(|hit| hit.covered).count();
+            Vector3::repeat(covered as f64 / hits.len() as f64)
+        }
+        Aov::Normal | Aov::Albedo | Aov::Emission => {
+            let (count, sum) = hits.iter().flatten().fold((0, BLACK), |(count, sum), hit| {
+                let value = match aov {
+                    Aov::Albedo => hit.albedo,
+                    Aov::Emission => hit.emission,
+                    _ => hit.normal,
+                };
+                (count + 1, sum + value)
+            });
+            if count > 0 {
+                sum / count as f64
+            } else {
+                BLACK
+            }
+        }
+        Aov::Depth => {
+            Vector3::repeat(resolve_hit(hits, resolve).map_or(f64::INFINITY, |hit| hit.distance))
+        }
+        Aov::PrimitiveId => {
+            Vector3::repeat(resolve_hit(hits, resolve).map_or(-1.0, |hit| hit.index as f64))
+        }
+        Aov::Light(_) => unreachable!("Light passes are rendered, not resolved."),
+    }
+}
+
+pub fn render_aov(scene: &Scene, aov: Aov, resolve: AovResolve) -> Vec<Vector3<f64>> {
+    if let Aov::Light(pass) = aov {
+        let mut values = Vec::with_capacity(scene.width as usize * scene.height as usize);
+        render_rows(scene, Some(pass), false, |row, _| {
+            values.extend_from_slice(row)
+        });
+        return values;
+    }
+    let filter = &FilterSampler::new(&scene.pixel_filter);
+    let mut path = PathContext {
+        rng: create_rng(scene.rng_backend, scene.seed),
+        sampler: Sampler::new(scene.samples, scene.sampler_type),
+        segments: None,
+        channel: None,
+        throughput: Vector3::repeat(1.0),
+        media: vec![scene.fog.clone()],
+        pass: None,
+    };
+    let mut result = Vec::<Vector3<f64>>::new();
+    let mut hits = Vec::<Option<AovHit>>::with_capacity(scene.samples as usize);
+    for row in 0..scene.height {
+        for column in 0..scene.width {
+            start_pixel(
+                scene,
+                &mut path,
+                row as u64 * scene.width as u64 + column as u64,
+            );
+            hits.clear();
+            for sample in 0..scene.samples {
+                path.sampler.start_sample(sample);
+                let ray = sample_camera_ray(scene, &mut path, filter, column, row);
+                hits.push(ray.and_then(|(ray, _)| first_hit(scene, &ray)));
+            }
+            match resolve {
+                AovResolve::Samples => result.extend(
+                    hits.iter()
+                        .map(|hit| aov_value(aov, std::slice::from_ref(hit), resolve)),
+                ),
+                _ => result.push(aov_value(aov, &hits, resolve)),
+            }
         }
     }
     result
 }
+
+// ACES and gamma down to 8 bits, also used to eyeball data passes while debugging.
+pub fn tonemap(values: &[Vector3<f64>]) -> Vec<u8> {
+    values
+        .iter()
+        .flat_map(|value| proportion_to_value(*value))
+        .collect()
+}
+
+// Traces all samples of the given pixels again, recording every ray segment.
+pub fn trace_pixel_paths(scene: &Scene, pixels: &[(u32, u32)]) -> Vec<[Vector3<f64>; 2]> {
+    let global_distr = &build_global_distr(scene);
+    let filter = &FilterSampler::new(&scene.pixel_filter);
+
+    let mut path = PathContext {
+        rng: create_rng(scene.rng_backend, scene.seed),
+        sampler: Sampler::new(scene.samples, scene.sampler_type),
+        segments: Some(vec![]),
+        channel: None,
+        throughput: Vector3::repeat(1.0),
+        media: vec![scene.fog.clone()],
+        pass: None,
+    };
+    for &(column, row) in pixels {
+        start_pixel(
+            scene,
+            &mut path,
+            row as u64 * scene.width as u64 + column as u64,
+        );
+        for sample in 0..scene.samples {
+            path.sampler.start_sample(sample);
+            let Some((ray, _)) = sample_camera_ray(scene, &mut path, filter, column, row) else {
+                continue;
+            };
+            get_ray_color(
+                scene,
+                &mut path,
+                global_distr,
+                &ray,
+                PathDepth::default(),
+                None,
+            );
+        }
+    }
+    path.segments.unwrap_or_default()
+}
@@ -0,0 +1,99 @@
+use std::fmt::Write;
+use std::fs;
+use std::time::Duration;
+
+use crate::rendering::RenderStatistics;
+use crate::scene::Scene;
+use crate::scene_dump::{json_number, json_string};
+
+// Everything known about one render once it is written, for --report.
+pub struct RenderReport {
+    pub scene_hash: u64,
+    // in the order the stages ran; PPM and EXR rows are written while rendering, so
+    // writing them is part of the render stage
+    pub stages: Vec<(&'static str, Duration)>,
+    pub statistics: RenderStatistics,
+    pub warnings: Vec<String>,
+}
+
+// Peak resident set size in bytes, only known on Linux.
+fn peak_memory() -> Option<u64> {
+    let status = fs::read_to_string("/proc/self/status").ok()?;
+    let line = status.lines().find(|line| line.starts_with("VmHWM:"))?;
+    let kilobytes: u64 = line.split_whitespace().nth(1)?.parse().ok()?;
+    Some(kilobytes * 1024)
+}
+
+fn json_option(value: Option<impl ToString>) -> String {
+    value.map_or("null".to_string(), |value| value.to_string())
+}
+
+pub fn report_to_json(scene: &Scene, report: &RenderReport) -> String {
+    let statistics = &report.statistics;
+    let render_seconds = report
+        .stages
+        .iter()
+        .find(|(stage, _)| *stage == "render")
+        .map(|(_, time)| time.as_secs_f64());
+    let mut json = String::new();
+    writeln!(json, "{{").unwrap();
+    writeln!(json, "  \"scene_hash\": \"{:016x}\",", report.scene_hash).unwrap();
+    writeln!(json, "  \"width\": {},", scene.width).unwrap();
+    writeln!(json, "  \"height\": {},", scene.height).unwrap();
+    writeln!(json, "  \"seconds\": {{").unwrap();
+    for (stage, time) in &report.stages {
+        writeln!(json, "    \"{}\": {},", stage, time.as_secs_f64()).unwrap();
+    }
+    let total: Duration = report.stages.iter().map(|(_, time)| *time).sum();
+    writeln!(json, "    \"total\": {}", total.as_secs_f64()).unwrap();
+    writeln!(json, "  }},").unwrap();
+    writeln!(
+        json,
+        "  \"peak_memory_bytes\": {},",
+        json_option(peak_memory())
+    )
+    .unwrap();
+    writeln!(json, "  \"rays\": {{").unwrap();
+    writeln!(json, "    \"camera\": {},", statistics.samples).unwrap();
+    writeln!(json, "    \"total\": {},", statistics.rays).unwrap();
+    writeln!(
+        json,
+        "    \"per_second\": {}",
+        render_seconds
+            .map(|seconds| statistics.rays as f64 / seconds)
+            .map_or("null".to_string(), json_number)
+    )
+    .unwrap();
+    writeln!(json, "  }},").unwrap();
+    writeln!(json, "  \"convergence\": {{").unwrap();
+    writeln!(json, "    \"samples_per_pixel\": {},", scene.samples).unwrap();
+    writeln!(
+        json,
+        "    \"mean_samples_per_pixel\": {},",
+        statistics.samples as f64 / statistics.pixels.max(1) as f64
+    )
+    .unwrap();
+    writeln!(
+        json,
+        "    \"converged_pixels\": {},",
+        statistics.converged_pixels
+    )
+    .unwrap();
+    writeln!(
+        json,
+        "    \"mean_relative_error\": {}",
+        statistics
+            .mean_relative_error()
+            .map_or("null".to_string(), json_number)
+    )
+    .unwrap();
+    writeln!(json, "  }},").unwrap();
+    let warnings: Vec<String> = report
+        .warnings
+        .iter()
+        .map(|warning| json_string(warning))
+        .collect();
+    writeln!(json, "  \"warnings\": [{}]", warnings.join(", ")).unwrap();
+    writeln!(json, "}}").unwrap();
+    json
+}
@@ -0,0 +1,42 @@
+use rand::{RngCore, SeedableRng};
+use rand_pcg::Pcg32;
+use rand_xoshiro::Xoshiro256PlusPlus;
+
+#[derive(Clone, Copy)]
+pub enum RngBackend {
+    Thread,
+    Pcg32,
+    Xoshiro256PlusPlus,
+}
+
+pub fn parse_rng_backend(name: &str) -> Option<RngBackend> {
+    match name {
+        "THREAD" => Some(RngBackend::Thread),
+        "PCG32" => Some(RngBackend::Pcg32),
+        "XOSHIRO256PP" => Some(RngBackend::Xoshiro256PlusPlus),
+        _ => None,
+    }
+}
+
+// Seeded from entropy unless a seed is given. The thread RNG cannot be seeded, so a
+// seeded Thread backend is a Xoshiro256PlusPlus.
+pub fn create_rng(backend: RngBackend, seed: Option<u64>) -> Box<dyn RngCore> {
+    match (backend, seed) {
+        (RngBackend::Thread, None) => Box::new(rand::thread_rng()),
+        (RngBackend::Pcg32, None) => Box::new(Pcg32::from_entropy()),
+        (RngBackend::Xoshiro256PlusPlus, None) => Box::new(Xoshiro256PlusPlus::from_entropy()),
+        (RngBackend::Pcg32, Some(seed)) => Box::new(Pcg32::seed_from_u64(seed)),
+        (RngBackend::Thread | RngBackend::Xoshiro256PlusPlus, Some(seed)) => {
+            Box::new(Xoshiro256PlusPlus::seed_from_u64(seed))
+        }
+    }
+}
+
+// SplitMix64 finalizer over the scene seed and the pixel index, so that neighbouring
+// pixels get unrelated streams.
+pub fn pixel_seed(seed: u64, pixel: u64) -> u64 {
+    let mut z = seed ^ pixel.wrapping_add(1).wrapping_mul(0x9e3779b97f4a7c15);
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+    z ^ (z >> 31)
+}
@@ -0,0 +1,216 @@
+use rand::{Rng, RngCore};
+
+// Every decision along a path reads a fixed dimension, so the same decision at the
+// same vertex sees the same stratified sequence in every sample of the pixel.
+#[derive(Clone, Copy)]
+pub enum Dimension {
+    PixelX,
+    PixelY,
+    LensU,
+    LensV,
+    LightU(u32),
+    LightV(u32),
+    LightPick(u32),
+    BsdfU(u32),
+    BsdfV(u32),
+    Lobe(u32),
+    RussianRoulette(u32),
+    MediumDistance(u32),
+    MaterialPick(u32),
+    ChannelPick(u32),
+}
+
+const CAMERA_DIMENSIONS: u32 = 4;
+// Even, so that the 2D pairs below stay pairs of the sequences at every vertex.
+const VERTEX_DIMENSIONS: u32 = 10;
+// Deeper vertices are padded with independent uniforms.
+const MAX_STRATIFIED_VERTICES: u32 = 16;
+
+fn dimension_index(dimension: Dimension) -> Option<u32> {
+    let (vertex, offset) = match dimension {
+        Dimension::PixelX => return Some(0),
+        Dimension::PixelY => return Some(1),
+        Dimension::LensU => return Some(2),
+        Dimension::LensV => return Some(3),
+        Dimension::LightU(vertex) => (vertex, 0),
+        Dimension::LightV(vertex) => (vertex, 1),
+        Dimension::BsdfU(vertex) => (vertex, 2),
+        Dimension::BsdfV(vertex) => (vertex, 3),
+        Dimension::LightPick(vertex) => (vertex, 4),
+        Dimension::Lobe(vertex) => (vertex, 5),
+        Dimension::RussianRoulette(vertex) => (vertex, 6),
+        Dimension::MediumDistance(vertex) => (vertex, 7),
+        Dimension::MaterialPick(vertex) => (vertex, 8),
+        Dimension::ChannelPick(vertex) => (vertex, 9),
+    };
+    (vertex < MAX_STRATIFIED_VERTICES)
+        .then_some(CAMERA_DIMENSIONS + vertex * VERTEX_DIMENSIONS + offset)
+}
+
+#[derive(Clone, Copy)]
+pub enum SamplerType {
+    // plain uniform random numbers, for reference
+    Independent,
+    Stratified,
+    // low-discrepancy sequences, consecutive dimensions are paired up so that each
+    // pair is well distributed in 2D
+    Halton,
+    Sobol,
+}
+
+pub fn parse_sampler_type(name: &str) -> Option<SamplerType> {
+    match name {
+        "INDEPENDENT" => Some(SamplerType::Independent),
+        "STRATIFIED" => Some(SamplerType::Stratified),
+        "HALTON" => Some(SamplerType::Halton),
+        "SOBOL" => Some(SamplerType::Sobol),
+        _ => None,
+    }
+}
+
+// Sample values for one pixel. In stratified mode sample `index` out of `count` lands
+// in its own stratum of [0, 1) for every dimension, with strata shuffled per pixel and
+// dimension so that different dimensions stay uncorrelated. The sequences shuffle
+// their points per pixel and dimension pair the same way.
+pub struct Sampler {
+    sampler_type: SamplerType,
+    seed: u64,
+    index: u32,
+    count: u32,
+}
+
+impl Sampler {
+    pub fn new(count: u32, sampler_type: SamplerType) -> Sampler {
+        Sampler {
+            sampler_type,
+            seed: 0,
+            index: 0,
+            count: count.max(1),
+        }
+    }
+
+    pub fn start_pixel(&mut self, pixel: u64) {
+        self.seed = pixel;
+        self.index = 0;
+    }
+
+    pub fn start_sample(&mut self, index: u32) {
+        self.index = index % self.count;
+    }
+
+    pub fn get_1d(&self, rng: &mut dyn RngCore, dimension: Dimension) -> f64 {
+        let Some(dimension) = dimension_index(dimension) else {
+            return rng.gen();
+        };
+        // the pair's points are shuffled together, each dimension is scrambled alone
+        let pair_seed = hash(self.seed, dimension / 2 + PAIR_SEED_OFFSET);
+        let dimension_seed = hash(self.seed, dimension);
+        match self.sampler_type {
+            SamplerType::Independent => rng.gen(),
+            SamplerType::Stratified => {
+                let stratum = permute(self.index, self.count, dimension_seed);
+                (stratum as f64 + rng.gen::<f64>()) / self.count as f64
+            }
+            SamplerType::Halton => {
+                let index = permute(self.index, self.count, pair_seed);
+                let base = if dimension % 2 == 0 { 2 } else { 3 };
+                // Cranley-Patterson rotation
+                (radical_inverse(index, base) + dimension_seed as f64 / 2f64.powi(32)).fract()
+            }
+            SamplerType::Sobol => {
+                let index = nested_uniform_scramble(self.index, pair_seed);
+                let value = if dimension % 2 == 0 {
+                    index.reverse_bits()
+                } else {
+                    sobol_second_dimension(index)
+                };
+                nested_uniform_scramble(value, dimension_seed) as f64 / 2f64.powi(32)
+            }
+        }
+    }
+}
+
+// keeps the pair seeds apart from the per dimension ones
+const PAIR_SEED_OFFSET: u32 = 1 << 16;
+
+fn radical_inverse(mut index: u32, base: u32) -> f64 {
+    let mut result = 0.0;
+    let mut scale = 1.0 / base as f64;
+    while index > 0 {
+        result += (index % base) as f64 * scale;
+        index /= base;
+        scale /= base as f64;
+    }
+    result
+}
+
+// Second dimension of the Sobol sequence, whose generator matrix is Pascal's triangle
+// mod 2. The first one is the bit reversed index.
+fn sobol_second_dimension(mut index: u32) -> u32 {
+    let mut result = 0;
+    let mut direction = 1 << 31;
+    while index != 0 {
+        if index & 1 != 0 {
+            result ^= direction;
+        }
+        index >>= 1;
+        direction ^= direction >> 1;
+    }
+    result
+}
+
+// Burley's hash based Owen scrambling. Each bit is flipped depending on the bits above
+// it, so the values keep their stratification at every power of two resolution.
+fn nested_uniform_scramble(value: u32, seed: u32) -> u32 {
+    let mut x = value.reverse_bits();
+    x ^= x.wrapping_mul(0x3d20adea);
+    x = x.wrapping_add(seed);
+    x = x.wrapping_mul((seed >> 16) | 1);
+    x ^= x.wrapping_mul(0x05526c56);
+    x ^= x.wrapping_mul(0x53a22864);
+    x.reverse_bits()
+}
+
+fn hash(seed: u64, dimension: u32) -> u32 {
+    // splitmix64 finalizer
+    let mut x = seed ^ (dimension as u64).wrapping_mul(0x9e3779b97f4a7c15);
+    x = (x ^ (x >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+    x = (x ^ (x >> 27)).wrapping_mul(0x94d049bb133111eb);
+    (x ^ (x >> 31)) as u32
+}
+
+// Kensler's hashed permutation of 0..length, indexed without building a table.
+fn permute(index: u32, length: u32, pattern: u32) -> u32 {
+    let mut mask = length - 1;
+    mask |= mask >> 1;
+    mask |= mask >> 2;
+    mask |= mask >> 4;
+    mask |= mask >> 8;
+    mask |= mask >> 16;
+
+    let mut i = index;
+    loop {
+        i ^= pattern;
+        i = i.wrapping_mul(0xe170893d);
+        i ^= pattern >> 16;
+        i ^= (i & mask) >> 4;
+        i ^= pattern >> 8;
+        i = i.wrapping_mul(0x0929eb3f);
+        i ^= pattern >> 23;
+        i ^= (i & mask) >> 1;
+        i = i.wrapping_mul(1 | pattern >> 27);
+        i = i.wrapping_mul(0x6935fa69);
+        i ^= (i & mask) >> 11;
+        i = i.wrapping_mul(0x74dcb303);
+        i ^= (i & mask) >> 2;
+        i = i.wrapping_mul(0x9e501cc3);
+        i ^= (i & mask) >> 2;
+        i = i.wrapping_mul(0xc860a3df);
+        i &= mask;
+        i ^= i >> 5;
+        if i < length {
+            break;
+        }
+    }
+    i.wrapping_add(pattern) % length
+}
@@ -1,194 +1,1916 @@
+use std::collections::HashMap;
+use std::f64::consts::PI;
+use std::fmt;
+use std::str::FromStr;
+use std::sync::Arc;
+
 use na::UnitQuaternion;
+use na::Vector2;
 use na::Vector3;
 use nalgebra::Quaternion;
 
-use crate::geometry::Shape;
+use crate::assets::Assets;
+use crate::bvh::{build_mesh_bvhs, Bvh};
+use crate::environment::{generate_starfield, load_environment_map, EnvironmentMap, Starfield};
+use crate::expression::{evaluate, is_variable_name};
+use crate::filter::{parse_pixel_filter, PixelFilter};
+use crate::geometry::{intersect_primitive, intersect_scene, surface_area, Ray, Shape};
+use crate::medium::{HomogeneousMedium, Medium, ShadowRays};
+use crate::obj::load_obj;
+use crate::rendering::build_camera_ray;
+use crate::rng::{parse_rng_backend, RngBackend};
+use crate::sampler::{parse_sampler_type, SamplerType};
+use crate::texture::{load_texture, parse_color_space, ApertureMask, ColorSpace, Texture};
+
+#[derive(Clone, Copy)]
+pub enum CameraType {
+    Perspective,
+    // parallel rays along the forward axis, `width` scene units across the image
+    Orthographic { width: f64 },
+    // equidistant, the horizontal field of view spans the image width and pixels more
+    // than 180 degrees off the forward axis stay black
+    Fisheye,
+    // every direction, laid out like an ENVIRONMENT_MAP with the forward axis at the
+    // center and the right axis to the right of it
+    Equirectangular,
+}
 
 pub struct Camera {
+    pub camera_type: CameraType,
     pub position: Vector3<f64>,
     pub right_axis: Vector3<f64>,
     pub up_axis: Vector3<f64>,
     pub forward_axis: Vector3<f64>,
+    // unused by orthographic and equirectangular cameras
     pub fov_x: f64,
     pub fov_y: f64,
+    // diameter of the thin lens, zero for a pinhole
+    pub aperture: f64,
+    // opening of the lens over the square of side `aperture`, a disk when None
+    pub aperture_mask: Option<Arc<ApertureMask>>,
+    // distance along the forward axis of the plane that stays in focus
+    pub focus_distance: f64,
+    pub exposure: f64,
+    pub sensor: Option<Sensor>,
+}
+
+pub struct Sensor {
+    // photo-electrons per unit of exposed value, already divided by ISO / 100
+    pub electrons: f64,
+    pub read_noise: f64,
+    // relative channel sensitivity, e.g. 1 2 1 for an RGGB Bayer mosaic
+    pub response: Vector3<f64>,
 }
 
 #[derive (Clone)]
+#[allow(clippy::upper_case_acronyms)]
 pub enum Material {
     METALLIC,
-    DIELECTRIC { ior: f64 },
+    DIELECTRIC {
+        ior: f64,
+        // Abbe number, None for a dielectric without dispersion
+        abbe: Option<f64>,
+        // per unit length traveled inside, zero for clear glass
+        absorption: Vector3<f64>,
+    },
     DIFFUSE,
+    CARPAINT {
+        flake_size: f64,
+        flake_density: f64,
+        flake_color: Vector3<f64>,
+    },
+    // black to the camera, diffuse to everything else
+    HOLDOUT,
+}
+
+#[derive(Clone, Copy)]
+pub enum GradientSpace {
+    Object,
+    World,
+    Camera,
+}
+
+#[derive(Clone)]
+pub enum EmissionGradient {
+    Constant,
+    Radial {
+        space: GradientSpace,
+        center: Vector3<f64>,
+        radius: f64,
+        inner_scale: f64,
+        outer_scale: f64,
+    },
+    Linear {
+        space: GradientSpace,
+        start: Vector3<f64>,
+        end: Vector3<f64>,
+        start_scale: f64,
+        end_scale: f64,
+    },
+}
+
+// What decides how much of a primitive shows its mix material.
+#[derive(Clone)]
+pub enum MixWeight {
+    Constant(f64),
+    // luminance of the texture at the shape's texture coordinates, 0 where it has none
+    Mask(Arc<Texture>),
+    // Schlick's reflectance of an interface with this IOR at the viewing angle, so the
+    // mix shows most at grazing angles
+    Fresnel(f64),
+}
+
+// IOR of a DIELECTRIC mix material given without one.
+const MIX_DEFAULT_IOR: f64 = 1.5;
+
+// Second material blended over the primitive's own. Every shading point picks one of
+// the two with the probability the weight gives, so each keeps its own sampling.
+#[derive(Clone)]
+pub struct MaterialMix {
+    pub material: Material,
+    pub color: Vector3<f64>,
+    pub weight: MixWeight,
+}
+
+// Maps the texture coordinates of a shape before the texture lookup: scaled, rotated
+// counterclockwise by `rotation` radians around the origin, then offset. Textures
+// repeat, so a scale above 1 tiles them.
+#[derive(Clone, Copy)]
+pub struct UvTransform {
+    pub scale: Vector2<f64>,
+    pub offset: Vector2<f64>,
+    pub rotation: f64,
+}
+
+impl Default for UvTransform {
+    fn default() -> Self {
+        UvTransform {
+            scale: Vector2::new(1.0, 1.0),
+            offset: Vector2::zeros(),
+            rotation: 0.0,
+        }
+    }
+}
+
+impl UvTransform {
+    pub fn apply(&self, uv: &Vector2<f64>) -> Vector2<f64> {
+        let scaled = uv.component_mul(&self.scale);
+        let (sin, cos) = self.rotation.sin_cos();
+        Vector2::new(
+            cos * scaled.x - sin * scaled.y,
+            sin * scaled.x + cos * scaled.y,
+        ) + self.offset
+    }
+}
+
+#[derive(Clone)]
+pub enum LightLink {
+    All,
+    Include(Vec<String>),
+    Exclude(Vec<String>),
 }
 
 #[derive (Clone)]
 pub struct Primitive {
+    pub name: Option<String>,
     pub shape: Shape,
     pub color: Vector3<f64>,
+    // replaces color where the shape has texture coordinates
+    pub texture: Option<Arc<Texture>>,
+    // applies to the texture and the mix mask
+    pub uv_transform: UvTransform,
     pub position: Vector3<f64>,
     pub rotation: UnitQuaternion<f64>,
     pub material: Material,
     pub emission: Vector3<f64>,
+    pub emission_gradient: EmissionGradient,
+    pub light_link: LightLink,
+    // fills the inside of a dielectric
+    pub medium: Option<Arc<dyn Medium>>,
+    pub mix: Option<MaterialMix>,
+}
+
+impl Primitive {
+    // Black diffuse primitive at the origin, the starting point of NEW_PRIMITIVE.
+    pub fn new(shape: Shape) -> Primitive {
+        Primitive {
+            name: None,
+            shape,
+            color: Default::default(),
+            texture: None,
+            uv_transform: Default::default(),
+            position: Default::default(),
+            rotation: Default::default(),
+            material: Material::DIFFUSE,
+            emission: Default::default(),
+            emission_gradient: EmissionGradient::Constant,
+            light_link: LightLink::All,
+            medium: None,
+            mix: None,
+        }
+    }
+}
+
+// Infinitely small or infinitely far lights, which paths can only reach through
+// explicit shadow rays.
+pub enum LightType {
+    // intensity in radiance units per steradian, divided by the attenuation
+    Point {
+        position: Vector3<f64>,
+    },
+    // irradiance on a surface facing the light, direction points from the light
+    Directed {
+        direction: Vector3<f64>,
+    },
+    // point light shining along direction, at full intensity within inner_angle of it
+    // and fading out smoothly towards outer_angle, both half angles in radians
+    Spot {
+        position: Vector3<f64>,
+        direction: Vector3<f64>,
+        inner_angle: f64,
+        outer_angle: f64,
+    },
+}
+
+pub struct Light {
+    pub intensity: Vector3<f64>,
+    pub light_type: LightType,
+    // c0, c1, c2 of the point light falloff c0 + c1 * d + c2 * d^2, inverse square
+    // by default
+    pub attenuation: Vector3<f64>,
+}
+
+// Smoothstep from the outer cone, where the spot goes dark, to the inner one.
+fn spot_falloff(cos_angle: f64, inner_angle: f64, outer_angle: f64) -> f64 {
+    let (cos_inner, cos_outer) = (inner_angle.cos(), outer_angle.cos());
+    if cos_angle >= cos_inner {
+        1.0
+    } else if cos_angle <= cos_outer {
+        0.0
+    } else {
+        let t = (cos_angle - cos_outer) / (cos_inner - cos_outer);
+        t * t * (3.0 - 2.0 * t)
+    }
+}
+
+// The falloff a point or spot light's intensity is divided by at the distance.
+pub(crate) fn light_attenuation(light: &Light, distance: f64) -> f64 {
+    light
+        .attenuation
+        .dot(&Vector3::new(1.0, distance, distance * distance))
+}
+
+// Direction from the point to the light, irradiance there on a surface facing the
+// light, and distance to the light (None for directed lights).
+pub fn get_light_characteristic_to_point(
+    light: &Light,
+    point: &Vector3<f64>,
+) -> (Vector3<f64>, Vector3<f64>, Option<f64>) {
+    match light.light_type {
+        LightType::Point { position } | LightType::Spot { position, .. } => {
+            let to_light = position - point;
+            let distance = to_light.norm();
+            let attenuation = light_attenuation(light, distance);
+            let falloff = match light.light_type {
+                LightType::Spot {
+                    direction,
+                    inner_angle,
+                    outer_angle,
+                    ..
+                } => spot_falloff(
+                    -to_light.dot(&direction.normalize()) / distance,
+                    inner_angle,
+                    outer_angle,
+                ),
+                _ => 1.0,
+            };
+            (
+                to_light / distance,
+                light.intensity * falloff / attenuation,
+                Some(distance),
+            )
+        }
+        LightType::Directed { direction } => (-direction.normalize(), light.intensity, None),
+    }
 }
 
 pub struct Scene {
     pub width: u32,
     pub height: u32,
     pub background_color: Vector3<f64>,
+    // replaces background_color for rays leaving the scene
+    pub environment_map: Option<Arc<EnvironmentMap>>,
+    // fills the space between surfaces, rays leaving the scene leave it too
+    pub fog: Option<Arc<dyn Medium>>,
     pub camera: Camera,
     pub primitives: Vec<Primitive>,
+    pub lights: Vec<Light>,
     pub ray_depth: u32,
+    // limits refraction separately, ray_depth applies when None so that it follows
+    // overrides of ray_depth
+    pub transparent_depth: Option<u32>,
+    // constant light from every direction that reaches surfaces but not the camera
     pub ambient_light: Vector3<f64>,
     pub samples: u32,
+    // relative standard error at which a pixel stops sampling, SAMPLES is then the most
+    // a pixel takes
+    pub adaptive_threshold: Option<f64>,
+    // seconds the render may take, spent on the tiles with the largest error first,
+    // SAMPLES is then the most a pixel takes
+    pub time_budget: Option<f64>,
+    // largest component of the light a scattered ray may bring back, brighter rays are
+    // scaled down to it
+    pub clamp: Option<f64>,
+    // groups the samples of a pixel are split into, the pixel then takes the mean of the
+    // group with the median luminance rather than the mean of all samples
+    pub median_of_means: Option<u32>,
+    pub pixel_filter: PixelFilter,
+    pub rng_backend: RngBackend,
+    // makes renders reproducible, each pixel reseeds the RNG from it
+    pub seed: Option<u64>,
+    pub sampler_type: SamplerType,
+    pub max_pdf_ratio: Option<f64>,
+    // vertex from which paths are randomly terminated by their throughput, never when None
+    pub roulette_depth: Option<u32>,
+    pub probes: Vec<Vector3<f64>>,
+    pub probe_samples: u32,
+    // lines the parser skipped because their first token is no keyword, as 1-based line
+    // number and token
+    pub unknown_keywords: Vec<(usize, String)>,
+    pub bvh: Bvh,
+}
+
+// Vertical field of view of pixels as wide as they are tall.
+pub fn vertical_fov(fov_x: f64, width: u32, height: u32) -> f64 {
+    2.0 * ((fov_x / 2.0).tan() * height as f64 / width as f64).atan()
+}
+
+const LUMINOUS_EFFICACY: f64 = 683.0;
+
+// Saturation-based exposure (ISO 12232). Radiance is converted to luminance with the
+// 683 lm/W peak efficacy, so emitters given in photometric units expose sensibly.
+fn photographic_exposure(iso: f64, shutter: f64, f_stop: f64) -> f64 {
+    let ev100 = (f_stop * f_stop / shutter * 100.0 / iso).log2();
+    LUMINOUS_EFFICACY / (1.2 * 2f64.powf(ev100))
+}
+
+#[derive(Clone, Copy)]
+enum PhotometricEmission {
+    Lumens(f64),
+    Candela(f64),
+    Nits(f64),
+}
+
+fn color_luminance(color: &Vector3<f64>) -> f64 {
+    0.2126 * color.x + 0.7152 * color.y + 0.0722 * color.z
+}
+
+// Emitters are Lambertian, so a flux spreads as L = flux / (pi * area), and an
+// intensity is taken as isotropic over the whole emitter, i.e. flux = 4 * pi * intensity.
+fn photometric_to_radiance(
+    color: &Vector3<f64>,
+    photometric: &PhotometricEmission,
+    shape: &Shape,
+) -> Vector3<f64> {
+    let luminance = match photometric {
+        PhotometricEmission::Lumens(flux) => flux / (PI * surface_area(shape)),
+        PhotometricEmission::Candela(intensity) => 4.0 * intensity / surface_area(shape),
+        PhotometricEmission::Nits(luminance) => *luminance,
+    };
+    color / color_luminance(color) * luminance / LUMINOUS_EFFICACY
+}
+
+pub enum MaterialOverride {
+    Clay,
+}
+
+pub fn parse_material_override(name: &str) -> Option<MaterialOverride> {
+    match name {
+        "clay" => Some(MaterialOverride::Clay),
+        _ => None,
+    }
+}
+
+// Emission is left untouched so lights keep illuminating the overridden scene.
+pub fn apply_material_override(scene: &mut Scene, material_override: &MaterialOverride) {
+    match material_override {
+        MaterialOverride::Clay => {
+            for primitive in scene.primitives.iter_mut() {
+                primitive.material = Material::DIFFUSE;
+                primitive.color = Vector3::new(0.8, 0.8, 0.8);
+                primitive.texture = None;
+                primitive.mix = None;
+            }
+        }
+    }
+}
+
+// Whole name against a pattern where * stands for any run of characters and ? for any
+// single one.
+fn matches_name_pattern(pattern: &[char], name: &[char]) -> bool {
+    match (pattern.split_first(), name.split_first()) {
+        (None, _) => name.is_empty(),
+        (Some(('*', pattern_rest)), _) => {
+            matches_name_pattern(pattern_rest, name)
+                || (!name.is_empty() && matches_name_pattern(pattern, &name[1..]))
+        }
+        (Some((expected, pattern_rest)), Some((found, name_rest))) => {
+            (*expected == '?' || expected == found) && matches_name_pattern(pattern_rest, name_rest)
+        }
+        (Some(_), None) => false,
+    }
+}
+
+// Turns the primitives whose NAME matches the pattern into holdouts, or with `inverse`
+// all the others, unnamed ones included. Returns how many primitives matched.
+pub fn apply_matte(scene: &mut Scene, pattern: &str, inverse: bool) -> usize {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let mut matched = 0;
+    for primitive in scene.primitives.iter_mut() {
+        let is_match = primitive
+            .name
+            .as_ref()
+            .is_some_and(|name| matches_name_pattern(&pattern, &name.chars().collect::<Vec<_>>()));
+        if is_match {
+            matched += 1;
+        }
+        if is_match != inverse {
+            primitive.material = Material::HOLDOUT;
+            primitive.mix = None;
+        }
+    }
+    matched
+}
+
+// Rotation taking the given up axis of the file onto the renderer's +Y.
+fn parse_up_axis_rotation(name: &str) -> Option<UnitQuaternion<f64>> {
+    match name {
+        "X" => Some(UnitQuaternion::from_axis_angle(
+            &Vector3::z_axis(),
+            PI / 2.0,
+        )),
+        "Y" => Some(UnitQuaternion::identity()),
+        "Z" => Some(UnitQuaternion::from_axis_angle(
+            &Vector3::x_axis(),
+            -PI / 2.0,
+        )),
+        _ => None,
+    }
+}
+
+fn apply_scene_transform(scene: &mut Scene, scale: f64, up_rotation: &UnitQuaternion<f64>) {
+    let transform_point = |point: &Vector3<f64>| up_rotation.transform_vector(&(point * scale));
+
+    scene.camera.position = transform_point(&scene.camera.position);
+    scene.camera.right_axis = up_rotation.transform_vector(&scene.camera.right_axis);
+    scene.camera.up_axis = up_rotation.transform_vector(&scene.camera.up_axis);
+    scene.camera.forward_axis = up_rotation.transform_vector(&scene.camera.forward_axis);
+    scene.camera.aperture *= scale;
+    scene.camera.focus_distance *= scale;
+    if let CameraType::Orthographic { width } = &mut scene.camera.camera_type {
+        *width *= scale;
+    }
+
+    for probe in scene.probes.iter_mut() {
+        *probe = transform_point(probe);
+    }
+
+    for light in scene.lights.iter_mut() {
+        // attenuation stays in file units, so scaling doesn't dim point lights
+        light.attenuation.y /= scale;
+        light.attenuation.z /= scale * scale;
+        match &mut light.light_type {
+            LightType::Point { position } => *position = transform_point(position),
+            LightType::Directed { direction } => {
+                *direction = up_rotation.transform_vector(direction)
+            }
+            LightType::Spot {
+                position,
+                direction,
+                ..
+            } => {
+                *position = transform_point(position);
+                *direction = up_rotation.transform_vector(direction);
+            }
+        }
+    }
+
+    // (original, scaled) vertex buffers
+    let mut scaled_vertices: Vec<(Arc<_>, Arc<Vec<_>>)> = vec![];
+    for primitive in scene.primitives.iter_mut() {
+        primitive.position = transform_point(&primitive.position);
+        primitive.rotation = up_rotation * primitive.rotation;
+        match &mut primitive.shape {
+            Shape::Plane { normal: _ } => {}
+            Shape::Ellipsoid { r } => *r *= scale,
+            Shape::Sphere { r } => *r *= scale,
+            Shape::Box { s } => *s *= scale,
+            Shape::Cylinder { r, h } | Shape::Cone { r, h } => {
+                *r *= scale;
+                *h *= scale;
+            }
+            Shape::Rectangle { s } => *s *= scale,
+            Shape::Disc { r } => *r *= scale,
+            Shape::TriangleMesh {
+                vertices,
+                triangles: _,
+                normals: _,
+                bvh: _,
+                area_cdf: _,
+            } => {
+                // instances share the vertex buffer, which is scaled only once
+                let scaled = match scaled_vertices
+                    .iter()
+                    .find(|(original, _)| Arc::ptr_eq(original, vertices))
+                {
+                    Some((_, scaled)) => scaled.clone(),
+                    None => {
+                        let scaled: Arc<Vec<_>> =
+                            Arc::new(vertices.iter().map(|vertex| vertex * scale).collect());
+                        scaled_vertices.push((vertices.clone(), scaled.clone()));
+                        scaled
+                    }
+                };
+                *vertices = scaled;
+            }
+        }
+        // object and camera frames are rotated along with the scene, so only world
+        // space gradient coordinates need the rotation
+        let transform_gradient_point = |space: &GradientSpace, point: &mut Vector3<f64>| {
+            *point = match space {
+                GradientSpace::World => transform_point(point),
+                GradientSpace::Object | GradientSpace::Camera => *point * scale,
+            }
+        };
+        match &mut primitive.emission_gradient {
+            EmissionGradient::Constant => {}
+            EmissionGradient::Radial {
+                space,
+                center,
+                radius,
+                ..
+            } => {
+                transform_gradient_point(space, center);
+                *radius *= scale;
+            }
+            EmissionGradient::Linear {
+                space, start, end, ..
+            } => {
+                transform_gradient_point(space, start);
+                transform_gradient_point(space, end);
+            }
+        }
+        // per unit length like the media, so the scene scale thins it out
+        if let Material::DIELECTRIC { absorption, .. } = &mut primitive.material {
+            *absorption /= scale;
+        }
+    }
+}
+
+fn link_admits(link: &LightLink, other: &Primitive) -> bool {
+    let named_in =
+        |names: &Vec<String>| other.name.as_ref().is_some_and(|name| names.contains(name));
+    match link {
+        LightLink::All => true,
+        LightLink::Include(names) => named_in(names),
+        LightLink::Exclude(names) => !named_in(names),
+    }
+}
+
+// Link lists are symmetric: an emitter lists the receivers it lights and a receiver
+// lists the emitters it sees, and a pair is linked only if both sides admit each other.
+pub fn is_light_linked(light: &Primitive, receiver: &Primitive) -> bool {
+    link_admits(&light.light_link, receiver) && link_admits(&receiver.light_link, light)
+}
+
+// What CAMERA_FOCUS_OBJECT and CAMERA_FOCUS_PIXEL focus on.
+enum FocusTarget {
+    Object(String),
+    // column and row
+    Pixel(u32, u32),
+}
+
+// Distance along the forward axis to the surface the target shows, found by casting a
+// ray through the finished scene. An object is focused where the ray from the camera
+// to its origin first meets it, or at the origin when the ray misses it.
+fn autofocus_distance(scene: &Scene, target: &FocusTarget) -> Result<f64, SceneParseError> {
+    let camera = &scene.camera;
+    let point = match target {
+        FocusTarget::Pixel(column, row) => {
+            if *column >= scene.width || *row >= scene.height {
+                return Err(scene_error("Camera focus pixel is outside the image"));
+            }
+            let ray = build_camera_ray(scene, *column as f64 + 0.5, *row as f64 + 0.5)
+                .ok_or_else(|| scene_error("Camera focus pixel sees no surface"))?;
+            let (intersection, _) = intersect_scene(&ray, scene, None)
+                .ok_or_else(|| scene_error("Camera focus pixel sees no surface"))?;
+            ray.point + ray.direction * intersection.ts[0]
+        }
+        FocusTarget::Object(name) => {
+            let primitive = scene
+                .primitives
+                .iter()
+                .find(|primitive| primitive.name.as_ref() == Some(name))
+                .ok_or_else(|| scene_error("Camera focus object is not found"))?;
+            let ray = Ray {
+                point: camera.position,
+                direction: primitive.position - camera.position,
+            };
+            intersect_primitive(&ray, primitive).map_or(primitive.position, |intersection| {
+                ray.point + ray.direction * intersection.ts[0]
+            })
+        }
+    };
+    let distance = (point - camera.position).dot(&camera.forward_axis.normalize());
+    if distance <= 0.0 {
+        return Err(scene_error(
+            "Camera focus target is not in front of the camera",
+        ));
+    }
+    Ok(distance)
+}
+
+pub enum SceneParseError {
+    // problem with a single line, `line` is 1-based
+    Line {
+        line: usize,
+        token: String,
+        message: String,
+    },
+    // something missing or inconsistent once the whole file is read
+    Scene {
+        message: String,
+    },
+}
+
+impl fmt::Display for SceneParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SceneParseError::Line {
+                line,
+                token,
+                message,
+            } => write!(f, "line {}: {} (at \"{}\")", line, message, token),
+            SceneParseError::Scene { message } => write!(f, "{}", message),
+        }
+    }
+}
+
+fn line_error(line: usize, token: &str, message: &str) -> SceneParseError {
+    SceneParseError::Line {
+        line,
+        token: token.to_string(),
+        message: message.to_string(),
+    }
+}
+
+fn scene_error(message: &str) -> SceneParseError {
+    SceneParseError::Scene {
+        message: message.to_string(),
+    }
+}
+
+fn parse_token<T: FromStr>(
+    tokens: &[String],
+    index: usize,
+    line: usize,
+) -> Result<T, SceneParseError> {
+    let token = tokens
+        .get(index)
+        .ok_or_else(|| line_error(line, &tokens[0], &format!("expected {} values", index)))?;
+    token
+        .parse()
+        .map_err(|_| line_error(line, token, "invalid value"))
+}
+
+fn parse_vector3_at(
+    tokens: &[String],
+    first: usize,
+    line: usize,
+) -> Result<Vector3<f64>, SceneParseError> {
+    Ok(Vector3::new(
+        parse_token(tokens, first, line)?,
+        parse_token(tokens, first + 1, line)?,
+        parse_token(tokens, first + 2, line)?,
+    ))
+}
+
+// Color space tag that may follow an image path, `default` when there is none: the
+// one images used that way are usually stored in.
+fn color_space_at(
+    tokens: &[String],
+    index: usize,
+    line: usize,
+    default: ColorSpace,
+) -> Result<ColorSpace, SceneParseError> {
+    tokens.get(index).map_or(Ok(default), |token| {
+        parse_color_space(token).ok_or_else(|| line_error(line, token, "unknown color space"))
+    })
+}
+
+fn parse_gradient_space(name: &str) -> Option<GradientSpace> {
+    match name {
+        "OBJECT" => Some(GradientSpace::Object),
+        "WORLD" => Some(GradientSpace::World),
+        "CAMERA" => Some(GradientSpace::Camera),
+        _ => None,
+    }
+}
+
+fn last_light<'a>(
+    lights: &'a mut [Light],
+    tokens: &[String],
+    line: usize,
+) -> Result<&'a mut Light, SceneParseError> {
+    lights
+        .last_mut()
+        .ok_or_else(|| line_error(line, &tokens[0], "no NEW_LIGHT before this line"))
+}
+
+fn parse_medium(tokens: &[String], line: usize) -> Result<HomogeneousMedium, SceneParseError> {
+    let medium = HomogeneousMedium {
+        sigma_a: parse_token(tokens, 1, line)?,
+        sigma_s: parse_token(tokens, 2, line)?,
+        g: parse_token(tokens, 3, line)?,
+        // optional, light samples per scatter event and the roulette threshold
+        shadow_rays: ShadowRays {
+            splits: tokens
+                .get(4)
+                .map_or(Ok(1), |_| parse_token(tokens, 4, line))?,
+            roulette_threshold: tokens
+                .get(5)
+                .map_or(Ok(0.0), |_| parse_token(tokens, 5, line))?,
+        },
+    };
+    if medium.sigma_a < 0.0 || medium.sigma_s < 0.0 || medium.sigma_t() <= 0.0 {
+        return Err(line_error(
+            line,
+            &tokens[0],
+            "coefficients must be non-negative and not both zero",
+        ));
+    }
+    if medium.g.abs() >= 1.0 {
+        return Err(line_error(
+            line,
+            &tokens[3],
+            "asymmetry must be between -1 and 1",
+        ));
+    }
+    if !(0.0..=1.0).contains(&medium.shadow_rays.roulette_threshold) {
+        return Err(line_error(
+            line,
+            &tokens[5],
+            "roulette threshold must be between 0 and 1",
+        ));
+    }
+    Ok(medium)
 }
 
-pub fn parse_scene(file_content: String) -> Scene {
+fn last_primitive<'a>(
+    primitives: &'a mut [Primitive],
+    tokens: &[String],
+    line: usize,
+) -> Result<&'a mut Primitive, SceneParseError> {
+    primitives
+        .last_mut()
+        .ok_or_else(|| line_error(line, &tokens[0], "no NEW_PRIMITIVE before this line"))
+}
+
+// Rules every scene has to follow before it can be rendered, whether it was parsed or
+// put together with SceneBuilder.
+pub(crate) fn validate_scene(scene: &Scene) -> Result<(), SceneParseError> {
+    if scene.width == 0 || scene.height == 0 {
+        return Err(scene_error("Image dimensions must be positive"));
+    }
+    if scene.camera.aperture < 0.0 {
+        return Err(scene_error("Camera aperture must be non-negative"));
+    }
+    if scene.camera.focus_distance <= 0.0 {
+        return Err(scene_error("Camera focus distance must be positive"));
+    }
+    if scene
+        .adaptive_threshold
+        .is_some_and(|threshold| threshold <= 0.0)
+    {
+        return Err(scene_error("Adaptive threshold must be positive"));
+    }
+    if scene.time_budget.is_some_and(|budget| budget <= 0.0) {
+        return Err(scene_error("Time budget must be positive"));
+    }
+    if scene.clamp.is_some_and(|clamp| clamp <= 0.0) {
+        return Err(scene_error("Clamp must be positive"));
+    }
+    // pixels and probes average their samples
+    if scene.samples == 0 || scene.probe_samples == 0 {
+        return Err(scene_error("Sample counts must be positive"));
+    }
+    if scene.median_of_means == Some(0) {
+        return Err(scene_error("Median of means needs at least one group"));
+    }
+    for primitive in &scene.primitives {
+        if let Shape::TriangleMesh {
+            vertices,
+            triangles,
+            normals,
+            bvh: _,
+            area_cdf: _,
+        } = &primitive.shape
+        {
+            if triangles.is_empty() {
+                return Err(scene_error("Triangle mesh has no triangles"));
+            }
+            if triangles
+                .iter()
+                .flatten()
+                .any(|&index| index >= vertices.len())
+            {
+                return Err(scene_error("Triangle vertex index out of range"));
+            }
+            if !normals.is_empty() && normals.len() != vertices.len() {
+                return Err(scene_error("Triangle mesh needs one normal per vertex"));
+            }
+            // meshes have no texture coordinates to look a texture up with
+            let mask = matches!(
+                primitive.mix,
+                Some(MaterialMix {
+                    weight: MixWeight::Mask(_),
+                    ..
+                })
+            );
+            if primitive.texture.is_some() || mask {
+                return Err(scene_error(
+                    "Textures and mix masks are not supported on triangle meshes",
+                ));
+            }
+        }
+    }
+    for light in &scene.lights {
+        if let LightType::Directed { direction } = light.light_type {
+            if direction == Vector3::zeros() {
+                return Err(scene_error(
+                    "Light needs a LIGHT_DIRECTION or a LIGHT_POSITION",
+                ));
+            }
+        }
+        if light.attenuation.min() < 0.0 || light.attenuation == Vector3::zeros() {
+            return Err(scene_error(
+                "Light attenuation must be non-negative and not all zero",
+            ));
+        }
+    }
+    Ok(())
+}
+
+pub fn parse_scene(file_content: String, assets: &Assets) -> Result<Scene, SceneParseError> {
     let mut width: Option<u32> = None;
     let mut height: Option<u32> = None;
     let mut background_color: Option<Vector3<f64>> = None;
+    let mut environment_map: Option<Arc<EnvironmentMap>> = None;
+    let mut starfield: Option<Starfield> = None;
+    let mut fog: Option<HomogeneousMedium> = None;
+    let mut media: Vec<(usize, HomogeneousMedium)> = vec![];
     let mut position: Option<Vector3<f64>> = None;
     let mut right_axis: Option<Vector3<f64>> = None;
     let mut up_axis: Option<Vector3<f64>> = None;
     let mut forward_axis: Option<Vector3<f64>> = None;
+    let mut camera_type = CameraType::Perspective;
     let mut fov_x: Option<f64> = None;
+    let mut iso: Option<f64> = None;
+    let mut shutter: Option<f64> = None;
+    let mut f_stop: Option<f64> = None;
+    let mut aperture: f64 = 0.0;
+    let mut aperture_mask: Option<Arc<ApertureMask>> = None;
+    let mut focus_distance: Option<f64> = None;
+    let mut focus_target: Option<FocusTarget> = None;
+    let mut sensor_electrons: Option<f64> = None;
+    let mut sensor_read_noise: f64 = 0.0;
+    let mut sensor_response = Vector3::new(1.0, 1.0, 1.0);
     let mut primitives: Vec<Primitive> = vec![];
+    let mut lights: Vec<Light> = vec![];
+    let mut photometric_emissions: Vec<(usize, Vector3<f64>, PhotometricEmission)> = vec![];
     let mut ray_depth: Option<u32> = None;
+    let mut transparent_depth: Option<u32> = None;
     let mut ambient_light: Option<Vector3<f64>> = Some(Default::default());
     let mut samples: Option<u32> = None;
+    let mut adaptive_threshold: Option<f64> = None;
+    let mut time_budget: Option<f64> = None;
+    let mut clamp: Option<f64> = None;
+    let mut median_of_means: Option<u32> = None;
+    let mut pixel_filter = PixelFilter::Center;
+    let mut rng_backend = RngBackend::Thread;
+    let mut sampler_type = SamplerType::Stratified;
+    let mut max_pdf_ratio: Option<f64> = None;
+    let mut roulette_depth: Option<u32> = None;
+    let mut seed: Option<u64> = None;
+    let mut probes: Vec<Vector3<f64>> = vec![];
+    let mut probe_samples: u32 = 1024;
+    let mut scene_scale: f64 = 1.0;
+    let mut scene_up_rotation: UnitQuaternion<f64> = UnitQuaternion::identity();
+    let mut unknown_keywords: Vec<(usize, String)> = vec![];
+
+    let mut variables: HashMap<String, f64> = HashMap::new();
 
-    for line in file_content.lines() {
-        let tokens: Vec<String> = line.split_whitespace().map(|s| s.to_string()).collect();
+    for (line_index, line) in file_content.lines().enumerate() {
+        let line_number = line_index + 1;
+        let mut tokens: Vec<String> = line.split_whitespace().map(|s| s.to_string()).collect();
 
-        if tokens.len() == 0 {
+        if tokens.is_empty() {
             continue;
         }
 
-        let parse_vector3 = || {
-            Vector3::new(
-                tokens[1].parse().expect("Input file format error."),
-                tokens[2].parse().expect("Input file format error."),
-                tokens[3].parse().expect("Input file format error."),
-            )
+        // `$define NAME expression`, the expression may contain spaces
+        if tokens[0] == "$define" {
+            let name = tokens
+                .get(1)
+                .filter(|name| is_variable_name(name))
+                .ok_or_else(|| line_error(line_number, &tokens[0], "missing variable name"))?;
+            let value = evaluate(&tokens[2..].join(" "), &variables)
+                .ok_or_else(|| line_error(line_number, name, "invalid expression"))?;
+            variables.insert(name.clone(), value);
+            continue;
+        }
+        // arguments that are whole expressions are replaced by their value, anything
+        // else (names, paths, plain numbers) is left for the keyword to parse, so that
+        // integers keep digits an f64 would lose and names keep their leading zeros
+        for token in tokens.iter_mut().skip(1) {
+            let is_expression = token.contains(['$', '(', ')', '+', '-', '*', '/'])
+                && token.parse::<f64>().is_err();
+            if !is_expression {
+                continue;
+            }
+            match evaluate(token, &variables) {
+                Some(value) => *token = value.to_string(),
+                None if token.contains('$') => {
+                    return Err(line_error(
+                        line_number,
+                        token,
+                        "undefined variable or invalid expression",
+                    ))
+                }
+                None => {}
+            }
+        }
+
+        let parse_vector3 = || parse_vector3_at(&tokens, 1, line_number);
+        // a token that has to be there, without parsing it
+        let token_at = |index: usize| {
+            tokens
+                .get(index)
+                .ok_or_else(|| line_error(line_number, &tokens[0], "missing value"))
         };
 
         match tokens[0].as_str() {
             "DIMENSIONS" => {
-                width = Some(tokens[1].parse().expect("Input file format error."));
-                height = Some(tokens[2].parse().expect("Input file format error."));
-            }
-            "BG_COLOR" => background_color = Some(parse_vector3()),
-            "CAMERA_POSITION" => position = Some(parse_vector3()),
-            "CAMERA_RIGHT" => right_axis = Some(parse_vector3()),
-            "CAMERA_UP" => up_axis = Some(parse_vector3()),
-            "CAMERA_FORWARD" => forward_axis = Some(parse_vector3()),
-            "CAMERA_FOV_X" => fov_x = Some(tokens[1].parse().expect("Input file format error.")),
-            "NEW_PRIMITIVE" => primitives.push(Primitive {
-                shape: Shape::Plane {
-                    normal: Default::default(),
-                },
-                color: Default::default(),
-                position: Default::default(),
-                rotation: Default::default(),
-                material: Material::DIFFUSE,
-                emission: Default::default(),
-            }),
+                width = Some(parse_token(&tokens, 1, line_number)?);
+                height = Some(parse_token(&tokens, 2, line_number)?);
+            }
+            "BG_COLOR" => background_color = Some(parse_vector3()?),
+            // the last of the environment map and the starfield given wins
+            "ENVIRONMENT_MAP" => {
+                starfield = None;
+                environment_map = Some(Arc::new(
+                    load_environment_map(
+                        assets,
+                        token_at(1)?,
+                        color_space_at(&tokens, 2, line_number, ColorSpace::Srgb)?,
+                    )
+                    .map_err(|message| line_error(line_number, &tokens[1], &message))?,
+                ))
+            }
+            "STARFIELD" => {
+                environment_map = None;
+                starfield = Some(Starfield {
+                    count: parse_token(&tokens, 1, line_number)?,
+                    brightness: parse_token(&tokens, 2, line_number)?,
+                    band: 0.0,
+                    seed: 0,
+                });
+            }
+            "STARFIELD_BAND" | "STARFIELD_SEED" => {
+                let Some(starfield) = &mut starfield else {
+                    return Err(line_error(
+                        line_number,
+                        &tokens[0],
+                        "no STARFIELD before this line",
+                    ));
+                };
+                match tokens[0].as_str() {
+                    "STARFIELD_BAND" => starfield.band = parse_token(&tokens, 1, line_number)?,
+                    _ => starfield.seed = parse_token(&tokens, 1, line_number)?,
+                }
+            }
+            "CAMERA_TYPE" => {
+                camera_type = match token_at(1)?.as_str() {
+                    "PERSPECTIVE" => CameraType::Perspective,
+                    "ORTHOGRAPHIC" => {
+                        let width = parse_token(&tokens, 2, line_number)?;
+                        if width <= 0.0 {
+                            return Err(line_error(
+                                line_number,
+                                &tokens[2],
+                                "width must be positive",
+                            ));
+                        }
+                        CameraType::Orthographic { width }
+                    }
+                    "FISHEYE" => CameraType::Fisheye,
+                    "EQUIRECTANGULAR" => CameraType::Equirectangular,
+                    _ => return Err(line_error(line_number, &tokens[1], "unknown camera type")),
+                }
+            }
+            "CAMERA_POSITION" => position = Some(parse_vector3()?),
+            "CAMERA_RIGHT" => right_axis = Some(parse_vector3()?),
+            "CAMERA_UP" => up_axis = Some(parse_vector3()?),
+            "CAMERA_FORWARD" => forward_axis = Some(parse_vector3()?),
+            "CAMERA_FOV_X" => fov_x = Some(parse_token(&tokens, 1, line_number)?),
+            "CAMERA_ISO" => iso = Some(parse_token(&tokens, 1, line_number)?),
+            "CAMERA_SHUTTER" => shutter = Some(parse_token(&tokens, 1, line_number)?),
+            "CAMERA_FSTOP" => f_stop = Some(parse_token(&tokens, 1, line_number)?),
+            "CAMERA_APERTURE" => aperture = parse_token(&tokens, 1, line_number)?,
+            // masks are data, so they are read linearly unless tagged
+            "CAMERA_APERTURE_MASK" => {
+                let color_space = color_space_at(&tokens, 2, line_number, ColorSpace::Linear)?;
+                let texture =
+                    load_texture(assets, token_at(1)?, color_space).map_err(|message| {
+                        let message = format!("cannot read aperture mask: {}", message);
+                        line_error(line_number, &tokens[1], &message)
+                    })?;
+                aperture_mask = Some(Arc::new(ApertureMask::new(&texture).ok_or_else(|| {
+                    line_error(line_number, &tokens[1], "aperture mask is black")
+                })?));
+            }
+            // the last way of focusing given wins
+            "CAMERA_FOCUS_DIST" => {
+                focus_distance = Some(parse_token(&tokens, 1, line_number)?);
+                focus_target = None;
+            }
+            "CAMERA_FOCUS_OBJECT" => {
+                focus_target = Some(FocusTarget::Object(token_at(1)?.clone()));
+                focus_distance = None;
+            }
+            "CAMERA_FOCUS_PIXEL" => {
+                focus_target = Some(FocusTarget::Pixel(
+                    parse_token(&tokens, 1, line_number)?,
+                    parse_token(&tokens, 2, line_number)?,
+                ));
+                focus_distance = None;
+            }
+            "SENSOR_ELECTRONS" => sensor_electrons = Some(parse_token(&tokens, 1, line_number)?),
+            "SENSOR_READ_NOISE" => sensor_read_noise = parse_token(&tokens, 1, line_number)?,
+            "SENSOR_RESPONSE" => sensor_response = parse_vector3()?,
+            "NEW_PRIMITIVE" => primitives.push(Primitive::new(Shape::Plane {
+                normal: Default::default(),
+            })),
+            // a new unnamed copy of the last primitive with the given NAME, sharing its
+            // mesh buffers; the lines that follow override its placement or material
+            "INSTANCE" => {
+                let name = token_at(1)?;
+                let source = primitives
+                    .iter()
+                    .rposition(|primitive| primitive.name.as_ref() == Some(name))
+                    .ok_or_else(|| {
+                        line_error(
+                            line_number,
+                            name,
+                            "no primitive with this NAME before this line",
+                        )
+                    })?;
+                let instance = primitives.len();
+                primitives.push(Primitive {
+                    name: None,
+                    ..primitives[source].clone()
+                });
+                // settings resolved after parsing go along with the copy
+                if let Some(&(_, medium)) = media.iter().rfind(|(index, _)| *index == source) {
+                    media.push((instance, medium));
+                }
+                if let Some(&(_, color, photometric)) = photometric_emissions
+                    .iter()
+                    .rfind(|(index, _, _)| *index == source)
+                {
+                    photometric_emissions.push((instance, color, photometric));
+                }
+            }
             "PLANE" => {
-                primitives
-                    .last_mut()
-                    .expect("Input file format error.")
-                    .shape = Shape::Plane {
-                    normal: parse_vector3(),
+                last_primitive(&mut primitives, &tokens, line_number)?.shape = Shape::Plane {
+                    normal: parse_vector3()?,
                 }
             }
             "ELLIPSOID" => {
-                primitives
-                    .last_mut()
-                    .expect("Input file format error.")
-                    .shape = Shape::Ellipsoid { r: parse_vector3() }
+                last_primitive(&mut primitives, &tokens, line_number)?.shape = Shape::Ellipsoid {
+                    r: parse_vector3()?,
+                }
+            }
+            "SPHERE" => {
+                last_primitive(&mut primitives, &tokens, line_number)?.shape = Shape::Sphere {
+                    r: parse_token(&tokens, 1, line_number)?,
+                }
             }
             "BOX" => {
-                primitives
-                    .last_mut()
-                    .expect("Input file format error.")
-                    .shape = Shape::Box { s: parse_vector3() }
+                last_primitive(&mut primitives, &tokens, line_number)?.shape = Shape::Box {
+                    s: parse_vector3()?,
+                }
+            }
+            "CYLINDER" => {
+                last_primitive(&mut primitives, &tokens, line_number)?.shape = Shape::Cylinder {
+                    r: parse_token(&tokens, 1, line_number)?,
+                    h: parse_token(&tokens, 2, line_number)?,
+                }
+            }
+            "CONE" => {
+                last_primitive(&mut primitives, &tokens, line_number)?.shape = Shape::Cone {
+                    r: parse_token(&tokens, 1, line_number)?,
+                    h: parse_token(&tokens, 2, line_number)?,
+                }
+            }
+            "RECTANGLE" => {
+                last_primitive(&mut primitives, &tokens, line_number)?.shape = Shape::Rectangle {
+                    s: Vector2::new(
+                        parse_token(&tokens, 1, line_number)?,
+                        parse_token(&tokens, 2, line_number)?,
+                    ),
+                }
+            }
+            "DISC" => {
+                last_primitive(&mut primitives, &tokens, line_number)?.shape = Shape::Disc {
+                    r: parse_token(&tokens, 1, line_number)?,
+                }
+            }
+            "TRIANGLE_MESH" => {
+                last_primitive(&mut primitives, &tokens, line_number)?.shape = Shape::TriangleMesh {
+                    vertices: Arc::new(vec![]),
+                    triangles: Arc::new(vec![]),
+                    normals: Arc::new(vec![]),
+                    bvh: Default::default(),
+                    area_cdf: Default::default(),
+                }
+            }
+            "MESH_FILE" => {
+                last_primitive(&mut primitives, &tokens, line_number)?.shape =
+                    load_obj(assets, token_at(1)?)
+                        .map_err(|message| line_error(line_number, &tokens[1], &message))?
+            }
+            "VERTEX" => {
+                let Shape::TriangleMesh { vertices, .. } =
+                    &mut last_primitive(&mut primitives, &tokens, line_number)?.shape
+                else {
+                    return Err(line_error(
+                        line_number,
+                        &tokens[0],
+                        "VERTEX requires TRIANGLE_MESH",
+                    ));
+                };
+                Arc::make_mut(vertices).push(parse_vector3()?);
+            }
+            "TRIANGLE" => {
+                let Shape::TriangleMesh { triangles, .. } =
+                    &mut last_primitive(&mut primitives, &tokens, line_number)?.shape
+                else {
+                    return Err(line_error(
+                        line_number,
+                        &tokens[0],
+                        "TRIANGLE requires TRIANGLE_MESH",
+                    ));
+                };
+                Arc::make_mut(triangles).push([
+                    parse_token(&tokens, 1, line_number)?,
+                    parse_token(&tokens, 2, line_number)?,
+                    parse_token(&tokens, 3, line_number)?,
+                ]);
             }
             "POSITION" => {
-                primitives
-                    .last_mut()
-                    .expect("Input file format error.")
-                    .position = parse_vector3()
+                last_primitive(&mut primitives, &tokens, line_number)?.position = parse_vector3()?
             }
             "ROTATION" => {
-                primitives
-                    .last_mut()
-                    .expect("Input file format error.")
-                    .rotation = UnitQuaternion::new_normalize(Quaternion::new(
-                    tokens[4].parse().expect("Input file format error."),
-                    tokens[1].parse().expect("Input file format error."),
-                    tokens[2].parse().expect("Input file format error."),
-                    tokens[3].parse().expect("Input file format error."),
-                ))
+                last_primitive(&mut primitives, &tokens, line_number)?.rotation =
+                    UnitQuaternion::new_normalize(Quaternion::new(
+                        parse_token(&tokens, 4, line_number)?,
+                        parse_token(&tokens, 1, line_number)?,
+                        parse_token(&tokens, 2, line_number)?,
+                        parse_token(&tokens, 3, line_number)?,
+                    ))
             }
             "COLOR" => {
-                primitives
-                    .last_mut()
-                    .expect("Input file format error.")
-                    .color = parse_vector3()
+                last_primitive(&mut primitives, &tokens, line_number)?.color = parse_vector3()?
+            }
+            "TEXTURE" => {
+                let primitive = last_primitive(&mut primitives, &tokens, line_number)?;
+                let color_space = color_space_at(&tokens, 2, line_number, ColorSpace::Srgb)?;
+                let texture =
+                    load_texture(assets, token_at(1)?, color_space).map_err(|message| {
+                        let message = format!("cannot read texture: {}", message);
+                        line_error(line_number, &tokens[1], &message)
+                    })?;
+                primitive.texture = Some(Arc::new(texture));
+            }
+            "UV_SCALE" => {
+                last_primitive(&mut primitives, &tokens, line_number)?
+                    .uv_transform
+                    .scale = Vector2::new(
+                    parse_token(&tokens, 1, line_number)?,
+                    parse_token(&tokens, 2, line_number)?,
+                )
+            }
+            "UV_OFFSET" => {
+                last_primitive(&mut primitives, &tokens, line_number)?
+                    .uv_transform
+                    .offset = Vector2::new(
+                    parse_token(&tokens, 1, line_number)?,
+                    parse_token(&tokens, 2, line_number)?,
+                )
+            }
+            "UV_ROTATE" => {
+                last_primitive(&mut primitives, &tokens, line_number)?
+                    .uv_transform
+                    .rotation = parse_token(&tokens, 1, line_number)?
             }
             "METALLIC" => {
-                primitives
-                    .last_mut()
-                    .expect("Input file format error.")
-                    .material = Material::METALLIC
+                last_primitive(&mut primitives, &tokens, line_number)?.material = Material::METALLIC
+            }
+            "HOLDOUT" => {
+                last_primitive(&mut primitives, &tokens, line_number)?.material = Material::HOLDOUT
             }
             "DIELECTRIC" => {
-                primitives
-                    .last_mut()
-                    .expect("Input file format error.")
-                    .material = Material::DIELECTRIC {
-                    ior: Default::default(),
-                }
+                last_primitive(&mut primitives, &tokens, line_number)?.material =
+                    Material::DIELECTRIC {
+                        ior: Default::default(),
+                        abbe: None,
+                        absorption: Vector3::zeros(),
+                    }
             }
             "IOR" => {
-                primitives
-                    .last_mut()
-                    .expect("Input file format error.")
-                    .material = Material::DIELECTRIC {
-                    ior: tokens[1].parse().expect("Input file format error."),
+                last_primitive(&mut primitives, &tokens, line_number)?.material =
+                    Material::DIELECTRIC {
+                        ior: parse_token(&tokens, 1, line_number)?,
+                        abbe: None,
+                        absorption: Vector3::zeros(),
+                    }
+            }
+            "ABBE" => {
+                let Material::DIELECTRIC { abbe, .. } =
+                    &mut last_primitive(&mut primitives, &tokens, line_number)?.material
+                else {
+                    return Err(line_error(
+                        line_number,
+                        &tokens[0],
+                        "ABBE is only allowed for DIELECTRIC",
+                    ));
+                };
+                *abbe = Some(parse_token(&tokens, 1, line_number)?)
+            }
+            "ABSORPTION" => {
+                let Material::DIELECTRIC { absorption, .. } =
+                    &mut last_primitive(&mut primitives, &tokens, line_number)?.material
+                else {
+                    return Err(line_error(
+                        line_number,
+                        &tokens[0],
+                        "ABSORPTION is only allowed for DIELECTRIC",
+                    ));
+                };
+                *absorption = parse_vector3()?;
+                if absorption.min() < 0.0 {
+                    return Err(line_error(
+                        line_number,
+                        &tokens[0],
+                        "absorption must be non-negative",
+                    ));
+                }
+            }
+            "CAR_PAINT" => {
+                last_primitive(&mut primitives, &tokens, line_number)?.material =
+                    Material::CARPAINT {
+                        flake_size: 0.02,
+                        flake_density: 0.3,
+                        flake_color: Vector3::new(1.0, 1.0, 1.0),
+                    }
+            }
+            "FLAKE_SIZE" => {
+                let Material::CARPAINT { flake_size, .. } =
+                    &mut last_primitive(&mut primitives, &tokens, line_number)?.material
+                else {
+                    return Err(line_error(
+                        line_number,
+                        &tokens[0],
+                        "FLAKE_SIZE is only allowed for CAR_PAINT",
+                    ));
+                };
+                *flake_size = parse_token(&tokens, 1, line_number)?
+            }
+            "FLAKE_DENSITY" => {
+                let Material::CARPAINT { flake_density, .. } =
+                    &mut last_primitive(&mut primitives, &tokens, line_number)?.material
+                else {
+                    return Err(line_error(
+                        line_number,
+                        &tokens[0],
+                        "FLAKE_DENSITY is only allowed for CAR_PAINT",
+                    ));
+                };
+                *flake_density = parse_token(&tokens, 1, line_number)?
+            }
+            "FLAKE_COLOR" => {
+                let Material::CARPAINT { flake_color, .. } =
+                    &mut last_primitive(&mut primitives, &tokens, line_number)?.material
+                else {
+                    return Err(line_error(
+                        line_number,
+                        &tokens[0],
+                        "FLAKE_COLOR is only allowed for CAR_PAINT",
+                    ));
+                };
+                *flake_color = parse_vector3()?
+            }
+            "MIX_MATERIAL" => {
+                let material = match token_at(1)?.as_str() {
+                    "DIFFUSE" => Material::DIFFUSE,
+                    "METALLIC" => Material::METALLIC,
+                    "DIELECTRIC" => Material::DIELECTRIC {
+                        ior: match tokens.get(5) {
+                            Some(_) => parse_token(&tokens, 5, line_number)?,
+                            None => MIX_DEFAULT_IOR,
+                        },
+                        abbe: None,
+                        absorption: Vector3::zeros(),
+                    },
+                    "CAR_PAINT" => Material::CARPAINT {
+                        flake_size: 0.02,
+                        flake_density: 0.3,
+                        flake_color: Vector3::new(1.0, 1.0, 1.0),
+                    },
+                    // the cut-out follows the base material, like emission
+                    "HOLDOUT" => {
+                        return Err(line_error(
+                            line_number,
+                            &tokens[1],
+                            "HOLDOUT can only be the base material",
+                        ));
+                    }
+                    _ => {
+                        return Err(line_error(line_number, &tokens[1], "unknown material"));
+                    }
+                };
+                let color = Vector3::new(
+                    parse_token(&tokens, 2, line_number)?,
+                    parse_token(&tokens, 3, line_number)?,
+                    parse_token(&tokens, 4, line_number)?,
+                );
+                last_primitive(&mut primitives, &tokens, line_number)?.mix = Some(MaterialMix {
+                    material,
+                    color,
+                    weight: MixWeight::Constant(0.5),
+                });
+            }
+            "MIX_WEIGHT" | "MIX_MASK" | "MIX_FRESNEL" => {
+                let weight = match tokens[0].as_str() {
+                    "MIX_WEIGHT" => {
+                        let weight: f64 = parse_token(&tokens, 1, line_number)?;
+                        if !(0.0..=1.0).contains(&weight) {
+                            return Err(line_error(
+                                line_number,
+                                &tokens[1],
+                                "mix weight must be between 0 and 1",
+                            ));
+                        }
+                        MixWeight::Constant(weight)
+                    }
+                    "MIX_MASK" => {
+                        let color_space =
+                            color_space_at(&tokens, 2, line_number, ColorSpace::Linear)?;
+                        let texture =
+                            load_texture(assets, token_at(1)?, color_space).map_err(|message| {
+                                let message = format!("cannot read texture: {}", message);
+                                line_error(line_number, &tokens[1], &message)
+                            })?;
+                        MixWeight::Mask(Arc::new(texture))
+                    }
+                    _ => MixWeight::Fresnel(parse_token(&tokens, 1, line_number)?),
+                };
+                let Some(mix) = &mut last_primitive(&mut primitives, &tokens, line_number)?.mix
+                else {
+                    return Err(line_error(
+                        line_number,
+                        &tokens[0],
+                        "no MIX_MATERIAL before this line",
+                    ));
+                };
+                mix.weight = weight;
+            }
+            "RAY_DEPTH" => ray_depth = Some(parse_token(&tokens, 1, line_number)?),
+            "TRANSPARENT_DEPTH" => transparent_depth = Some(parse_token(&tokens, 1, line_number)?),
+            "AMBIENT_LIGHT" => ambient_light = Some(parse_vector3()?),
+            // a light is directed until given a position
+            "NEW_LIGHT" => lights.push(Light {
+                intensity: Default::default(),
+                light_type: LightType::Directed {
+                    direction: Default::default(),
+                },
+                attenuation: Vector3::new(0.0, 0.0, 1.0),
+            }),
+            "LIGHT_INTENSITY" => {
+                last_light(&mut lights, &tokens, line_number)?.intensity = parse_vector3()?
+            }
+            "LIGHT_DIRECTION" => {
+                last_light(&mut lights, &tokens, line_number)?.light_type = LightType::Directed {
+                    direction: parse_vector3()?,
+                }
+            }
+            "LIGHT_ATTENUATION" => {
+                last_light(&mut lights, &tokens, line_number)?.attenuation = parse_vector3()?
+            }
+            "LIGHT_POSITION" => {
+                last_light(&mut lights, &tokens, line_number)?.light_type = LightType::Point {
+                    position: parse_vector3()?,
+                }
+            }
+            // narrows the point light given by LIGHT_POSITION down to a cone
+            "LIGHT_SPOT" => {
+                let light = last_light(&mut lights, &tokens, line_number)?;
+                let LightType::Point { position } = light.light_type else {
+                    return Err(line_error(
+                        line_number,
+                        &tokens[0],
+                        "no LIGHT_POSITION before this line",
+                    ));
+                };
+                let direction = parse_vector3()?;
+                let inner_angle: f64 = parse_token(&tokens, 4, line_number)?;
+                let outer_angle: f64 = parse_token(&tokens, 5, line_number)?;
+                if direction == Vector3::zeros() {
+                    return Err(line_error(line_number, &tokens[1], "zero spot direction"));
                 }
+                if !(0.0 <= inner_angle && inner_angle <= outer_angle && outer_angle < PI) {
+                    return Err(line_error(
+                        line_number,
+                        &tokens[4],
+                        "spot angles must satisfy 0 <= inner <= outer < pi",
+                    ));
+                }
+                light.light_type = LightType::Spot {
+                    position,
+                    direction,
+                    inner_angle,
+                    outer_angle,
+                };
+            }
+            "SAMPLES" => samples = Some(parse_token(&tokens, 1, line_number)?),
+            "ADAPTIVE_THRESHOLD" => {
+                adaptive_threshold = Some(parse_token(&tokens, 1, line_number)?)
+            }
+            "TIME_BUDGET" => time_budget = Some(parse_token(&tokens, 1, line_number)?),
+            "CLAMP" => clamp = Some(parse_token(&tokens, 1, line_number)?),
+            "MEDIAN_OF_MEANS" => median_of_means = Some(parse_token(&tokens, 1, line_number)?),
+            "PIXEL_FILTER" => {
+                token_at(1)?;
+                pixel_filter = parse_pixel_filter(&tokens[1..])
+                    .ok_or_else(|| line_error(line_number, &tokens[1], "invalid pixel filter"))?
+            }
+            "RNG" => {
+                rng_backend = parse_rng_backend(token_at(1)?)
+                    .ok_or_else(|| line_error(line_number, &tokens[1], "unknown RNG backend"))?
+            }
+            "SAMPLER" => {
+                sampler_type = parse_sampler_type(token_at(1)?)
+                    .ok_or_else(|| line_error(line_number, &tokens[1], "unknown sampler"))?
+            }
+            "MAX_PDF_RATIO" => max_pdf_ratio = Some(parse_token(&tokens, 1, line_number)?),
+            "ROULETTE_DEPTH" => roulette_depth = Some(parse_token(&tokens, 1, line_number)?),
+            "SEED" => seed = Some(parse_token(&tokens, 1, line_number)?),
+            "SCENE_SCALE" => scene_scale = parse_token(&tokens, 1, line_number)?,
+            "SCENE_UP_AXIS" => {
+                scene_up_rotation = parse_up_axis_rotation(token_at(1)?)
+                    .ok_or_else(|| line_error(line_number, &tokens[1], "unknown up axis"))?
+            }
+            "PROBE" => probes.push(parse_vector3()?),
+            "PROBE_SAMPLES" => probe_samples = parse_token(&tokens, 1, line_number)?,
+            "FOG" => fog = Some(parse_medium(&tokens, line_number)?),
+            "MEDIUM" => {
+                last_primitive(&mut primitives, &tokens, line_number)?;
+                media.push((primitives.len() - 1, parse_medium(&tokens, line_number)?))
             }
-            "RAY_DEPTH" => ray_depth = Some(tokens[1].parse().expect("Input file format error.")),
-            "AMBIENT_LIGHT" => ambient_light = Some(parse_vector3()),
-            "SAMPLES" => samples = Some(tokens[1].parse().expect("Input file format error.")),
             "EMISSION" => {
-                primitives
-                    .last_mut()
-                    .expect("Input file format error.")
-                    .emission = parse_vector3()
+                last_primitive(&mut primitives, &tokens, line_number)?.emission = parse_vector3()?
+            }
+            "NAME" => {
+                last_primitive(&mut primitives, &tokens, line_number)?.name =
+                    Some(token_at(1)?.clone())
+            }
+            "LIGHT_LINK_INCLUDE" => {
+                last_primitive(&mut primitives, &tokens, line_number)?.light_link =
+                    LightLink::Include(tokens[1..].to_vec())
             }
-            _ => {}
+            "LIGHT_LINK_EXCLUDE" => {
+                last_primitive(&mut primitives, &tokens, line_number)?.light_link =
+                    LightLink::Exclude(tokens[1..].to_vec())
+            }
+            "EMISSION_LUMENS" | "EMISSION_CANDELA" | "EMISSION_NITS" => {
+                let value = parse_token(&tokens, 4, line_number)?;
+                let color = parse_vector3()?;
+                // the color is scaled to the luminance, so it needs some of its own
+                if color_luminance(&color) <= 0.0 {
+                    return Err(line_error(
+                        line_number,
+                        &tokens[0],
+                        "photometric emission color must have a positive luminance",
+                    ));
+                }
+                last_primitive(&mut primitives, &tokens, line_number)?;
+                photometric_emissions.push((
+                    primitives.len() - 1,
+                    color,
+                    match tokens[0].as_str() {
+                        "EMISSION_LUMENS" => PhotometricEmission::Lumens(value),
+                        "EMISSION_CANDELA" => PhotometricEmission::Candela(value),
+                        _ => PhotometricEmission::Nits(value),
+                    },
+                ))
+            }
+            "EMISSION_RADIAL" => {
+                last_primitive(&mut primitives, &tokens, line_number)?.emission_gradient =
+                    EmissionGradient::Radial {
+                        space: parse_gradient_space(token_at(1)?).ok_or_else(|| {
+                            line_error(line_number, &tokens[1], "unknown gradient space")
+                        })?,
+                        center: parse_vector3_at(&tokens, 2, line_number)?,
+                        radius: parse_token(&tokens, 5, line_number)?,
+                        inner_scale: parse_token(&tokens, 6, line_number)?,
+                        outer_scale: parse_token(&tokens, 7, line_number)?,
+                    }
+            }
+            "EMISSION_LINEAR" => {
+                last_primitive(&mut primitives, &tokens, line_number)?.emission_gradient =
+                    EmissionGradient::Linear {
+                        space: parse_gradient_space(token_at(1)?).ok_or_else(|| {
+                            line_error(line_number, &tokens[1], "unknown gradient space")
+                        })?,
+                        start: parse_vector3_at(&tokens, 2, line_number)?,
+                        end: parse_vector3_at(&tokens, 5, line_number)?,
+                        start_scale: parse_token(&tokens, 8, line_number)?,
+                        end_scale: parse_token(&tokens, 9, line_number)?,
+                    }
+            }
+            // lines starting with # are comments
+            keyword if keyword.starts_with('#') => {}
+            keyword => unknown_keywords.push((line_number, keyword.to_string())),
         }
     }
 
-    let width = width.expect("Width is not specified in input file.");
-    let height = height.expect("Height is not specified in input file.");
-    let fov_x = fov_x.expect("FOVx is not specified in input file.");
+    let width = width.ok_or_else(|| scene_error("Width is not specified in input file"))?;
+    let height = height.ok_or_else(|| scene_error("Height is not specified in input file"))?;
+    let fov_x = match camera_type {
+        CameraType::Perspective | CameraType::Fisheye => {
+            fov_x.ok_or_else(|| scene_error("FOVx is not specified in input file"))?
+        }
+        CameraType::Orthographic { .. } | CameraType::Equirectangular => fov_x.unwrap_or(PI / 2.0),
+    };
+    let ray_depth =
+        ray_depth.ok_or_else(|| scene_error("Ray depth is not specified in input file"))?;
+    let exposure = if iso.is_some() || shutter.is_some() || f_stop.is_some() {
+        photographic_exposure(
+            iso.unwrap_or(100.0),
+            shutter.unwrap_or(1.0 / 125.0),
+            f_stop.unwrap_or(16.0),
+        )
+    } else {
+        1.0
+    };
+    // a pinhole is in focus everywhere, so the distance only matters with a lens
+    let focus_distance = match focus_distance {
+        Some(focus_distance) => focus_distance,
+        // replaced once the scene can be ray cast
+        None if focus_target.is_some() => 1.0,
+        None if aperture > 0.0 => {
+            return Err(scene_error(
+                "Focus distance is not specified for a camera with an aperture",
+            ))
+        }
+        None => 1.0,
+    };
+    if let Some(starfield) = &starfield {
+        environment_map = Some(Arc::new(generate_starfield(starfield)));
+    }
 
-    Scene {
+    let mut scene = Scene {
         width,
         height,
+        // an environment map makes the constant background optional
         background_color: background_color
-            .expect("Background color is not specified in input file."),
+            .or(environment_map.as_ref().map(|_| Vector3::zeros()))
+            .ok_or_else(|| scene_error("Background color is not specified in input file"))?,
+        environment_map,
+        fog: None,
         camera: Camera {
-            position: position.expect("Position is not specified in input file."),
-            right_axis: right_axis.expect("Right axis is not specified in input file."),
-            up_axis: up_axis.expect("Up axis is not specified in input file."),
-            forward_axis: forward_axis.expect("Forward axis is not specified in input file."),
+            camera_type,
+            position: position
+                .ok_or_else(|| scene_error("Position is not specified in input file"))?,
+            right_axis: right_axis
+                .ok_or_else(|| scene_error("Right axis is not specified in input file"))?,
+            up_axis: up_axis
+                .ok_or_else(|| scene_error("Up axis is not specified in input file"))?,
+            forward_axis: forward_axis
+                .ok_or_else(|| scene_error("Forward axis is not specified in input file"))?,
             fov_x,
-            fov_y: 2.0 * ((fov_x / 2.0).tan() * height as f64 / width as f64).atan(),
+            fov_y: vertical_fov(fov_x, width, height),
+            aperture,
+            aperture_mask,
+            focus_distance,
+            exposure,
+            sensor: sensor_electrons.map(|electrons| Sensor {
+                electrons: electrons * 100.0 / iso.unwrap_or(100.0),
+                read_noise: sensor_read_noise,
+                response: sensor_response,
+            }),
         },
         primitives,
-        ray_depth: ray_depth.expect("Ray depth is not specified in input file."),
-        ambient_light: ambient_light.expect("Ambient light is not specified in input file."),
-        samples: samples.expect("Samples number is not specified in input file.")
+        lights,
+        ray_depth,
+        transparent_depth,
+        ambient_light: ambient_light
+            .ok_or_else(|| scene_error("Ambient light is not specified in input file"))?,
+        samples: samples
+            .ok_or_else(|| scene_error("Samples number is not specified in input file"))?,
+        adaptive_threshold,
+        time_budget,
+        clamp,
+        median_of_means,
+        pixel_filter,
+        rng_backend,
+        seed,
+        sampler_type,
+        max_pdf_ratio,
+        roulette_depth,
+        probes,
+        probe_samples,
+        unknown_keywords,
+        bvh: Default::default(),
+    };
+    validate_scene(&scene)?;
+    // lookups into the map stay in its own frame, with its pole along the scene's y
+    if scene_up_rotation != UnitQuaternion::identity() && scene.environment_map.is_some() {
+        return Err(scene_error(
+            "SCENE_UP_AXIS other than Y is not supported with an ENVIRONMENT_MAP",
+        ));
+    }
+    apply_scene_transform(&mut scene, scene_scale, &scene_up_rotation);
+
+    // coefficients are per unit length, so the scene scale thins media out
+    let scaled_medium = |medium: HomogeneousMedium| -> Arc<dyn Medium> {
+        Arc::new(HomogeneousMedium {
+            sigma_a: medium.sigma_a / scene_scale,
+            sigma_s: medium.sigma_s / scene_scale,
+            ..medium
+        })
+    };
+    scene.fog = fog.map(scaled_medium);
+    for (index, medium) in media {
+        scene.primitives[index].medium = Some(scaled_medium(medium));
+    }
+
+    // resolved after parsing since the shape may be given after the emission,
+    // and after the scene transform since it changes the emitter's area
+    for (index, color, photometric) in photometric_emissions {
+        let primitive = &mut scene.primitives[index];
+        if let Shape::Plane { normal: _ } = primitive.shape {
+            return Err(scene_error("Photometric emission requires a bounded shape"));
+        }
+        primitive.emission = photometric_to_radiance(&color, &photometric, &primitive.shape);
+    }
+
+    build_mesh_bvhs(&mut scene.primitives);
+    scene.bvh = Bvh::build(&scene.primitives);
+    if let Some(target) = focus_target {
+        scene.camera.focus_distance = autofocus_distance(&scene, &target)?;
+    }
+
+    Ok(scene)
+}
+
+// Every keyword parse_scene knows, to suggest one for a misspelled keyword.
+const KEYWORDS: &[&str] = &[
+    "DIMENSIONS",
+    "BG_COLOR",
+    "ENVIRONMENT_MAP",
+    "STARFIELD",
+    "STARFIELD_BAND",
+    "STARFIELD_SEED",
+    "CAMERA_TYPE",
+    "CAMERA_POSITION",
+    "CAMERA_RIGHT",
+    "CAMERA_UP",
+    "CAMERA_FORWARD",
+    "CAMERA_FOV_X",
+    "CAMERA_ISO",
+    "CAMERA_SHUTTER",
+    "CAMERA_FSTOP",
+    "CAMERA_APERTURE",
+    "CAMERA_APERTURE_MASK",
+    "CAMERA_FOCUS_DIST",
+    "CAMERA_FOCUS_OBJECT",
+    "CAMERA_FOCUS_PIXEL",
+    "SENSOR_ELECTRONS",
+    "SENSOR_READ_NOISE",
+    "SENSOR_RESPONSE",
+    "NEW_PRIMITIVE",
+    "INSTANCE",
+    "PLANE",
+    "ELLIPSOID",
+    "SPHERE",
+    "BOX",
+    "CYLINDER",
+    "CONE",
+    "RECTANGLE",
+    "DISC",
+    "TRIANGLE_MESH",
+    "MESH_FILE",
+    "VERTEX",
+    "TRIANGLE",
+    "POSITION",
+    "ROTATION",
+    "COLOR",
+    "TEXTURE",
+    "UV_SCALE",
+    "UV_OFFSET",
+    "UV_ROTATE",
+    "METALLIC",
+    "HOLDOUT",
+    "DIELECTRIC",
+    "IOR",
+    "ABBE",
+    "ABSORPTION",
+    "CAR_PAINT",
+    "FLAKE_SIZE",
+    "FLAKE_DENSITY",
+    "FLAKE_COLOR",
+    "MIX_MATERIAL",
+    "MIX_WEIGHT",
+    "MIX_MASK",
+    "MIX_FRESNEL",
+    "RAY_DEPTH",
+    "TRANSPARENT_DEPTH",
+    "AMBIENT_LIGHT",
+    "NEW_LIGHT",
+    "LIGHT_INTENSITY",
+    "LIGHT_DIRECTION",
+    "LIGHT_ATTENUATION",
+    "LIGHT_POSITION",
+    "LIGHT_SPOT",
+    "SAMPLES",
+    "ADAPTIVE_THRESHOLD",
+    "TIME_BUDGET",
+    "CLAMP",
+    "MEDIAN_OF_MEANS",
+    "PIXEL_FILTER",
+    "RNG",
+    "SAMPLER",
+    "MAX_PDF_RATIO",
+    "ROULETTE_DEPTH",
+    "SEED",
+    "SCENE_SCALE",
+    "SCENE_UP_AXIS",
+    "PROBE",
+    "PROBE_SAMPLES",
+    "FOG",
+    "MEDIUM",
+    "EMISSION",
+    "NAME",
+    "LIGHT_LINK_INCLUDE",
+    "LIGHT_LINK_EXCLUDE",
+    "EMISSION_LUMENS",
+    "EMISSION_CANDELA",
+    "EMISSION_NITS",
+    "EMISSION_RADIAL",
+    "EMISSION_LINEAR",
+    "$define",
+];
+
+// Levenshtein distance, the number of single character insertions, deletions and
+// substitutions that turn one string into the other.
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut previous: Vec<usize> = (0..=b.len()).collect();
+    for (i, a_char) in a.chars().enumerate() {
+        let mut current = vec![i + 1];
+        for (j, b_char) in b.iter().enumerate() {
+            let substitution = previous[j] + (a_char != *b_char) as usize;
+            current.push(substitution.min(previous[j + 1] + 1).min(current[j] + 1));
+        }
+        previous = current;
+    }
+    previous[b.len()]
+}
+
+// The keyword closest to a misspelled one, if it is close enough to be a typo: about
+// one edit for every three characters, ignoring case.
+fn closest_keyword(token: &str) -> Option<&'static str> {
+    let token = token.to_uppercase();
+    KEYWORDS
+        .iter()
+        .map(|keyword| (edit_distance(&token, &keyword.to_uppercase()), *keyword))
+        .min()
+        .filter(|(distance, keyword)| *distance <= (keyword.len() / 3).max(1))
+        .map(|(_, keyword)| keyword)
+}
+
+// Lines parse_scene skipped, as errors so that they can be reported as warnings or
+// fail the scene.
+pub fn unknown_keyword_errors(scene: &Scene) -> Vec<SceneParseError> {
+    scene
+        .unknown_keywords
+        .iter()
+        .map(|(line, token)| {
+            let message = match closest_keyword(token) {
+                Some(keyword) => format!("unknown keyword, did you mean {}", keyword),
+                None => "unknown keyword".to_string(),
+            };
+            line_error(*line, token, &message)
+        })
+        .collect()
+}
+
+// Settings that parse fine but may not do what a scene written for another renderer
+// expects.
+pub fn scene_warnings(scene: &Scene) -> Vec<String> {
+    let mut warnings: Vec<String> = unknown_keyword_errors(scene)
+        .iter()
+        .map(|error| error.to_string())
+        .collect();
+    if scene.ambient_light != Vector3::zeros() {
+        warnings.push(
+            "AMBIENT_LIGHT is light from every direction that surfaces receive unless \
+             occluded, it is not added to their color and the camera does not see it"
+                .to_string(),
+        );
+        if scene.environment_map.is_some() {
+            warnings.push("AMBIENT_LIGHT adds to the light of ENVIRONMENT_MAP".to_string());
+        }
+    }
+    let medium_outside_dielectric = scene.primitives.iter().any(|primitive| {
+        primitive.medium.is_some() && !matches!(primitive.material, Material::DIELECTRIC { .. })
+    });
+    if medium_outside_dielectric {
+        warnings.push("MEDIUM only fills the inside of DIELECTRIC primitives".to_string());
+    }
+    let wide_camera = matches!(
+        scene.camera.camera_type,
+        CameraType::Fisheye | CameraType::Equirectangular
+    );
+    if wide_camera && scene.camera.aperture > 0.0 {
+        warnings
+            .push("CAMERA_APERTURE is ignored by FISHEYE and EQUIRECTANGULAR cameras".to_string());
+    }
+    if scene.camera.aperture_mask.is_some() && scene.camera.aperture <= 0.0 {
+        warnings.push("CAMERA_APERTURE_MASK has no effect without CAMERA_APERTURE".to_string());
+    }
+    warnings
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BASE_SCENE: &str = "
+DIMENSIONS 4 3
+BG_COLOR 0 0 0
+CAMERA_POSITION 0 0 0
+CAMERA_RIGHT 1 0 0
+CAMERA_UP 0 1 0
+CAMERA_FORWARD 0 0 1
+CAMERA_FOV_X 1
+RAY_DEPTH 2
+SAMPLES 1
+NEW_PRIMITIVE
+PLANE 0 1 0
+NEW_LIGHT
+LIGHT_DIRECTION 0 -1 0
+";
+
+    // Lines parse_scene skips, or None when the scene fails, which only known keywords
+    // can make it do.
+    fn unknown_keywords_after(line: &str) -> Option<Vec<(usize, String)>> {
+        let assets = Assets::FileSystem { roots: vec![] };
+        parse_scene(format!("{}{}\n", BASE_SCENE, line), &assets)
+            .ok()
+            .map(|scene| scene.unknown_keywords)
+    }
+
+    #[test]
+    fn unknown_keywords_are_collected() {
+        assert_eq!(
+            unknown_keywords_after("EMISION 1 1 1"),
+            Some(vec![(15, "EMISION".to_string())])
+        );
+    }
+
+    #[test]
+    fn every_suggested_keyword_is_parsed() {
+        assert_eq!(unknown_keywords_after(""), Some(vec![]));
+        for keyword in KEYWORDS {
+            let unknown = unknown_keywords_after(keyword);
+            assert!(
+                unknown.is_none_or(|unknown| unknown.is_empty()),
+                "{} is not parsed",
+                keyword
+            );
+        }
     }
 }
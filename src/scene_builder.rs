@@ -0,0 +1,191 @@
+use std::f64::consts::PI;
+use std::sync::Arc;
+
+use nalgebra::Vector3;
+
+use crate::bvh::{build_mesh_bvhs, Bvh};
+use crate::filter::PixelFilter;
+use crate::medium::Medium;
+use crate::rng::RngBackend;
+use crate::sampler::SamplerType;
+use crate::scene::{
+    validate_scene, vertical_fov, Camera, CameraType, Light, Primitive, Scene, SceneParseError,
+};
+
+// Scenes put together in code. Anything not set keeps the default of the text format,
+// and the settings the format requires start as a 640x480 image seen through a 90°
+// pinhole camera at the origin looking along +Z, with 16 samples and a ray depth of 6.
+pub struct SceneBuilder {
+    scene: Scene,
+}
+
+impl Default for SceneBuilder {
+    fn default() -> Self {
+        SceneBuilder::new()
+    }
+}
+
+impl SceneBuilder {
+    pub fn new() -> SceneBuilder {
+        SceneBuilder {
+            scene: Scene {
+                width: 640,
+                height: 480,
+                background_color: Vector3::zeros(),
+                environment_map: None,
+                fog: None,
+                camera: Camera {
+                    camera_type: CameraType::Perspective,
+                    position: Vector3::zeros(),
+                    right_axis: Vector3::x(),
+                    up_axis: Vector3::y(),
+                    forward_axis: Vector3::z(),
+                    fov_x: PI / 2.0,
+                    fov_y: 0.0,
+                    aperture: 0.0,
+                    aperture_mask: None,
+                    focus_distance: 1.0,
+                    exposure: 1.0,
+                    sensor: None,
+                },
+                primitives: vec![],
+                lights: vec![],
+                ray_depth: 6,
+                transparent_depth: None,
+                ambient_light: Vector3::zeros(),
+                samples: 16,
+                adaptive_threshold: None,
+                time_budget: None,
+                clamp: None,
+                median_of_means: None,
+                pixel_filter: PixelFilter::Center,
+                rng_backend: RngBackend::Thread,
+                seed: None,
+                sampler_type: SamplerType::Stratified,
+                max_pdf_ratio: None,
+                roulette_depth: None,
+                probes: vec![],
+                probe_samples: 1024,
+                unknown_keywords: vec![],
+                bvh: Default::default(),
+            },
+        }
+    }
+
+    pub fn dimensions(mut self, width: u32, height: u32) -> SceneBuilder {
+        self.scene.width = width;
+        self.scene.height = height;
+        self
+    }
+
+    // Pinhole camera, the vertical field of view follows from the image dimensions.
+    pub fn camera(
+        mut self,
+        position: Vector3<f64>,
+        right_axis: Vector3<f64>,
+        up_axis: Vector3<f64>,
+        forward_axis: Vector3<f64>,
+        fov_x: f64,
+    ) -> SceneBuilder {
+        self.scene.camera = Camera {
+            position,
+            right_axis,
+            up_axis,
+            forward_axis,
+            fov_x,
+            ..self.scene.camera
+        };
+        self
+    }
+
+    // Projection of the camera set with camera(), perspective unless changed.
+    pub fn camera_type(mut self, camera_type: CameraType) -> SceneBuilder {
+        self.scene.camera.camera_type = camera_type;
+        self
+    }
+
+    pub fn background_color(mut self, color: Vector3<f64>) -> SceneBuilder {
+        self.scene.background_color = color;
+        self
+    }
+
+    // Participating medium filling the space between surfaces.
+    pub fn fog(mut self, fog: Arc<dyn Medium>) -> SceneBuilder {
+        self.scene.fog = Some(fog);
+        self
+    }
+
+    pub fn ambient_light(mut self, light: Vector3<f64>) -> SceneBuilder {
+        self.scene.ambient_light = light;
+        self
+    }
+
+    pub fn samples(mut self, samples: u32) -> SceneBuilder {
+        self.scene.samples = samples;
+        self
+    }
+
+    // Pixels stop once their relative standard error drops below the threshold, taking
+    // at most the number of samples set.
+    pub fn adaptive_threshold(mut self, threshold: f64) -> SceneBuilder {
+        self.scene.adaptive_threshold = Some(threshold);
+        self
+    }
+
+    // Stops the render after `seconds`, sampling the tiles with the largest error first.
+    pub fn time_budget(mut self, seconds: f64) -> SceneBuilder {
+        self.scene.time_budget = Some(seconds);
+        self
+    }
+
+    // Scales down scattered rays brighter than `clamp`, trading a little energy for
+    // fewer fireflies.
+    pub fn clamp(mut self, clamp: f64) -> SceneBuilder {
+        self.scene.clamp = Some(clamp);
+        self
+    }
+
+    // Pixels take the median of the means of this many groups of their samples, which
+    // rejects the rare very bright ones.
+    pub fn median_of_means(mut self, groups: u32) -> SceneBuilder {
+        self.scene.median_of_means = Some(groups);
+        self
+    }
+
+    pub fn ray_depth(mut self, ray_depth: u32) -> SceneBuilder {
+        self.scene.ray_depth = ray_depth;
+        self
+    }
+
+    // Limits refraction separately, RAY_DEPTH applies when this is not set.
+    pub fn transparent_depth(mut self, transparent_depth: u32) -> SceneBuilder {
+        self.scene.transparent_depth = Some(transparent_depth);
+        self
+    }
+
+    // Fixes the random numbers, so that the same scene always renders the same image.
+    pub fn seed(mut self, seed: u64) -> SceneBuilder {
+        self.scene.seed = Some(seed);
+        self
+    }
+
+    pub fn add_primitive(mut self, primitive: Primitive) -> SceneBuilder {
+        self.scene.primitives.push(primitive);
+        self
+    }
+
+    pub fn add_light(mut self, light: Light) -> SceneBuilder {
+        self.scene.lights.push(light);
+        self
+    }
+
+    // Fails on the settings a parsed scene would be rejected for.
+    pub fn build(self) -> Result<Scene, SceneParseError> {
+        let mut scene = self.scene;
+        validate_scene(&scene)?;
+        scene.camera.fov_y = vertical_fov(scene.camera.fov_x, scene.width, scene.height);
+        build_mesh_bvhs(&mut scene.primitives);
+        scene.bvh = Bvh::build(&scene.primitives);
+        Ok(scene)
+    }
+}
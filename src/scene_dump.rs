@@ -0,0 +1,372 @@
+use std::fmt::Write;
+
+use nalgebra::Vector3;
+
+use crate::geometry::Shape;
+use crate::scene::{
+    CameraType, Light, LightLink, LightType, Material, MaterialMix, MixWeight, Primitive, Scene,
+};
+
+fn format_vector3(v: &Vector3<f64>) -> String {
+    format!("({}, {}, {})", v.x, v.y, v.z)
+}
+
+fn describe_shape(shape: &Shape) -> String {
+    match shape {
+        Shape::Plane { normal } => format!("PLANE normal={}", format_vector3(normal)),
+        Shape::Ellipsoid { r } => format!("ELLIPSOID r={}", format_vector3(r)),
+        Shape::Sphere { r } => format!("SPHERE r={}", r),
+        Shape::Box { s } => format!("BOX s={}", format_vector3(s)),
+        Shape::Cylinder { r, h } => format!("CYLINDER r={} h={}", r, h),
+        Shape::Cone { r, h } => format!("CONE r={} h={}", r, h),
+        Shape::Rectangle { s } => format!("RECTANGLE s=({}, {})", s.x, s.y),
+        Shape::Disc { r } => format!("DISC r={}", r),
+        Shape::TriangleMesh {
+            vertices,
+            triangles,
+            normals: _,
+            bvh: _,
+            area_cdf: _,
+        } => format!(
+            "TRIANGLE_MESH vertices={} triangles={}",
+            vertices.len(),
+            triangles.len()
+        ),
+    }
+}
+
+fn describe_camera_type(camera_type: &CameraType) -> String {
+    match camera_type {
+        CameraType::Perspective => "PERSPECTIVE".to_string(),
+        CameraType::Orthographic { width } => format!("ORTHOGRAPHIC width={}", width),
+        CameraType::Fisheye => "FISHEYE".to_string(),
+        CameraType::Equirectangular => "EQUIRECTANGULAR".to_string(),
+    }
+}
+
+fn describe_material(material: &Material) -> String {
+    match material {
+        Material::METALLIC => "METALLIC".to_string(),
+        Material::DIELECTRIC {
+            ior,
+            abbe,
+            absorption,
+        } => {
+            let mut description = format!("DIELECTRIC ior={}", ior);
+            if let Some(abbe) = abbe {
+                write!(description, " abbe={}", abbe).unwrap();
+            }
+            if *absorption != Vector3::zeros() {
+                write!(description, " absorption={}", format_vector3(absorption)).unwrap();
+            }
+            description
+        }
+        Material::DIFFUSE => "DIFFUSE".to_string(),
+        Material::HOLDOUT => "HOLDOUT".to_string(),
+        Material::CARPAINT {
+            flake_size,
+            flake_density,
+            flake_color,
+        } => format!(
+            "CAR_PAINT flake_size={} flake_density={} flake_color={}",
+            flake_size,
+            flake_density,
+            format_vector3(flake_color)
+        ),
+    }
+}
+
+fn describe_mix(mix: &Option<MaterialMix>) -> String {
+    let Some(mix) = mix else {
+        return "none".to_string();
+    };
+    let weight = match &mix.weight {
+        MixWeight::Constant(weight) => format!("weight={}", weight),
+        MixWeight::Mask(texture) => format!("mask={}x{}", texture.width, texture.height),
+        MixWeight::Fresnel(ior) => format!("fresnel ior={}", ior),
+    };
+    format!(
+        "{} color={} {}",
+        describe_material(&mix.material),
+        format_vector3(&mix.color),
+        weight
+    )
+}
+
+fn describe_light(light: &Light) -> String {
+    match light.light_type {
+        LightType::Point { position } => format!(
+            "POINT position={} intensity={} attenuation={}",
+            format_vector3(&position),
+            format_vector3(&light.intensity),
+            format_vector3(&light.attenuation)
+        ),
+        LightType::Directed { direction } => format!(
+            "DIRECTED direction={} intensity={}",
+            format_vector3(&direction),
+            format_vector3(&light.intensity)
+        ),
+        LightType::Spot {
+            position,
+            direction,
+            inner_angle,
+            outer_angle,
+        } => format!(
+            "SPOT position={} direction={} angles={} {} intensity={} attenuation={}",
+            format_vector3(&position),
+            format_vector3(&direction),
+            inner_angle,
+            outer_angle,
+            format_vector3(&light.intensity),
+            format_vector3(&light.attenuation)
+        ),
+    }
+}
+
+fn describe_light_link(light_link: &LightLink) -> String {
+    match light_link {
+        LightLink::All => "all".to_string(),
+        LightLink::Include(names) => format!("include {}", names.join(" ")),
+        LightLink::Exclude(names) => format!("exclude {}", names.join(" ")),
+    }
+}
+
+fn is_emitter(primitive: &Primitive) -> bool {
+    primitive.emission != Vector3::zeros()
+}
+
+fn escape(text: &str) -> String {
+    text.replace('\\', "\\\\").replace('"', "\\\"")
+}
+
+pub fn dump_scene_graph_dot(scene: &Scene) -> String {
+    let mut dot = String::new();
+    writeln!(dot, "digraph scene {{").unwrap();
+    writeln!(dot, "    node [shape=box, fontname=monospace];").unwrap();
+    writeln!(
+        dot,
+        "    scene [label=\"Scene\\n{}x{}\\nsamples {}\\nray depth {}\\nbackground {}\"];",
+        scene.width,
+        scene.height,
+        scene.samples,
+        scene.ray_depth,
+        format_vector3(&scene.background_color)
+    )
+    .unwrap();
+    writeln!(
+        dot,
+        "    camera [label=\"Camera\\nposition {}\\nforward {}\\nfov_x {}\\nexposure {}\"];",
+        format_vector3(&scene.camera.position),
+        format_vector3(&scene.camera.forward_axis),
+        scene.camera.fov_x,
+        scene.camera.exposure
+    )
+    .unwrap();
+    writeln!(dot, "    scene -> camera;").unwrap();
+
+    for (index, primitive) in scene.primitives.iter().enumerate() {
+        let style = if is_emitter(primitive) {
+            ", style=filled, fillcolor=lightyellow"
+        } else {
+            ""
+        };
+        writeln!(
+            dot,
+            "    primitive_{} [label=\"#{} {}\\n{}\\nposition {}\\nemission {}\\nlight link {}\"{}];",
+            index,
+            index,
+            escape(primitive.name.as_deref().unwrap_or("")),
+            describe_shape(&primitive.shape),
+            format_vector3(&primitive.position),
+            format_vector3(&primitive.emission),
+            escape(&describe_light_link(&primitive.light_link)),
+            style
+        )
+        .unwrap();
+        writeln!(
+            dot,
+            "    material_{} [shape=ellipse, label=\"{}\\ncolor {}\\nmix {}\"];",
+            index,
+            describe_material(&primitive.material),
+            format_vector3(&primitive.color),
+            describe_mix(&primitive.mix)
+        )
+        .unwrap();
+        writeln!(dot, "    scene -> primitive_{};", index).unwrap();
+        writeln!(dot, "    primitive_{} -> material_{};", index, index).unwrap();
+    }
+    for (index, light) in scene.lights.iter().enumerate() {
+        writeln!(
+            dot,
+            "    light_{} [shape=diamond, style=filled, fillcolor=lightyellow, label=\"light #{}\\n{}\"];",
+            index,
+            index,
+            describe_light(light)
+        )
+        .unwrap();
+        writeln!(dot, "    scene -> light_{};", index).unwrap();
+    }
+    writeln!(dot, "}}").unwrap();
+    dot
+}
+
+// JSON has no NaN or infinity, so those are written as null.
+pub(crate) fn json_number(value: f64) -> String {
+    if value.is_finite() {
+        value.to_string()
+    } else {
+        "null".to_string()
+    }
+}
+
+fn json_vector3(v: &Vector3<f64>) -> String {
+    format!(
+        "[{}, {}, {}]",
+        json_number(v.x),
+        json_number(v.y),
+        json_number(v.z)
+    )
+}
+
+pub(crate) fn json_string(text: &str) -> String {
+    format!("\"{}\"", escape(text))
+}
+
+pub fn dump_scene_graph_json(scene: &Scene) -> String {
+    let mut json = String::new();
+    writeln!(json, "{{").unwrap();
+    writeln!(json, "  \"width\": {},", scene.width).unwrap();
+    writeln!(json, "  \"height\": {},", scene.height).unwrap();
+    writeln!(json, "  \"samples\": {},", scene.samples).unwrap();
+    writeln!(json, "  \"ray_depth\": {},", scene.ray_depth).unwrap();
+    writeln!(
+        json,
+        "  \"background_color\": {},",
+        json_vector3(&scene.background_color)
+    )
+    .unwrap();
+    writeln!(json, "  \"camera\": {{").unwrap();
+    writeln!(
+        json,
+        "    \"type\": {},",
+        json_string(&describe_camera_type(&scene.camera.camera_type))
+    )
+    .unwrap();
+    writeln!(
+        json,
+        "    \"position\": {},",
+        json_vector3(&scene.camera.position)
+    )
+    .unwrap();
+    writeln!(
+        json,
+        "    \"right\": {},",
+        json_vector3(&scene.camera.right_axis)
+    )
+    .unwrap();
+    writeln!(json, "    \"up\": {},", json_vector3(&scene.camera.up_axis)).unwrap();
+    writeln!(
+        json,
+        "    \"forward\": {},",
+        json_vector3(&scene.camera.forward_axis)
+    )
+    .unwrap();
+    writeln!(json, "    \"fov_x\": {},", json_number(scene.camera.fov_x)).unwrap();
+    writeln!(json, "    \"fov_y\": {},", json_number(scene.camera.fov_y)).unwrap();
+    writeln!(
+        json,
+        "    \"aperture\": {},",
+        json_number(scene.camera.aperture)
+    )
+    .unwrap();
+    writeln!(
+        json,
+        "    \"focus_distance\": {},",
+        json_number(scene.camera.focus_distance)
+    )
+    .unwrap();
+    writeln!(
+        json,
+        "    \"exposure\": {}",
+        json_number(scene.camera.exposure)
+    )
+    .unwrap();
+    writeln!(json, "  }},").unwrap();
+    writeln!(json, "  \"primitives\": [").unwrap();
+    for (index, primitive) in scene.primitives.iter().enumerate() {
+        let rotation = primitive.rotation;
+        writeln!(json, "    {{").unwrap();
+        writeln!(json, "      \"index\": {},", index).unwrap();
+        writeln!(
+            json,
+            "      \"name\": {},",
+            primitive
+                .name
+                .as_deref()
+                .map_or("null".to_string(), json_string)
+        )
+        .unwrap();
+        writeln!(
+            json,
+            "      \"shape\": {},",
+            json_string(&describe_shape(&primitive.shape))
+        )
+        .unwrap();
+        writeln!(
+            json,
+            "      \"position\": {},",
+            json_vector3(&primitive.position)
+        )
+        .unwrap();
+        writeln!(
+            json,
+            "      \"rotation\": [{}, {}, {}, {}],",
+            json_number(rotation.i),
+            json_number(rotation.j),
+            json_number(rotation.k),
+            json_number(rotation.w)
+        )
+        .unwrap();
+        writeln!(
+            json,
+            "      \"material\": {},",
+            json_string(&describe_material(&primitive.material))
+        )
+        .unwrap();
+        writeln!(json, "      \"color\": {},", json_vector3(&primitive.color)).unwrap();
+        writeln!(
+            json,
+            "      \"mix\": {},",
+            json_string(&describe_mix(&primitive.mix))
+        )
+        .unwrap();
+        writeln!(
+            json,
+            "      \"emission\": {},",
+            json_vector3(&primitive.emission)
+        )
+        .unwrap();
+        writeln!(json, "      \"emitter\": {},", is_emitter(primitive)).unwrap();
+        writeln!(
+            json,
+            "      \"light_link\": {}",
+            json_string(&describe_light_link(&primitive.light_link))
+        )
+        .unwrap();
+        let separator = if index + 1 < scene.primitives.len() {
+            ","
+        } else {
+            ""
+        };
+        writeln!(json, "    }}{}", separator).unwrap();
+    }
+    writeln!(json, "  ],").unwrap();
+    let lights: Vec<String> = scene
+        .lights
+        .iter()
+        .map(|light| json_string(&describe_light(light)))
+        .collect();
+    writeln!(json, "  \"lights\": [{}]", lights.join(", ")).unwrap();
+    writeln!(json, "}}").unwrap();
+    json
+}
@@ -0,0 +1,26 @@
+use nalgebra::Vector3;
+use rand::RngCore;
+use rand_distr::{Distribution, Normal, Poisson};
+
+use crate::scene::Sensor;
+
+// Photon shot noise and read noise on an exposed pixel value. A channel collects
+// `electrons * response` photo-electrons per unit of value, so weak channels and high
+// ISO (fewer electrons for the same value) come out grainier.
+pub fn simulate_sensor(
+    color: &Vector3<f64>,
+    sensor: &Sensor,
+    rng: &mut dyn RngCore,
+) -> Vector3<f64> {
+    let read_noise = Normal::new(0.0, sensor.read_noise).expect("Invalid sensor read noise.");
+    Vector3::from_fn(|channel, _| {
+        let scale = sensor.electrons * sensor.response[channel];
+        let mean = color[channel].max(0.0) * scale;
+        let shot = if mean > 0.0 {
+            Poisson::new(mean).unwrap().sample(rng)
+        } else {
+            0.0
+        };
+        (shot + read_noise.sample(rng)) / scale
+    })
+}
@@ -0,0 +1,317 @@
+use std::f64::consts::PI;
+use std::fmt::Write;
+
+use nalgebra::{Vector2, Vector3};
+
+use crate::frame::Frame;
+use crate::geometry::Shape;
+use crate::scene::Scene;
+
+// Planes are infinite, so they are cut down to a square of this half size.
+const PLANE_HALF_SIZE: f64 = 100.0;
+
+pub struct TriangleList {
+    pub vertices: Vec<Vector3<f64>>,
+    // unit shading normal of each vertex, zero for flat shaded meshes
+    pub normals: Vec<Vector3<f64>>,
+    // texture coordinates of each vertex as texture_coordinates gives them, zero for
+    // meshes. Vertices on a texture seam come twice, once for each side.
+    pub uvs: Vec<Vector2<f64>>,
+    // counter-clockwise when seen from outside
+    pub triangles: Vec<[usize; 3]>,
+}
+
+fn push_vertex(
+    list: &mut TriangleList,
+    vertex: Vector3<f64>,
+    normal: Vector3<f64>,
+    uv: Vector2<f64>,
+) -> usize {
+    list.vertices.push(vertex);
+    list.normals.push(normal);
+    list.uvs.push(uv);
+    list.vertices.len() - 1
+}
+
+fn push_quad(
+    list: &mut TriangleList,
+    corners: [(Vector3<f64>, Vector2<f64>); 4],
+    normal: Vector3<f64>,
+) {
+    let first = list.vertices.len();
+    for (corner, uv) in corners {
+        push_vertex(list, corner, normal, uv);
+    }
+    list.triangles.push([first, first + 1, first + 2]);
+    list.triangles.push([first, first + 2, first + 3]);
+}
+
+// Angle around the y axis of a column of a curved shape. The first column sits where
+// texture_coordinates has u = 0, so that u grows from 0 to 1 over the columns.
+fn column_angle(column: usize, segments: usize) -> f64 {
+    2.0 * PI * column as f64 / segments as f64 + PI / 2.0
+}
+
+// Flat disk of a cylinder or cone at height y, facing down or up. Its fan is split into
+// one center vertex per segment, so the center can take the u of each segment.
+fn push_cap(list: &mut TriangleList, r: f64, h: f64, y: f64, segments: usize) {
+    let normal = Vector3::new(0.0, y.signum(), 0.0);
+    let v = (h - y) / (2.0 * h);
+    let ring = list.vertices.len();
+    for column in 0..=segments {
+        let phi = column_angle(column, segments);
+        push_vertex(
+            list,
+            Vector3::new(r * phi.cos(), y, r * phi.sin()),
+            normal,
+            Vector2::new(column as f64 / segments as f64, v),
+        );
+    }
+    for segment in 0..segments {
+        let center = push_vertex(
+            list,
+            Vector3::new(0.0, y, 0.0),
+            normal,
+            Vector2::new((segment as f64 + 0.5) / segments as f64, v),
+        );
+        let (first, second) = (ring + segment, ring + segment + 1);
+        list.triangles.push(if y < 0.0 {
+            [center, first, second]
+        } else {
+            [center, second, first]
+        });
+    }
+}
+
+// Triangles of the shape in its local space. `resolution` is the number of segments
+// around curved outlines.
+pub fn tessellate_shape(shape: &Shape, resolution: usize) -> TriangleList {
+    let mut list = TriangleList {
+        vertices: vec![],
+        normals: vec![],
+        uvs: vec![],
+        triangles: vec![],
+    };
+    match shape {
+        Shape::Plane { normal } => {
+            let frame = Frame::from_normal(&normal.normalize());
+            let corner = |a: f64, b: f64| {
+                (
+                    (frame.tangent * a + frame.bitangent * b) * PLANE_HALF_SIZE,
+                    Vector2::new(a, b) * PLANE_HALF_SIZE,
+                )
+            };
+            push_quad(
+                &mut list,
+                [
+                    corner(-1.0, -1.0),
+                    corner(1.0, -1.0),
+                    corner(1.0, 1.0),
+                    corner(-1.0, 1.0),
+                ],
+                normal.normalize(),
+            );
+        }
+        Shape::Ellipsoid { r } => {
+            let rings = resolution.max(3) / 2;
+            let segments = resolution.max(3);
+            for ring in 0..=rings {
+                let theta = PI * ring as f64 / rings as f64;
+                for column in 0..=segments {
+                    let phi = column_angle(column, segments);
+                    let direction = Vector3::new(
+                        theta.sin() * phi.cos(),
+                        theta.cos(),
+                        theta.sin() * phi.sin(),
+                    );
+                    push_vertex(
+                        &mut list,
+                        direction.component_mul(r),
+                        direction.component_div(r).normalize(),
+                        Vector2::new(column as f64 / segments as f64, ring as f64 / rings as f64),
+                    );
+                }
+            }
+            let columns = segments + 1;
+            for ring in 0..rings {
+                for segment in 0..segments {
+                    let a = ring * columns + segment;
+                    let b = a + 1;
+                    let c = a + columns;
+                    let d = c + 1;
+                    // skip the triangles that collapse into the poles
+                    if ring != 0 {
+                        list.triangles.push([a, b, c]);
+                    }
+                    if ring != rings - 1 {
+                        list.triangles.push([b, d, c]);
+                    }
+                }
+            }
+        }
+        Shape::Sphere { r } => {
+            list = tessellate_shape(
+                &Shape::Ellipsoid {
+                    r: Vector3::repeat(*r),
+                },
+                resolution,
+            )
+        }
+        Shape::Cylinder { r, h } => {
+            let segments = resolution.max(3);
+            // a bottom and a top row of the side, then the caps
+            for y in [-h, *h] {
+                for column in 0..=segments {
+                    let phi = column_angle(column, segments);
+                    push_vertex(
+                        &mut list,
+                        Vector3::new(r * phi.cos(), y, r * phi.sin()),
+                        Vector3::new(phi.cos(), 0.0, phi.sin()),
+                        Vector2::new(column as f64 / segments as f64, (h - y) / (2.0 * h)),
+                    );
+                }
+            }
+            let columns = segments + 1;
+            for segment in 0..segments {
+                let (bottom, bottom_next) = (segment, segment + 1);
+                let (top, top_next) = (columns + segment, columns + segment + 1);
+                list.triangles.push([bottom, top, top_next]);
+                list.triangles.push([bottom, top_next, bottom_next]);
+            }
+            push_cap(&mut list, *r, *h, -h, segments);
+            push_cap(&mut list, *r, *h, *h, segments);
+        }
+        Shape::Cone { r, h } => {
+            let segments = resolution.max(3);
+            // the side leans in by r over a height of 2h
+            let side_normal =
+                |phi: f64| Vector3::new(2.0 * h * phi.cos(), *r, 2.0 * h * phi.sin()).normalize();
+            for column in 0..=segments {
+                let phi = column_angle(column, segments);
+                push_vertex(
+                    &mut list,
+                    Vector3::new(r * phi.cos(), -h, r * phi.sin()),
+                    side_normal(phi),
+                    Vector2::new(column as f64 / segments as f64, 1.0),
+                );
+            }
+            // one tip per segment, like the cap centers
+            for segment in 0..segments {
+                let u = (segment as f64 + 0.5) / segments as f64;
+                let tip = push_vertex(
+                    &mut list,
+                    Vector3::new(0.0, *h, 0.0),
+                    side_normal(column_angle(segment, segments) + PI / segments as f64),
+                    Vector2::new(u, 0.0),
+                );
+                list.triangles.push([segment, tip, segment + 1]);
+            }
+            push_cap(&mut list, *r, *h, -h, segments);
+        }
+        Shape::Box { s } => {
+            for axis in 0..3 {
+                let (u, v) = ((axis + 1) % 3, (axis + 2) % 3);
+                for sign in [1.0, -1.0] {
+                    let corner = |a: f64, b: f64| {
+                        let mut point = Vector3::zeros();
+                        point[axis] = sign * s[axis];
+                        point[u] = a * s[u];
+                        point[v] = b * s[v];
+                        (point, Vector2::new((a + 1.0) / 2.0, (b + 1.0) / 2.0))
+                    };
+                    let mut corners = [
+                        corner(-1.0, -1.0),
+                        corner(1.0, -1.0),
+                        corner(1.0, 1.0),
+                        corner(-1.0, 1.0),
+                    ];
+                    if sign < 0.0 {
+                        corners.reverse();
+                    }
+                    let mut normal = Vector3::zeros();
+                    normal[axis] = sign;
+                    push_quad(&mut list, corners, normal);
+                }
+            }
+        }
+        Shape::Rectangle { s } => push_quad(
+            &mut list,
+            [
+                (Vector3::new(-s.x, 0.0, -s.y), Vector2::new(0.0, 0.0)),
+                (Vector3::new(-s.x, 0.0, s.y), Vector2::new(0.0, 1.0)),
+                (Vector3::new(s.x, 0.0, s.y), Vector2::new(1.0, 1.0)),
+                (Vector3::new(s.x, 0.0, -s.y), Vector2::new(1.0, 0.0)),
+            ],
+            Vector3::y(),
+        ),
+        Shape::Disc { r } => {
+            let segments = resolution.max(3);
+            push_vertex(
+                &mut list,
+                Vector3::zeros(),
+                Vector3::y(),
+                Vector2::new(0.5, 0.5),
+            );
+            for segment in 0..segments {
+                let phi = 2.0 * PI * segment as f64 / segments as f64;
+                push_vertex(
+                    &mut list,
+                    Vector3::new(r * phi.cos(), 0.0, r * phi.sin()),
+                    Vector3::y(),
+                    Vector2::new((phi.cos() + 1.0) / 2.0, (phi.sin() + 1.0) / 2.0),
+                );
+            }
+            for segment in 0..segments {
+                list.triangles
+                    .push([0, 1 + (segment + 1) % segments, 1 + segment]);
+            }
+        }
+        Shape::TriangleMesh {
+            vertices,
+            triangles,
+            normals,
+            bvh: _,
+            area_cdf: _,
+        } => {
+            list.vertices.clone_from(vertices);
+            list.normals = if normals.is_empty() {
+                vec![Vector3::zeros(); vertices.len()]
+            } else {
+                normals.to_vec()
+            };
+            list.uvs = vec![Vector2::zeros(); vertices.len()];
+            list.triangles.clone_from(triangles);
+        }
+    }
+    list
+}
+
+// All primitives baked into world space, one OBJ object per primitive.
+pub fn flatten_scene_to_obj(scene: &Scene, resolution: usize) -> String {
+    let mut obj = String::new();
+    let mut vertex_offset = 0;
+    for (index, primitive) in scene.primitives.iter().enumerate() {
+        let list = tessellate_shape(&primitive.shape, resolution);
+        match &primitive.name {
+            Some(name) => writeln!(obj, "o {}", name).unwrap(),
+            None => writeln!(obj, "o primitive_{}", index).unwrap(),
+        }
+        for vertex in &list.vertices {
+            let world = primitive.rotation.transform_vector(vertex) + primitive.position;
+            writeln!(obj, "v {} {} {}", world.x, world.y, world.z).unwrap();
+        }
+        // OBJ indices are 1-based
+        for [a, b, c] in &list.triangles {
+            writeln!(
+                obj,
+                "f {} {} {}",
+                vertex_offset + a + 1,
+                vertex_offset + b + 1,
+                vertex_offset + c + 1
+            )
+            .unwrap();
+        }
+        vertex_offset += list.vertices.len();
+    }
+    obj
+}
@@ -0,0 +1,151 @@
+use std::path::Path;
+
+use image::codecs::hdr::HdrDecoder;
+use image::ColorType;
+use nalgebra::{Vector2, Vector3};
+
+use crate::assets::Assets;
+use crate::environment::{luminance, sample_cdf};
+
+// Linear RGB image, repeated in both directions when looked up.
+pub struct Texture {
+    pub width: usize,
+    pub height: usize,
+    pub texels: Vec<Vector3<f64>>,
+}
+
+impl Texture {
+    // Bilinear lookup, v = 0 is the top row of the image.
+    pub fn color(&self, uv: &Vector2<f64>) -> Vector3<f64> {
+        let x = uv.x * self.width as f64 - 0.5;
+        let y = uv.y * self.height as f64 - 0.5;
+        let (column, row) = (x.floor(), y.floor());
+        let texel = |column: f64, row: f64| {
+            let column = (column as i64).rem_euclid(self.width as i64) as usize;
+            let row = (row as i64).rem_euclid(self.height as i64) as usize;
+            self.texels[row * self.width + column]
+        };
+        let top = texel(column, row).lerp(&texel(column + 1.0, row), x - column);
+        let bottom = texel(column, row + 1.0).lerp(&texel(column + 1.0, row + 1.0), x - column);
+        top.lerp(&bottom, y - row)
+    }
+}
+
+// How the values of an integer image encode linear color. Floating point images
+// always hold linear values and are read as they are.
+#[derive(Clone, Copy)]
+pub enum ColorSpace {
+    // the usual encoding of photos and painted colors
+    Srgb,
+    // data such as masks, stored without any curve
+    Linear,
+}
+
+pub fn parse_color_space(name: &str) -> Option<ColorSpace> {
+    match name {
+        "SRGB" => Some(ColorSpace::Srgb),
+        "LINEAR" => Some(ColorSpace::Linear),
+        _ => None,
+    }
+}
+
+// The sRGB transfer function from an encoded value in 0..1 to linear.
+fn srgb_to_linear(value: f64) -> f64 {
+    if value <= 0.04045 {
+        value / 12.92
+    } else {
+        ((value + 0.055) / 1.055).powf(2.4)
+    }
+}
+
+fn to_vector3(pixel: &[f32]) -> Vector3<f64> {
+    Vector3::new(pixel[0] as f64, pixel[1] as f64, pixel[2] as f64)
+}
+
+// Radiance HDR and other floating point images are taken as linear, the rest are
+// linearized as `color_space` says.
+pub fn load_texture(
+    assets: &Assets,
+    path: &str,
+    color_space: ColorSpace,
+) -> Result<Texture, String> {
+    let bytes = assets.read(path)?;
+    // image::load_from_memory tone maps Radiance files down to 8 bits, so they are
+    // decoded directly
+    let is_radiance = Path::new(path)
+        .extension()
+        .is_some_and(|extension| extension.eq_ignore_ascii_case("hdr"));
+    if is_radiance {
+        let decoder = HdrDecoder::new(bytes.as_slice()).map_err(|error| error.to_string())?;
+        let metadata = decoder.metadata();
+        let texels = decoder
+            .read_image_hdr()
+            .map_err(|error| error.to_string())?
+            .iter()
+            .map(|pixel| to_vector3(&pixel.0))
+            .collect();
+        return Ok(Texture {
+            width: metadata.width as usize,
+            height: metadata.height as usize,
+            texels,
+        });
+    }
+
+    let image = image::load_from_memory(&bytes).map_err(|error| error.to_string())?;
+    let is_linear = matches!(image.color(), ColorType::Rgb32F | ColorType::Rgba32F);
+    let image = image.into_rgb32f();
+    let texels = image
+        .pixels()
+        .map(|pixel| {
+            let color = to_vector3(&pixel.0);
+            match (is_linear, color_space) {
+                (false, ColorSpace::Srgb) => color.map(srgb_to_linear),
+                _ => color,
+            }
+        })
+        .collect();
+    Ok(Texture {
+        width: image.width() as usize,
+        height: image.height() as usize,
+        texels,
+    })
+}
+
+// Shape of a lens opening, the luminance of an image over the square the aperture
+// fits in. Points are drawn with probability proportional to it.
+pub struct ApertureMask {
+    width: usize,
+    height: usize,
+    // cumulative luminance of the texels row by row
+    cdf: Vec<f64>,
+}
+
+impl ApertureMask {
+    // None for an image that lets no light through.
+    pub fn new(texture: &Texture) -> Option<ApertureMask> {
+        let cdf: Vec<f64> = texture
+            .texels
+            .iter()
+            .scan(0.0, |total, texel| {
+                *total += luminance(texel).max(0.0);
+                Some(*total)
+            })
+            .collect();
+        (cdf.last().is_some_and(|&total| total > 0.0)).then_some(ApertureMask {
+            width: texture.width,
+            height: texture.height,
+            cdf,
+        })
+    }
+
+    // Point of the unit square centered on the lens, x to the right and y up. `u[0]`
+    // picks the texel and places the point across it, `u[1]` places it down it.
+    pub fn sample(&self, u: [f64; 2]) -> Vector2<f64> {
+        let (texel, offset) = sample_cdf(&self.cdf, u[0]);
+        let (row, column) = (texel / self.width, texel % self.width);
+        Vector2::new(
+            (column as f64 + offset) / self.width as f64 - 0.5,
+            0.5 - (row as f64 + u[1]) / self.height as f64,
+        )
+    }
+}